@@ -25,12 +25,24 @@ variable "image" {
   type        = string
 }
 
+variable "config_path" {
+  default     = "config.toml"
+  description = "Bot config file to copy onto the instance, see config.example.toml"
+  type        = string
+}
+
 variable "machine_type" {
   default     = "e2-micro"
   description = "GCP Machine Type"
   type        = string
 }
 
+variable "data_disk_size_gb" {
+  default     = 10
+  description = "Size of the persistent disk holding the bot's database"
+  type        = number
+}
+
 variable "instance_name" {
   default     = "spam-eater"
   description = "The desired name to assign to the deployed instance"
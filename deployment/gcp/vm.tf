@@ -15,7 +15,9 @@
  */
 
 locals {
-  instance_name = format("%s-%s", var.instance_name, substr(md5(module.gce-container.container.image), 0, 8))
+  config = file(var.config_path)
+  # A new image or config replaces the instance, as its metadata is otherwise left alone.
+  instance_name = format("%s-%s", var.instance_name, substr(md5("${module.gce-container.container.image}${local.config}"), 0, 8))
 }
 
 module "gce-container" {
@@ -34,14 +36,48 @@ module "gce-container" {
         value = google_secret_manager_secret_version.openai_token_version.secret_data,
       }
     ]
-    volumeMounts = []
+    volumeMounts = [
+      {
+        mountPath = "/etc/spam_eater"
+        name      = "config"
+        readOnly  = true
+      },
+      {
+        mountPath = "/data"
+        name      = "data"
+        readOnly  = false
+      }
+    ]
   }
 
-  volumes = []
+  volumes = [
+    {
+      name = "config"
+      hostPath = {
+        path = "/var/lib/spam-eater"
+      }
+    },
+    {
+      name = "data"
+      gcePersistentDisk = {
+        pdName = "data"
+        fsType = "ext4"
+      }
+    }
+  ]
 
   restart_policy = "Always"
 }
 
+# The database lives here, apart from the instance, which each deploy replaces.
+resource "google_compute_disk" "data" {
+  project = var.project_id
+  name    = "${var.instance_name}-data"
+  type    = "pd-standard"
+  zone    = var.zone
+  size    = var.data_disk_size_gb
+}
+
 resource "google_compute_instance" "vm" {
   project      = var.project_id
   name         = local.instance_name
@@ -61,6 +97,12 @@ resource "google_compute_instance" "vm" {
     instance_termination_action = "STOP"
   }
 
+  attached_disk {
+    source      = google_compute_disk.data.self_link
+    device_name = "data"
+    mode        = "READ_WRITE"
+  }
+
   network_interface {
     network    = module.vpc.network_id
     subnetwork = local.subnet_name
@@ -69,6 +111,16 @@ resource "google_compute_instance" "vm" {
 
   metadata = {
     gce-container-declaration = module.gce-container.metadata_value
+    # Written by cloud-init on boot; the container restarts until it's there.
+    user-data = "#cloud-config\n${yamlencode({
+      write_files = [
+        {
+          path        = "/var/lib/spam-eater/config.toml"
+          permissions = "0644"
+          content     = local.config
+        }
+      ]
+    })}"
     google-logging-enabled    = "true"
     google-monitoring-enabled = "true"
   }
@@ -0,0 +1,371 @@
+use crate::classifier::SpamCategory;
+use crate::config::{FailurePolicy, GuildConfig, SpamAction};
+use crate::corpus::LabelledMessage;
+use crate::trust::TrustSignals;
+use crate::{classify_candidate, Candidate, MessageClassification};
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Instant;
+
+/// What the pipeline did with one corpus entry.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// Passed the pre-filter, the classifier wasn't sure enough to act, or it failed
+    /// and the guild fails open.
+    Ignored,
+    /// The classifier failed and the guild's `llm_failure.spam_detection` policy
+    /// takes such messages down.
+    Held,
+    Warn,
+    Delete,
+    /// Older reports call this `timeout`.
+    #[serde(alias = "timeout")]
+    Escalate,
+}
+
+impl Outcome {
+    fn is_flagged(&self) -> bool {
+        *self != Outcome::Ignored
+    }
+}
+
+/// One corpus entry and what happened to it, as saved with `--output`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryResult {
+    pub content: String,
+    pub is_spam: bool,
+    #[serde(default)]
+    pub labelled_category: Option<SpamCategory>,
+    pub outcome: Outcome,
+    #[serde(default)]
+    pub category: Option<SpamCategory>,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+impl EntryResult {
+    fn is_flagged(&self) -> bool {
+        self.outcome.is_flagged()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConfusionMatrix {
+    pub true_positives: usize,
+    pub false_positives: usize,
+    pub true_negatives: usize,
+    pub false_negatives: usize,
+}
+
+impl ConfusionMatrix {
+    pub fn from_results(results: &[EntryResult]) -> ConfusionMatrix {
+        let mut matrix = ConfusionMatrix::default();
+        for result in results {
+            match (result.is_spam, result.is_flagged()) {
+                (true, true) => matrix.true_positives += 1,
+                (false, true) => matrix.false_positives += 1,
+                (false, false) => matrix.true_negatives += 1,
+                (true, false) => matrix.false_negatives += 1,
+            }
+        }
+        matrix
+    }
+
+    pub fn precision(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_positives,
+        )
+    }
+
+    pub fn recall(&self) -> f64 {
+        ratio(
+            self.true_positives,
+            self.true_positives + self.false_negatives,
+        )
+    }
+
+    pub fn f1(&self) -> f64 {
+        let (precision, recall) = (self.precision(), self.recall());
+        if precision + recall == 0.0 {
+            0.0
+        } else {
+            2.0 * precision * recall / (precision + recall)
+        }
+    }
+}
+
+fn ratio(numerator: usize, denominator: usize) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+/// How one spam category fared. Recall is over entries labelled with the category,
+/// precision over entries the classifier put in it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CategoryMetrics {
+    pub labelled: usize,
+    pub caught: usize,
+    pub predicted: usize,
+    pub predicted_spam: usize,
+}
+
+pub fn category_metrics(results: &[EntryResult]) -> Vec<(SpamCategory, CategoryMetrics)> {
+    SpamCategory::ALL
+        .into_iter()
+        .map(|category| {
+            let mut metrics = CategoryMetrics::default();
+            for result in results {
+                if result.is_spam && result.labelled_category == Some(category) {
+                    metrics.labelled += 1;
+                    metrics.caught += result.is_flagged() as usize;
+                }
+                if result.is_flagged() && result.category == Some(category) {
+                    metrics.predicted += 1;
+                    metrics.predicted_spam += result.is_spam as usize;
+                }
+            }
+            (category, metrics)
+        })
+        .filter(|(_, metrics)| metrics.labelled + metrics.predicted > 0)
+        .collect()
+}
+
+/// Entries whose outcome flipped between two runs, matched by content.
+#[derive(Debug, Default)]
+pub struct RunDiff<'a> {
+    pub newly_flagged: Vec<&'a EntryResult>,
+    pub no_longer_flagged: Vec<&'a EntryResult>,
+    /// Entries with no counterpart in the previous run.
+    pub unmatched: usize,
+}
+
+pub fn diff_runs<'a>(previous: &[EntryResult], current: &'a [EntryResult]) -> RunDiff<'a> {
+    let previous: HashMap<&str, &EntryResult> = previous
+        .iter()
+        .map(|result| (result.content.as_str(), result))
+        .collect();
+    let mut diff = RunDiff::default();
+    for result in current {
+        match previous.get(result.content.as_str()) {
+            None => diff.unmatched += 1,
+            Some(before) if !before.is_flagged() && result.is_flagged() => {
+                diff.newly_flagged.push(result)
+            }
+            Some(before) if before.is_flagged() && !result.is_flagged() => {
+                diff.no_longer_flagged.push(result)
+            }
+            Some(_) => {}
+        }
+    }
+    diff
+}
+
+/// Runs every corpus entry through the same pre-filter and classifier as live messages.
+pub async fn run(guild_config: &GuildConfig, corpus: &[LabelledMessage]) -> Vec<EntryResult> {
+    let now = chrono::Utc::now().timestamp();
+    let mut results = Vec::with_capacity(corpus.len());
+    for message in corpus {
+        let candidate = Candidate {
+            author_name: "backtest",
+            content: message.content.as_str(),
+            mention_everyone: message.mention_everyone,
+            trust: TrustSignals {
+                account_created_at: message.account_age_days.map(|days| now - days * 86_400),
+                // Unknown join dates count for nothing, as they do live.
+                joined_at: message.member_age_minutes.map(|minutes| now - minutes * 60),
+                message_count: message.message_count,
+                ..Default::default()
+            },
+            context: message.context.clone(),
+            guild_id: None,
+        };
+        let (outcome, category, reason) = match classify_candidate(guild_config, candidate).await {
+            MessageClassification::Normal => (Outcome::Ignored, None, None),
+            MessageClassification::MaybeSpam(_)
+                if guild_config.llm_failure.spam_detection == FailurePolicy::FailOpen =>
+            {
+                (Outcome::Ignored, None, None)
+            }
+            MessageClassification::MaybeSpam(reason) => (Outcome::Held, None, Some(reason)),
+            MessageClassification::DefinitelySpam(verdict, action, _) => {
+                if action != SpamAction::Warn {
+                    guild_config.spam_templates.add(
+                        message.content.as_str(),
+                        &verdict,
+                        Instant::now(),
+                    );
+                }
+                let outcome = match action {
+                    SpamAction::Warn => Outcome::Warn,
+                    SpamAction::Delete => Outcome::Delete,
+                    SpamAction::Escalate => Outcome::Escalate,
+                };
+                (outcome, verdict.category, Some(verdict.summary()))
+            }
+        };
+        results.push(EntryResult {
+            content: message.content.clone(),
+            is_spam: message.is_spam,
+            labelled_category: message.category,
+            outcome,
+            category,
+            reason,
+        });
+    }
+    results
+}
+
+pub fn load_results(path: &Path) -> anyhow::Result<Vec<EntryResult>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Couldn't read backtest results {}", path.display()))?;
+    serde_json::from_str(contents.as_str())
+        .with_context(|| format!("Invalid backtest results {}", path.display()))
+}
+
+pub fn save_results(path: &Path, results: &[EntryResult]) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(results)?)
+        .with_context(|| format!("Couldn't write backtest results {}", path.display()))
+}
+
+fn print_metrics(label: &str, matrix: &ConfusionMatrix) {
+    println!(
+        "{label}: precision {:.3}, recall {:.3}, F1 {:.3}",
+        matrix.precision(),
+        matrix.recall(),
+        matrix.f1()
+    );
+}
+
+pub fn print_report(results: &[EntryResult], previous: Option<&[EntryResult]>) {
+    let matrix = ConfusionMatrix::from_results(results);
+    println!("{} messages", results.len());
+    println!();
+    println!("                 flagged  ignored");
+    println!(
+        "labelled spam    {:>7}  {:>7}",
+        matrix.true_positives, matrix.false_negatives
+    );
+    println!(
+        "labelled ham     {:>7}  {:>7}",
+        matrix.false_positives, matrix.true_negatives
+    );
+    println!();
+    print_metrics("Overall", &matrix);
+
+    let categories = category_metrics(results);
+    if !categories.is_empty() {
+        println!();
+        println!("category        labelled  recall  predicted  precision");
+        for (category, metrics) in categories {
+            println!(
+                "{:<14}  {:>8}  {:>6.3}  {:>9}  {:>9.3}",
+                category.as_str(),
+                metrics.labelled,
+                ratio(metrics.caught, metrics.labelled),
+                metrics.predicted,
+                ratio(metrics.predicted_spam, metrics.predicted),
+            );
+        }
+    }
+
+    let Some(previous) = previous else {
+        return;
+    };
+    println!();
+    print_metrics("Previous run", &ConfusionMatrix::from_results(previous));
+    let diff = diff_runs(previous, results);
+    for (heading, entries) in [
+        ("Newly flagged", &diff.newly_flagged),
+        ("No longer flagged", &diff.no_longer_flagged),
+    ] {
+        println!("{heading}: {}", entries.len());
+        for entry in entries.iter() {
+            println!(
+                "  [{}] {}",
+                if entry.is_spam { "spam" } else { "ham" },
+                entry.content.replace('\n', " ")
+            );
+        }
+    }
+    if diff.unmatched > 0 {
+        println!("{} messages weren't in the previous run", diff.unmatched);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(content: &str, is_spam: bool, outcome: Outcome) -> EntryResult {
+        EntryResult {
+            content: content.to_string(),
+            is_spam,
+            labelled_category: is_spam.then_some(SpamCategory::Phishing),
+            outcome,
+            category: outcome.is_flagged().then_some(SpamCategory::Phishing),
+            reason: None,
+        }
+    }
+
+    #[test]
+    fn computes_metrics() {
+        let results = [
+            result("a", true, Outcome::Escalate),
+            result("b", true, Outcome::Ignored),
+            result("c", false, Outcome::Warn),
+            result("d", false, Outcome::Ignored),
+            result("e", true, Outcome::Held),
+        ];
+        let matrix = ConfusionMatrix::from_results(&results);
+        assert_eq!(
+            matrix,
+            ConfusionMatrix {
+                true_positives: 2,
+                false_positives: 1,
+                true_negatives: 1,
+                false_negatives: 1,
+            }
+        );
+        assert!((matrix.precision() - 2.0 / 3.0).abs() < 1e-9);
+        assert!((matrix.recall() - 2.0 / 3.0).abs() < 1e-9);
+        let categories = category_metrics(&results);
+        assert_eq!(categories.len(), 1);
+        assert_eq!(
+            categories[0].1,
+            CategoryMetrics {
+                labelled: 3,
+                caught: 2,
+                predicted: 3,
+                predicted_spam: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn diffs_runs_by_content() {
+        let previous = [
+            result("a", true, Outcome::Ignored),
+            result("b", false, Outcome::Delete),
+            result("c", true, Outcome::Delete),
+        ];
+        let current = [
+            result("c", true, Outcome::Escalate),
+            result("b", false, Outcome::Ignored),
+            result("a", true, Outcome::Delete),
+            result("new", false, Outcome::Ignored),
+        ];
+        let diff = diff_runs(&previous, &current);
+        assert_eq!(diff.newly_flagged.len(), 1);
+        assert_eq!(diff.newly_flagged[0].content, "a");
+        assert_eq!(diff.no_longer_flagged.len(), 1);
+        assert_eq!(diff.no_longer_flagged[0].content, "b");
+        assert_eq!(diff.unmatched, 1);
+    }
+}
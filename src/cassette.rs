@@ -0,0 +1,190 @@
+use crate::llm::{ChatRequest, Completion, LlmClient};
+use crate::usage::TokenUsage;
+use anyhow::{bail, Context as _};
+use openai::chat::ChatCompletionMessage;
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use sha2::{Digest, Sha256};
+use std::env;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::info;
+
+/// Where recordings go when `BSE_CASSETTE_DIR` isn't set.
+const DEFAULT_CASSETTE_DIR: &str = "cassettes";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Call the API as usual and save every request/response pair.
+    Record,
+    /// Never call the API; answer from saved pairs and fail on anything unrecorded.
+    Replay,
+}
+
+/// One saved request/response pair, stored as `<key>.json`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Recording {
+    model: String,
+    messages: Vec<ChatCompletionMessage>,
+    response: Option<String>,
+    /// Absent from recordings made before usage was tracked.
+    #[serde(default)]
+    usage: TokenUsage,
+}
+
+/// A directory of recorded chat completions wrapped around a real client, so
+/// LLM-backed code can run offline.
+#[derive(Debug)]
+pub struct Cassette {
+    mode: CassetteMode,
+    dir: PathBuf,
+    inner: Arc<dyn LlmClient>,
+}
+
+/// Hex SHA-256 of the model and messages, which is all that decides a response.
+pub fn request_key(model: &str, messages: &[ChatCompletionMessage]) -> String {
+    let request = serde_json::to_string(&(model, messages)).expect("Messages always serialise");
+    Sha256::digest(request.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+impl Cassette {
+    pub fn new(mode: CassetteMode, dir: PathBuf, inner: Arc<dyn LlmClient>) -> Cassette {
+        Cassette { mode, dir, inner }
+    }
+
+    /// Wraps `inner` in a cassette if `BSE_CASSETTE` is `record` or `replay`, reading
+    /// recordings from `BSE_CASSETTE_DIR`.
+    pub fn from_env(inner: Arc<dyn LlmClient>) -> anyhow::Result<Arc<dyn LlmClient>> {
+        let mode = match env::var("BSE_CASSETTE").as_deref() {
+            Err(_) | Ok("") | Ok("off") => return Ok(inner),
+            Ok("record") => CassetteMode::Record,
+            Ok("replay") => CassetteMode::Replay,
+            Ok(other) => bail!("`BSE_CASSETTE` must be `record`, `replay` or `off`, not `{other}`"),
+        };
+        let dir = env::var("BSE_CASSETTE_DIR").unwrap_or_else(|_| DEFAULT_CASSETTE_DIR.to_string());
+        info!("Using {mode:?} cassette in {dir}");
+        Ok(Arc::new(Cassette::new(mode, PathBuf::from(dir), inner)))
+    }
+
+    /// Replays the recordings checked in under `fixtures/cassettes`.
+    #[cfg(test)]
+    pub fn fixtures() -> Cassette {
+        Cassette::new(
+            CassetteMode::Replay,
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/cassettes"),
+            Arc::new(crate::llm::OpenAiCompatible::new(
+                String::new(),
+                "BSE_TEST_UNSET_KEY".to_string(),
+                false,
+            )),
+        )
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.json"))
+    }
+
+    fn replay(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+    ) -> anyhow::Result<Completion> {
+        let key = request_key(model, messages);
+        let path = self.path(key.as_str());
+        let contents = std::fs::read_to_string(&path).with_context(|| {
+            format!(
+                "No recorded response at {} - run once with BSE_CASSETTE=record to capture it",
+                path.display()
+            )
+        })?;
+        let recording: Recording = serde_json::from_str(contents.as_str())
+            .with_context(|| format!("Invalid recording {}", path.display()))?;
+        Ok(Completion {
+            content: recording.response,
+            usage: recording.usage,
+        })
+    }
+
+    fn record(
+        &self,
+        model: &str,
+        messages: Vec<ChatCompletionMessage>,
+        completion: Completion,
+    ) -> anyhow::Result<()> {
+        let key = request_key(model, &messages);
+        let path = self.path(key.as_str());
+        std::fs::create_dir_all(&self.dir).with_context(|| {
+            format!("Couldn't create cassette directory {}", self.dir.display())
+        })?;
+        let recording = Recording {
+            model: model.to_string(),
+            messages,
+            response: completion.content,
+            usage: completion.usage,
+        };
+        std::fs::write(&path, serde_json::to_string_pretty(&recording)?)
+            .with_context(|| format!("Couldn't write recording {}", path.display()))
+    }
+}
+
+#[async_trait]
+impl LlmClient for Cassette {
+    async fn complete(&self, request: &ChatRequest) -> anyhow::Result<Completion> {
+        if self.mode == CassetteMode::Replay {
+            return self.replay(request.model.as_str(), &request.messages);
+        }
+        let completion = self.inner.complete(request).await?;
+        self.record(
+            request.model.as_str(),
+            request.messages.clone(),
+            completion.clone(),
+        )?;
+        Ok(completion)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openai::chat::ChatCompletionMessageRole;
+
+    fn message(content: &str) -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some(content.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn records_and_replays() {
+        let dir = env::temp_dir().join("bse_cassette_round_trip");
+        let _ = std::fs::remove_dir_all(&dir);
+        let inner = Cassette::fixtures().inner;
+        let recorder = Cassette::new(CassetteMode::Record, dir.clone(), inner.clone());
+        recorder
+            .record(
+                "gpt-4o-mini",
+                vec![message("hello")],
+                Completion {
+                    content: Some("hi!".to_string()),
+                    usage: TokenUsage {
+                        prompt_tokens: 9,
+                        completion_tokens: 2,
+                    },
+                },
+            )
+            .unwrap();
+
+        let player = Cassette::new(CassetteMode::Replay, dir, inner);
+        let completion = player.replay("gpt-4o-mini", &[message("hello")]).unwrap();
+        assert_eq!(completion.content.as_deref(), Some("hi!"));
+        assert_eq!(completion.usage.completion_tokens, 2);
+        // A different model or message is a different request.
+        assert!(player.replay("gpt-4o", &[message("hello")]).is_err());
+        assert!(player.replay("gpt-4o-mini", &[message("hi")]).is_err());
+    }
+}
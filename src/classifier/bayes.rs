@@ -0,0 +1,186 @@
+use super::{ClassifierInput, SpamClassifier, Verdict};
+use crate::corpus::LabelledMessage;
+use anyhow::{bail, Context as _};
+use serde::{Deserialize, Serialize};
+use serenity::async_trait;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Splits a message into lowercase words plus adjacent word pairs.
+fn tokenise(content: &str) -> Vec<String> {
+    let words: Vec<String> = content
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    let bigrams = words
+        .windows(2)
+        .map(|pair| format!("{} {}", pair[0], pair[1]));
+    words.iter().cloned().chain(bigrams).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ClassCounts {
+    documents: u64,
+    tokens: u64,
+    token_counts: HashMap<String, u64>,
+}
+
+impl ClassCounts {
+    fn add(&mut self, tokens: &[String]) {
+        self.documents += 1;
+        self.tokens += tokens.len() as u64;
+        for token in tokens {
+            *self.token_counts.entry(token.clone()).or_default() += 1;
+        }
+    }
+
+    fn log_likelihood(&self, tokens: &[String], vocabulary: usize) -> f64 {
+        let denominator = (self.tokens + vocabulary as u64) as f64;
+        tokens
+            .iter()
+            .map(|token| {
+                let count = self.token_counts.get(token).copied().unwrap_or(0);
+                ((count + 1) as f64 / denominator).ln()
+            })
+            .sum()
+    }
+}
+
+/// Multinomial naive Bayes over word unigrams and bigrams, with add-one smoothing.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct NaiveBayes {
+    spam: ClassCounts,
+    ham: ClassCounts,
+    /// Distinct tokens seen across both classes.
+    vocabulary: usize,
+}
+
+impl NaiveBayes {
+    pub fn train(corpus: &[LabelledMessage]) -> anyhow::Result<NaiveBayes> {
+        let mut model = NaiveBayes::default();
+        for message in corpus {
+            let tokens = tokenise(message.content.as_str());
+            if message.is_spam {
+                model.spam.add(&tokens);
+            } else {
+                model.ham.add(&tokens);
+            }
+        }
+        if model.spam.documents == 0 || model.ham.documents == 0 {
+            bail!("The corpus needs at least one spam and one non-spam message")
+        }
+        model.vocabulary = model
+            .spam
+            .token_counts
+            .keys()
+            .chain(model.ham.token_counts.keys())
+            .collect::<HashSet<_>>()
+            .len();
+        Ok(model)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<NaiveBayes> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read spam model {}", path.display()))?;
+        serde_json::from_str(contents.as_str())
+            .with_context(|| format!("Invalid spam model {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_string(self)?)
+            .with_context(|| format!("Couldn't write spam model {}", path.display()))
+    }
+
+    pub fn documents(&self) -> (u64, u64) {
+        (self.spam.documents, self.ham.documents)
+    }
+
+    /// Probability that `content` is spam.
+    pub fn spam_probability(&self, content: &str) -> f64 {
+        let tokens = tokenise(content);
+        let total_documents = (self.spam.documents + self.ham.documents) as f64;
+        let spam_score = (self.spam.documents as f64 / total_documents).ln()
+            + self.spam.log_likelihood(&tokens, self.vocabulary);
+        let ham_score = (self.ham.documents as f64 / total_documents).ln()
+            + self.ham.log_likelihood(&tokens, self.vocabulary);
+        1.0 / (1.0 + (ham_score - spam_score).exp())
+    }
+}
+
+/// Runs a model trained with the `train` subcommand entirely locally.
+#[derive(Debug)]
+pub struct BayesClassifier {
+    model: Arc<NaiveBayes>,
+}
+
+impl BayesClassifier {
+    pub fn new(model: NaiveBayes) -> BayesClassifier {
+        BayesClassifier {
+            model: Arc::new(model),
+        }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for BayesClassifier {
+    fn name(&self) -> &str {
+        "bayes"
+    }
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict> {
+        let probability = self.model.spam_probability(input.content.as_str()) as f32;
+        Ok(Verdict {
+            is_spam: probability >= 0.5,
+            confidence: probability.max(1.0 - probability),
+            category: None,
+            reason: format!("{:.0}% likely spam", probability * 100.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labelled(content: &str, is_spam: bool) -> LabelledMessage {
+        LabelledMessage {
+            content: content.to_string(),
+            is_spam,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn separates_spam_from_ham() {
+        let model = NaiveBayes::train(&[
+            labelled("free nitro click here https://discord.gg/abc", true),
+            labelled("claim your free steam gift now", true),
+            labelled("earn money fast with crypto signals dm me", true),
+            labelled("how do I merge two dataframes in pandas", false),
+            labelled("try sklearn's train_test_split for that", false),
+            labelled("the lecture notes on gradient descent are great", false),
+        ])
+        .unwrap();
+        assert!(model.spam_probability("free nitro giveaway, claim here") > 0.5);
+        assert!(model.spam_probability("pandas dataframes question") < 0.5);
+    }
+
+    #[test]
+    fn needs_both_classes() {
+        assert!(NaiveBayes::train(&[labelled("hello", false)]).is_err());
+    }
+
+    #[test]
+    fn tokenises_bigrams() {
+        assert_eq!(
+            tokenise("Free NITRO!"),
+            vec![
+                "free".to_string(),
+                "nitro".to_string(),
+                "free nitro".to_string()
+            ]
+        );
+    }
+}
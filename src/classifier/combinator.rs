@@ -0,0 +1,175 @@
+use super::{ClassifierInput, SpamCategory, SpamClassifier, Verdict};
+use anyhow::{anyhow, bail};
+use serenity::async_trait;
+use std::sync::Arc;
+use tracing::warn;
+
+/// Tries each stage in turn and stops at the first one that's sure enough, so
+/// cheap classifiers can go first and only escalate the messages they're unsure of.
+/// If none is sure, the last stage has the final say, and if it failed so does the chain,
+/// so the guild's failure policy applies rather than an unsure earlier guess.
+#[derive(Debug)]
+pub struct Chain {
+    stages: Vec<Arc<dyn SpamClassifier>>,
+    min_confidence: f32,
+}
+
+impl Chain {
+    pub fn new(stages: Vec<Arc<dyn SpamClassifier>>, min_confidence: f32) -> Chain {
+        Chain {
+            stages,
+            min_confidence,
+        }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Chain {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict> {
+        let mut last = Err(anyhow!("The chain has no classifiers"));
+        for stage in &self.stages {
+            last = match stage.classify(input).await {
+                Ok(verdict) if verdict.confidence >= self.min_confidence => {
+                    return Ok(Verdict {
+                        reason: format!("{}: {}", stage.name(), verdict.reason),
+                        ..verdict
+                    });
+                }
+                Ok(verdict) => Ok(Verdict {
+                    reason: format!("{}: {}", stage.name(), verdict.reason),
+                    ..verdict
+                }),
+                Err(e) => {
+                    warn!("Classifier {} failed, escalating - {e}", stage.name());
+                    Err(e.context(format!("Classifier {} failed", stage.name())))
+                }
+            };
+        }
+        last
+    }
+}
+
+/// Runs every member and sides with whichever answer has the larger total confidence.
+#[derive(Debug)]
+pub struct Vote {
+    members: Vec<Arc<dyn SpamClassifier>>,
+}
+
+impl Vote {
+    pub fn new(members: Vec<Arc<dyn SpamClassifier>>) -> Vote {
+        Vote { members }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for Vote {
+    fn name(&self) -> &str {
+        "vote"
+    }
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict> {
+        let mut spam_weight = 0.0;
+        let mut ham_weight = 0.0;
+        let mut reasons = vec![];
+        // The most confident spam vote gets to name the category.
+        let mut category: Option<(f32, SpamCategory)> = None;
+        for member in &self.members {
+            match member.classify(input).await {
+                Ok(verdict) => {
+                    if verdict.is_spam {
+                        spam_weight += verdict.confidence;
+                        if let Some(verdict_category) = verdict.category {
+                            if category
+                                .is_none_or(|(confidence, _)| verdict.confidence > confidence)
+                            {
+                                category = Some((verdict.confidence, verdict_category));
+                            }
+                        }
+                    } else {
+                        ham_weight += verdict.confidence;
+                    }
+                    reasons.push(format!("{}: {}", member.name(), verdict.reason));
+                }
+                Err(e) => warn!(
+                    "Classifier {} failed, skipping its vote - {e}",
+                    member.name()
+                ),
+            }
+        }
+        if reasons.is_empty() {
+            bail!("Every classifier in the vote failed")
+        }
+        let is_spam = spam_weight > ham_weight;
+        Ok(Verdict {
+            is_spam,
+            confidence: spam_weight.max(ham_weight) / (spam_weight + ham_weight).max(f32::EPSILON),
+            category: category.filter(|_| is_spam).map(|(_, category)| category),
+            reason: reasons.join("; "),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct Fixed(Option<Verdict>);
+
+    #[async_trait]
+    impl SpamClassifier for Fixed {
+        fn name(&self) -> &str {
+            "fixed"
+        }
+
+        async fn classify(&self, _input: &ClassifierInput) -> anyhow::Result<Verdict> {
+            match &self.0 {
+                Some(verdict) => Ok(verdict.clone()),
+                None => bail!("unavailable"),
+            }
+        }
+    }
+
+    fn fixed(is_spam: bool, confidence: f32) -> Arc<dyn SpamClassifier> {
+        Arc::new(Fixed(Some(Verdict {
+            is_spam,
+            confidence,
+            category: is_spam.then_some(SpamCategory::Scam),
+            reason: String::new(),
+        })))
+    }
+
+    #[tokio::test]
+    async fn chain_escalates_unsure_verdicts() {
+        let input = ClassifierInput::default();
+        let chain = Chain::new(vec![fixed(false, 0.5), fixed(true, 0.9)], 0.8);
+        assert!(chain.classify(&input).await.unwrap().is_spam);
+
+        let chain = Chain::new(vec![fixed(false, 0.95), fixed(true, 0.9)], 0.8);
+        assert!(!chain.classify(&input).await.unwrap().is_spam);
+
+        // An unsure guess doesn't hide the escalated stage failing.
+        let chain = Chain::new(vec![fixed(true, 0.6), Arc::new(Fixed(None))], 0.8);
+        assert!(chain.classify(&input).await.is_err());
+
+        let chain = Chain::new(vec![Arc::new(Fixed(None)), fixed(true, 0.6)], 0.8);
+        assert!(chain.classify(&input).await.unwrap().is_spam);
+
+        let chain = Chain::new(vec![Arc::new(Fixed(None))], 0.8);
+        assert!(chain.classify(&input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn vote_weighs_confidence() {
+        let input = ClassifierInput::default();
+        let vote = Vote::new(vec![fixed(true, 0.9), fixed(false, 0.3), fixed(false, 0.4)]);
+        let verdict = vote.classify(&input).await.unwrap();
+        assert!(verdict.is_spam);
+        assert_eq!(verdict.category, Some(SpamCategory::Scam));
+        assert!((verdict.confidence - 0.5625).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,199 @@
+use super::{ClassifierInput, SpamCategory, SpamClassifier, Verdict};
+use crate::trust::TrustTier;
+use chrono::Duration;
+use serenity::async_trait;
+
+const INVITE_LINKS: [&str; 3] = ["discord.gg/", "discord.com/invite", "discordapp.com/invite"];
+
+const URL_SHORTENERS: [&str; 6] = [
+    "bit.ly/",
+    "tinyurl.com/",
+    "t.co/",
+    "goo.gl/",
+    "is.gd/",
+    "cutt.ly/",
+];
+
+const LURE_PHRASES: [(&str, SpamCategory); 14] = [
+    ("free nitro", SpamCategory::Phishing),
+    ("nitro for free", SpamCategory::Phishing),
+    ("steam gift", SpamCategory::Phishing),
+    ("airdrop", SpamCategory::Scam),
+    ("giveaway", SpamCategory::Phishing),
+    ("claim your", SpamCategory::Phishing),
+    ("dm me", SpamCategory::Scam),
+    ("send me a dm", SpamCategory::Scam),
+    ("onlyfans", SpamCategory::PaidPromotion),
+    ("nudes", SpamCategory::Scam),
+    ("crypto signals", SpamCategory::Scam),
+    ("investment opportunity", SpamCategory::Scam),
+    ("earn $", SpamCategory::Scam),
+    ("make money", SpamCategory::Scam),
+];
+
+/// Score at which a message is called spam.
+const SPAM_SCORE: f32 = 0.5;
+
+/// A clean message isn't proof of ham, so never be more sure than this of it.
+const HAM_CONFIDENCE: f32 = 0.6;
+
+/// Keyword and link-shape rules that need no network access.
+#[derive(Debug)]
+pub struct HeuristicClassifier;
+
+struct Signal {
+    name: &'static str,
+    weight: f32,
+    category: Option<SpamCategory>,
+}
+
+impl Signal {
+    fn new(name: &'static str, weight: f32, category: Option<SpamCategory>) -> Signal {
+        Signal {
+            name,
+            weight,
+            category,
+        }
+    }
+}
+
+impl HeuristicClassifier {
+    fn signals(content: &str) -> Vec<Signal> {
+        let content = content.to_lowercase();
+        let mut signals = vec![];
+        if INVITE_LINKS.iter().any(|link| content.contains(link)) {
+            signals.push(Signal::new(
+                "server invite",
+                0.5,
+                Some(SpamCategory::SelfPromo),
+            ));
+        }
+        if URL_SHORTENERS.iter().any(|link| content.contains(link)) {
+            signals.push(Signal::new(
+                "shortened link",
+                0.3,
+                Some(SpamCategory::Phishing),
+            ));
+        }
+        if let Some((phrase, category)) = LURE_PHRASES
+            .iter()
+            .find(|(phrase, _)| content.contains(phrase))
+        {
+            signals.push(Signal::new(phrase, 0.4, Some(*category)));
+        }
+        if content.contains("@everyone") || content.contains("@here") {
+            signals.push(Signal::new("mass mention", 0.3, None));
+        }
+        if content.matches("http").count() > 3 {
+            signals.push(Signal::new("many links", 0.2, None));
+        }
+        signals
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for HeuristicClassifier {
+    fn name(&self) -> &str {
+        "heuristic"
+    }
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict> {
+        // Lures are often split over several messages, so read them together.
+        let mut signals = HeuristicClassifier::signals(input.conversation().as_str());
+        if input
+            .account_age
+            .is_some_and(|account_age| account_age < Duration::days(1))
+        {
+            signals.push(Signal::new("account under a day old", 0.1, None));
+        } else if input.trust == Some(TrustTier::New) {
+            signals.push(Signal::new("new member", 0.1, None));
+        }
+        let score: f32 = signals.iter().map(|signal| signal.weight).sum();
+        let reason = if signals.is_empty() {
+            "No spam signals".to_string()
+        } else {
+            signals
+                .iter()
+                .map(|signal| signal.name)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        Ok(if score >= SPAM_SCORE {
+            // A lure phrase says more about the kind of spam than the link it comes with.
+            let category = signals
+                .iter()
+                .rev()
+                .find_map(|signal| signal.category)
+                .unwrap_or(SpamCategory::Other);
+            Verdict {
+                is_spam: true,
+                confidence: score.min(1.0),
+                category: Some(category),
+                reason,
+            }
+        } else {
+            Verdict {
+                is_spam: false,
+                confidence: HAM_CONFIDENCE - score,
+                category: None,
+                reason,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn classify(content: &str) -> Verdict {
+        HeuristicClassifier
+            .classify(&ClassifierInput {
+                content: content.to_string(),
+                ..Default::default()
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn flags_lures() {
+        let verdict = classify("Free Nitro giveaway!! join https://discord.gg/blueberry").await;
+        assert!(verdict.is_spam);
+        assert!(verdict.reason.contains("server invite"));
+        assert_eq!(verdict.category, Some(SpamCategory::Phishing));
+        assert!(verdict.confidence >= SPAM_SCORE);
+    }
+
+    #[tokio::test]
+    async fn reads_lures_across_messages() {
+        let input = ClassifierInput {
+            content: "https://discord.gg/blueberry".to_string(),
+            context: vec!["hey, anyone want free nitro?".to_string()],
+            account_age: Some(Duration::hours(2)),
+            member_age: Some(Duration::minutes(5)),
+            guild_id: None,
+            ..Default::default()
+        };
+        let verdict = HeuristicClassifier.classify(&input).await.unwrap();
+        assert!(verdict.is_spam);
+        assert!(verdict.reason.contains("free nitro"));
+        assert!(verdict.reason.contains("account under a day old"));
+
+        let input = ClassifierInput {
+            account_age: Some(Duration::days(30)),
+            trust: Some(TrustTier::New),
+            ..input
+        };
+        let verdict = HeuristicClassifier.classify(&input).await.unwrap();
+        assert!(verdict.reason.contains("new member"));
+    }
+
+    #[tokio::test]
+    async fn passes_ordinary_links() {
+        let verdict =
+            classify("There's a good answer at https://stackoverflow.com/questions/1").await;
+        assert!(!verdict.is_spam);
+        assert!(verdict.confidence <= HAM_CONFIDENCE);
+    }
+}
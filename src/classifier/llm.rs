@@ -0,0 +1,34 @@
+use super::{ClassifierInput, SpamClassifier, Verdict};
+use crate::llm::Llm;
+use crate::spam_detection::classify_message_spam;
+use serenity::async_trait;
+use std::sync::Arc;
+
+/// Asks the `spam` task's chat model via `classify_message_spam`.
+#[derive(Debug)]
+pub struct LlmClassifier {
+    llm: Arc<Llm>,
+}
+
+impl LlmClassifier {
+    pub fn new(llm: Arc<Llm>) -> LlmClassifier {
+        LlmClassifier { llm }
+    }
+}
+
+#[async_trait]
+impl SpamClassifier for LlmClassifier {
+    fn name(&self) -> &str {
+        "llm"
+    }
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict> {
+        let result = classify_message_spam(&self.llm, input).await?;
+        Ok(Verdict {
+            is_spam: result.is_spam,
+            confidence: result.confidence.clamp(0.0, 1.0),
+            category: result.category.filter(|_| result.is_spam),
+            reason: result.reason,
+        })
+    }
+}
@@ -0,0 +1,186 @@
+use crate::few_shot::Example;
+use crate::llm::Llm;
+use crate::trust::TrustTier;
+use chrono::Duration;
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+use serenity::async_trait;
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+mod bayes;
+mod combinator;
+mod heuristic;
+mod llm;
+
+pub use bayes::{BayesClassifier, NaiveBayes};
+pub use combinator::{Chain, Vote};
+pub use heuristic::HeuristicClassifier;
+pub use llm::LlmClassifier;
+
+/// Everything a classifier gets to look at for a single message.
+#[derive(Debug, Clone, Default)]
+pub struct ClassifierInput {
+    pub content: String,
+    /// Earlier messages from the same author, oldest first.
+    pub context: Vec<String>,
+    /// Time since the author's Discord account was created.
+    pub account_age: Option<Duration>,
+    /// Time since the author joined the guild, if known.
+    pub member_age: Option<Duration>,
+    /// Messages the author has posted in the guild, if known.
+    pub message_count: Option<u32>,
+    /// How far the pre-filter trusts the author.
+    pub trust: Option<TrustTier>,
+    /// Where the message was posted, so LLM usage is billed to that guild.
+    pub guild_id: Option<GuildId>,
+    /// Similar messages the guild's moderators labelled, for the LLM to learn from.
+    pub examples: Vec<Example>,
+}
+
+impl ClassifierInput {
+    /// The earlier messages and this one as a single conversation.
+    pub fn conversation(&self) -> String {
+        self.context
+            .iter()
+            .chain(std::iter::once(&self.content))
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// The kind of spam a verdict is about, as named in the spam prompt.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SpamCategory {
+    Phishing,
+    PaidPromotion,
+    SelfPromo,
+    Questionnaire,
+    Scam,
+    /// Anything else, including categories a model made up.
+    #[serde(other)]
+    Other,
+}
+
+impl SpamCategory {
+    pub const ALL: [SpamCategory; 6] = [
+        SpamCategory::Phishing,
+        SpamCategory::Scam,
+        SpamCategory::PaidPromotion,
+        SpamCategory::SelfPromo,
+        SpamCategory::Questionnaire,
+        SpamCategory::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SpamCategory::Phishing => "phishing",
+            SpamCategory::PaidPromotion => "paid_promotion",
+            SpamCategory::SelfPromo => "self_promo",
+            SpamCategory::Questionnaire => "questionnaire",
+            SpamCategory::Scam => "scam",
+            SpamCategory::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Verdict {
+    pub is_spam: bool,
+    /// How sure the classifier is of `is_spam`, from 0.0 to 1.0.
+    pub confidence: f32,
+    /// What kind of spam this is, if the classifier can tell.
+    pub category: Option<SpamCategory>,
+    pub reason: String,
+}
+
+impl Verdict {
+    /// One-line description for logs and warnings, e.g. `phishing (90%) - lure without explanation`.
+    pub fn summary(&self) -> String {
+        format!(
+            "{} ({:.0}%) - {}",
+            self.category.unwrap_or(SpamCategory::Other).as_str(),
+            self.confidence * 100.0,
+            self.reason
+        )
+    }
+}
+
+#[async_trait]
+pub trait SpamClassifier: Send + Sync + Debug {
+    /// Short name used in logs and verdict reasons.
+    fn name(&self) -> &str;
+
+    async fn classify(&self, input: &ClassifierInput) -> anyhow::Result<Verdict>;
+}
+
+fn default_min_confidence() -> f32 {
+    0.8
+}
+
+/// Which classifier(s) to run, set under `[classifier]` or `[guilds.<guild id>.classifier]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ClassifierConfig {
+    #[default]
+    Llm,
+    Heuristic,
+    /// A local model trained with the `train` subcommand.
+    Bayes {
+        model_path: PathBuf,
+    },
+    /// Runs `stages` in order, stopping at the first verdict at least `min_confidence` sure.
+    Chain {
+        stages: Vec<ClassifierConfig>,
+        #[serde(default = "default_min_confidence")]
+        min_confidence: f32,
+    },
+    /// Runs every member and sides with the larger total confidence.
+    Vote {
+        members: Vec<ClassifierConfig>,
+    },
+}
+
+impl ClassifierConfig {
+    pub fn build(&self, llm: &Arc<Llm>) -> anyhow::Result<Arc<dyn SpamClassifier>> {
+        Ok(match self {
+            ClassifierConfig::Llm => Arc::new(LlmClassifier::new(llm.clone())),
+            ClassifierConfig::Heuristic => Arc::new(HeuristicClassifier),
+            ClassifierConfig::Bayes { model_path } => {
+                Arc::new(BayesClassifier::new(NaiveBayes::load(model_path)?))
+            }
+            ClassifierConfig::Chain {
+                stages,
+                min_confidence,
+            } => {
+                if stages.is_empty() {
+                    anyhow::bail!("A `chain` classifier needs at least one stage")
+                }
+                if !(0.0..=1.0).contains(min_confidence) {
+                    anyhow::bail!("`min_confidence` must be between 0.0 and 1.0")
+                }
+                Arc::new(Chain::new(
+                    stages
+                        .iter()
+                        .map(|stage| stage.build(llm))
+                        .collect::<anyhow::Result<_>>()?,
+                    *min_confidence,
+                ))
+            }
+            ClassifierConfig::Vote { members } => {
+                if members.is_empty() {
+                    anyhow::bail!("A `vote` classifier needs at least one member")
+                }
+                Arc::new(Vote::new(
+                    members
+                        .iter()
+                        .map(|member| member.build(llm))
+                        .collect::<anyhow::Result<_>>()?,
+                ))
+            }
+        })
+    }
+}
@@ -0,0 +1,220 @@
+use crate::config;
+use crate::feedback;
+use crate::storage;
+use crate::usage::{Budget, Feature, Usage, UsageKey};
+use crate::user_info;
+use anyhow::bail;
+use chrono::{Days, NaiveDate, Utc};
+use serenity::all::{
+    CommandInteraction, CommandOptionType, CommandType, Context, CreateCommand,
+    CreateCommandOption, CreateInteractionResponse, CreateInteractionResponseMessage, GuildId,
+    Permissions, ResolvedTarget,
+};
+use std::collections::BTreeMap;
+use tracing::error;
+
+/// How many days `/stats cost` looks back over, including today.
+const COST_REPORT_DAYS: u64 = 7;
+
+/// Message context-menu commands that label a message for the dataset.
+const REPORT_AS_SPAM: &str = "Report as spam";
+const NOT_SPAM: &str = "Not spam";
+
+/// Every slash and context-menu command the bot offers. Only moderators see them.
+fn definitions() -> Vec<CreateCommand> {
+    vec![
+        CreateCommand::new("stats")
+            .description("How the bot is doing")
+            .default_member_permissions(Permissions::MANAGE_MESSAGES)
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::SubCommand,
+                "cost",
+                "LLM tokens and spend over the last week",
+            )),
+        CreateCommand::new(REPORT_AS_SPAM)
+            .kind(CommandType::Message)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+        CreateCommand::new(NOT_SPAM)
+            .kind(CommandType::Message)
+            .default_member_permissions(Permissions::MANAGE_MESSAGES),
+    ]
+}
+
+/// Replaces the slash commands in each guild with the current set.
+pub async fn register(ctx: &Context, guild_ids: impl Iterator<Item = GuildId>) {
+    for guild_id in guild_ids {
+        if let Err(e) = guild_id.set_commands(&ctx.http, definitions()).await {
+            error!("Failed to register slash commands in guild {guild_id} due to {e}")
+        }
+    }
+}
+
+pub async fn handle(ctx: &Context, command: &CommandInteraction) -> anyhow::Result<()> {
+    let Some(guild_id) = command.guild_id else {
+        bail!("Slash commands only work in a guild")
+    };
+    let subcommand = command
+        .data
+        .options
+        .first()
+        .map(|option| option.name.as_str());
+    let content = match (command.data.name.as_str(), subcommand) {
+        ("stats", Some("cost")) => cost_stats(ctx, guild_id).await?,
+        (REPORT_AS_SPAM, _) => label_message(ctx, guild_id, command, true).await?,
+        (NOT_SPAM, _) => label_message(ctx, guild_id, command, false).await?,
+        (name, subcommand) => bail!("Unknown command /{name} {subcommand:?}"),
+    };
+    command
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn cost_stats(ctx: &Context, guild_id: GuildId) -> anyhow::Result<String> {
+    let llm = config::get_config(ctx).await.llm.clone();
+    let storage = storage::get_storage(ctx).await;
+    storage.save_usage(llm.usage())?;
+    let today = Utc::now().date_naive();
+    let since = today - Days::new(COST_REPORT_DAYS - 1);
+    let mut rows: Vec<(UsageKey, Usage)> = storage
+        .load_usage(since)?
+        .into_iter()
+        .filter(|(key, _)| key.guild_id == Some(guild_id))
+        .collect();
+    rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let budget = llm.usage().budget(guild_id);
+    let paused = llm.usage().paused(today, guild_id);
+    Ok(cost_report(today, &rows, budget, &paused))
+}
+
+/// Adds the message a context-menu command was used on to the dataset.
+async fn label_message(
+    ctx: &Context,
+    guild_id: GuildId,
+    command: &CommandInteraction,
+    is_spam: bool,
+) -> anyhow::Result<String> {
+    let Some(ResolvedTarget::Message(message)) = command.data.target() else {
+        bail!("/{} wasn't used on a message", command.data.name)
+    };
+    let example = feedback::labelled_message(
+        message.content.as_str(),
+        is_spam,
+        message.timestamp.unix_timestamp(),
+        &user_info::trust_signals(ctx, guild_id, message).await,
+        message.mention_everyone,
+    );
+    let saved = feedback::save_label(
+        ctx,
+        (guild_id, Some(message.id)),
+        command.user.id,
+        (message.content.as_str(), &example),
+    )
+    .await?;
+    Ok(match (saved, is_spam) {
+        (false, _) => "That message is already labelled.".to_string(),
+        (true, true) => "Thanks - saved as spam for training.".to_string(),
+        (true, false) => "Thanks - saved as not spam for training.".to_string(),
+    })
+}
+
+fn describe(usage: &Usage) -> String {
+    format!(
+        "{} calls, {} prompt + {} completion tokens, ${:.4}",
+        usage.calls, usage.prompt_tokens, usage.completion_tokens, usage.cost_usd
+    )
+}
+
+/// Today's usage by task and model, then a line per day.
+fn cost_report(
+    today: NaiveDate,
+    rows: &[(UsageKey, Usage)],
+    budget: Option<&Budget>,
+    paused: &[Feature],
+) -> String {
+    let mut by_day: BTreeMap<NaiveDate, Usage> = BTreeMap::new();
+    let mut lines = vec!["**LLM usage in this server**".to_string()];
+    let mut today_total = Usage::default();
+    for (key, usage) in rows {
+        by_day.entry(key.day).or_default().add(usage);
+        if key.day == today {
+            today_total.add(usage);
+        }
+    }
+    lines.push(format!("Today: {}", describe(&today_total)));
+    if let Some(budget) = budget {
+        lines.push(format!("Daily cap: ${:.2}", budget.daily_usd));
+    }
+    if !paused.is_empty() {
+        let paused: Vec<&str> = paused.iter().map(Feature::as_str).collect();
+        lines.push(format!("Paused for today: {}", paused.join(", ")));
+    }
+    for (key, usage) in rows.iter().filter(|(key, _)| key.day == today) {
+        lines.push(format!(
+            "- {} ({}): {}",
+            key.task.as_str(),
+            key.model,
+            describe(usage)
+        ));
+    }
+    if by_day.len() > 1 {
+        lines.push(String::new());
+        for (day, usage) in by_day.iter().rev() {
+            lines.push(format!("{day}: {}", describe(usage)));
+        }
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::LlmTask;
+
+    #[test]
+    fn reports_cost() {
+        let today = NaiveDate::from_ymd_opt(2025, 1, 2).unwrap();
+        let row = |day: NaiveDate, task, cost_usd| {
+            (
+                UsageKey {
+                    day,
+                    guild_id: Some(GuildId::new(1)),
+                    task,
+                    model: "gpt-4.1-mini".to_string(),
+                },
+                Usage {
+                    calls: 2,
+                    prompt_tokens: 400,
+                    completion_tokens: 40,
+                    cost_usd,
+                },
+            )
+        };
+        let rows = [
+            row(today.pred_opt().unwrap(), LlmTask::Spam, 0.5),
+            row(today, LlmTask::Spam, 0.25),
+            row(today, LlmTask::RoadmapCreate, 0.5),
+        ];
+        let budget: Budget = toml::from_str("daily_usd = 1.0").unwrap();
+        let report = cost_report(today, &rows, Some(&budget), &[Feature::Roadmaps]);
+        assert_eq!(
+            report,
+            "**LLM usage in this server**\n\
+             Today: 4 calls, 800 prompt + 80 completion tokens, $0.7500\n\
+             Daily cap: $1.00\n\
+             Paused for today: roadmaps\n\
+             - spam (gpt-4.1-mini): 2 calls, 400 prompt + 40 completion tokens, $0.2500\n\
+             - roadmap_create (gpt-4.1-mini): 2 calls, 400 prompt + 40 completion tokens, $0.5000\n\
+             \n\
+             2025-01-02: 4 calls, 800 prompt + 80 completion tokens, $0.7500\n\
+             2025-01-01: 2 calls, 400 prompt + 40 completion tokens, $0.5000"
+        );
+    }
+}
@@ -0,0 +1,890 @@
+use crate::classifier::{ClassifierConfig, SpamCategory, SpamClassifier, Verdict};
+use crate::consts::DEFAULT_VAGUELY_OKAY_WEBSITES;
+use crate::domain_lists::{DomainList, DomainMatcher};
+use crate::few_shot::{ExampleBank, FewShot};
+use crate::llm::{Llm, LlmConfig};
+use crate::review::ReviewConfig;
+use crate::spam_templates::SpamTemplates;
+use crate::spam_waves::{SpamWaves, WaveDetector};
+use crate::strikes::Strikes;
+use crate::trust::{Trust, TrustConfig, TrustSignals, TrustTier};
+use crate::usage::Budget;
+use anyhow::{bail, Context as _};
+use serde::Deserialize;
+use serenity::all::{ChannelId, Context, EmojiId, GuildId, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Location of the config file when `BSE_CONFIG` isn't set.
+pub(crate) const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+fn default_database_path() -> PathBuf {
+    PathBuf::from("big_spam_eater.sqlite3")
+}
+
+fn default_dataset_path() -> PathBuf {
+    PathBuf::from("labelled.jsonl")
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    spam_eater_id: u64,
+    /// Default chat model for every LLM task.
+    model: String,
+    #[serde(default)]
+    llm: LlmConfig,
+    #[serde(default = "default_database_path")]
+    database_path: PathBuf,
+    #[serde(default = "default_dataset_path")]
+    dataset_path: PathBuf,
+    #[serde(default)]
+    classifier: ClassifierConfig,
+    #[serde(default)]
+    guilds: HashMap<String, RawGuildConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawGuildConfig {
+    bot_channel: u64,
+    honey_pot_channel: u64,
+    blunder_emoji_id: u64,
+    vaguely_okay_websites: Option<Vec<String>>,
+    #[serde(default)]
+    allowlist_files: Vec<PathBuf>,
+    #[serde(default)]
+    blocked_websites: Vec<String>,
+    #[serde(default)]
+    blocklist_files: Vec<PathBuf>,
+    #[serde(default)]
+    thresholds: Thresholds,
+    #[serde(default)]
+    features: Features,
+    #[serde(default)]
+    spam_actions: SpamActions,
+    #[serde(default)]
+    spam_waves: SpamWaves,
+    #[serde(default)]
+    strikes: Strikes,
+    #[serde(default)]
+    review: ReviewConfig,
+    #[serde(default)]
+    few_shot: FewShot,
+    #[serde(default)]
+    trust: TrustConfig,
+    #[serde(default)]
+    shadow: Shadow,
+    #[serde(default)]
+    llm_failure: LlmFailure,
+    budget: Option<Budget>,
+    classifier: Option<ClassifierConfig>,
+}
+
+/// Settings shared by every guild the bot is running in.
+#[derive(Debug)]
+pub struct Config {
+    pub spam_eater_id: UserId,
+    pub llm: Arc<Llm>,
+    /// SQLite file holding join dates, message history and moderation actions.
+    pub database_path: PathBuf,
+    /// JSONL corpus moderators' labels are appended to.
+    pub dataset_path: PathBuf,
+    guilds: HashMap<GuildId, Arc<GuildConfig>>,
+}
+
+/// Settings for a single guild, keyed by guild ID in the config file.
+#[derive(Debug)]
+pub struct GuildConfig {
+    pub bot_channel: ChannelId,
+    pub honey_pot_channel: ChannelId,
+    pub blunder_emoji_id: EmojiId,
+    /// `vaguely_okay_websites` plus every `allowlist_files` entry.
+    pub allowlist: DomainMatcher,
+    /// `blocked_websites` plus every `blocklist_files` entry. Links to these skip the classifier.
+    pub blocklist: DomainMatcher,
+    pub thresholds: Thresholds,
+    pub features: Features,
+    pub spam_actions: SpamActions,
+    pub shadow: Shadow,
+    pub llm_failure: LlmFailure,
+    /// Built from the guild's `classifier` table, or the top-level one if it has none.
+    pub classifier: Arc<dyn SpamClassifier>,
+    /// Spam removed recently, to catch copies of it.
+    pub spam_templates: SpamTemplates,
+    /// Recent messages, to catch the same one posted everywhere at once.
+    pub wave_detector: WaveDetector,
+    pub strikes: Strikes,
+    pub review: ReviewConfig,
+    /// Messages moderators labelled, to pick few-shot examples for the spam prompt from.
+    pub examples: ExampleBank,
+    pub trust: Trust,
+}
+
+/// Tunable limits for a guild, set under `[guilds.<guild id>.thresholds]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// Members who joined less than this many minutes ago are treated as new.
+    pub new_user_window_minutes: i64,
+    /// How far back the author's earlier messages are shown to the classifier.
+    pub context_window_minutes: i64,
+    /// Number of blunder reactions needed before the author gets a strike.
+    pub blunder_reactions: u8,
+    /// Blunder reactions only count on messages younger than this.
+    pub blunder_window_minutes: i64,
+    /// Days of the author's messages Discord removes alongside a honeypot ban.
+    pub honey_pot_delete_message_days: u8,
+    /// How many bits a message's fingerprint may differ from removed spam and still
+    /// count as a copy of it. 0 only catches exact copies.
+    pub near_duplicate_bits: u32,
+    /// How long removed spam is remembered for catching copies.
+    pub near_duplicate_hours: u64,
+    /// When a member is timed out, kicked or banned for spam, their messages from this
+    /// many minutes before are removed too. 0 only removes the spam itself.
+    pub purge_window_minutes: i64,
+    /// Deprecated: sets the first timeout of `strikes.ladder`, in hours.
+    pub spam_timeout_hours: Option<i64>,
+    /// Deprecated: sets the first timeout of `strikes.blunder_ladder`.
+    pub blunder_timeout_minutes: Option<i64>,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Thresholds {
+            new_user_window_minutes: 120,
+            context_window_minutes: 5,
+            blunder_reactions: 4,
+            blunder_window_minutes: 15,
+            honey_pot_delete_message_days: 7,
+            near_duplicate_bits: 10,
+            near_duplicate_hours: 72,
+            purge_window_minutes: 60,
+            spam_timeout_hours: None,
+            blunder_timeout_minutes: None,
+        }
+    }
+}
+
+/// Per-guild switches, set under `[guilds.<guild id>.features]`. Everything is on by default.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub honey_pot: bool,
+    pub spam_detection: bool,
+    pub spam_waves: bool,
+    pub blunder_timeouts: bool,
+    pub requests: bool,
+    pub roadmaps: bool,
+    pub ask: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            honey_pot: true,
+            spam_detection: true,
+            spam_waves: true,
+            blunder_timeouts: true,
+            requests: true,
+            roadmaps: true,
+            ask: true,
+        }
+    }
+}
+
+/// The rules that can moderate a message, so each can be shadowed separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    HoneyPot,
+    Blocklist,
+    SpamWave,
+    /// A classifier verdict, or `None` when classification failed.
+    Classifier(Option<SpamCategory>),
+}
+
+/// Rules to run in shadow mode, set under `[guilds.<guild id>.shadow]`. A shadowed rule
+/// posts what it would have done to the bot channel instead of doing it. Everything is off
+/// by default; single spam categories can also be shadowed with `spam_actions.<category>.shadow`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shadow {
+    /// Shadow every rule in the guild.
+    pub all: bool,
+    pub honey_pot: bool,
+    pub blocklist: bool,
+    pub spam_waves: bool,
+    /// Every classifier verdict, whatever its category.
+    pub spam_detection: bool,
+}
+
+/// What a handler does when it can't get an answer from the LLM.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FailurePolicy {
+    /// Carry on as if nothing was asked: let the message through, or don't reply.
+    FailOpen,
+    /// Treat it as a no: remove the message and warn its author, or tell them the
+    /// bot can't help right now.
+    FailClosed,
+    /// Take the message down quietly, or leave the request unanswered, and post it to
+    /// the bot channel for a moderator.
+    HoldForReview,
+}
+
+impl FailurePolicy {
+    /// What happens to a message under this policy, for notices in the bot channel.
+    pub fn describe(&self) -> &'static str {
+        match self {
+            FailurePolicy::FailOpen => "let through unchecked",
+            FailurePolicy::FailClosed => "removed with a warning to the author",
+            FailurePolicy::HoldForReview => "taken down and posted here for review",
+        }
+    }
+}
+
+/// Per-handler failure policies, set under `[guilds.<guild id>.llm_failure]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmFailure {
+    pub spam_detection: FailurePolicy,
+    pub requests: FailurePolicy,
+    pub roadmaps: FailurePolicy,
+}
+
+impl Default for LlmFailure {
+    fn default() -> Self {
+        LlmFailure {
+            spam_detection: FailurePolicy::HoldForReview,
+            requests: FailurePolicy::FailOpen,
+            roadmaps: FailurePolicy::FailOpen,
+        }
+    }
+}
+
+impl Thresholds {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.new_user_window_minutes < 0
+            || self.context_window_minutes < 0
+            || self.purge_window_minutes < 0
+            || self.blunder_window_minutes < 0
+        {
+            bail!("Windows in `thresholds` must not be negative")
+        }
+        // Discord returns at most 100 reacting users per request.
+        if !(1..=100).contains(&self.blunder_reactions) {
+            bail!("`thresholds.blunder_reactions` must be between 1 and 100")
+        }
+        if self.honey_pot_delete_message_days > 7 {
+            bail!("`thresholds.honey_pot_delete_message_days` must be at most 7")
+        }
+        if self.near_duplicate_bits > 32 {
+            bail!("`thresholds.near_duplicate_bits` must be at most 32")
+        }
+        // Discord refuses timeouts longer than 28 days.
+        if self
+            .spam_timeout_hours
+            .is_some_and(|hours| !(1..=28 * 24).contains(&hours))
+        {
+            bail!("`thresholds.spam_timeout_hours` must be between 1 and 672")
+        }
+        if self
+            .blunder_timeout_minutes
+            .is_some_and(|minutes| !(1..=28 * 1_440).contains(&minutes))
+        {
+            bail!("`thresholds.blunder_timeout_minutes` must be between 1 and 40320")
+        }
+        Ok(())
+    }
+}
+
+/// What to do about a message the classifier thinks is spam, from mildest to harshest.
+/// What then happens to its author follows the strike ladder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SpamAction {
+    /// Leave the message up.
+    Warn,
+    /// Remove the message.
+    Delete,
+    /// Remove the message, for the heavier strike weight that escalates its author.
+    Escalate,
+}
+
+/// Minimum confidence for each action on one category of spam.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ActionThresholds {
+    #[serde(default)]
+    pub warn: f32,
+    pub delete: f32,
+    /// Leave unset to never give the heavier strikes for this category.
+    #[serde(alias = "timeout")]
+    pub escalate: Option<f32>,
+    /// Only report what would have been done about this category.
+    #[serde(default)]
+    pub shadow: bool,
+}
+
+impl ActionThresholds {
+    fn new(delete: f32, escalate: Option<f32>) -> ActionThresholds {
+        ActionThresholds {
+            warn: 0.0,
+            delete,
+            escalate,
+            shadow: false,
+        }
+    }
+
+    fn action_for(&self, confidence: f32) -> Option<SpamAction> {
+        if self.escalate.is_some_and(|escalate| confidence >= escalate) {
+            Some(SpamAction::Escalate)
+        } else if confidence >= self.delete {
+            Some(SpamAction::Delete)
+        } else if confidence >= self.warn {
+            Some(SpamAction::Warn)
+        } else {
+            None
+        }
+    }
+
+    fn validate(&self, category: SpamCategory) -> anyhow::Result<()> {
+        let thresholds = [Some(self.warn), Some(self.delete), self.escalate];
+        if thresholds
+            .iter()
+            .flatten()
+            .any(|threshold| !(0.0..=1.0).contains(threshold))
+        {
+            bail!(
+                "`spam_actions.{}` thresholds must be between 0.0 and 1.0",
+                category.as_str()
+            )
+        }
+        if self.warn > self.delete || self.escalate.is_some_and(|escalate| self.delete > escalate) {
+            bail!(
+                "`spam_actions.{}` thresholds must satisfy warn <= delete <= escalate",
+                category.as_str()
+            )
+        }
+        Ok(())
+    }
+}
+
+/// Per-category thresholds, set under `[guilds.<guild id>.spam_actions.<category>]`.
+/// A category table replaces that category's defaults entirely.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamActions {
+    pub phishing: ActionThresholds,
+    pub scam: ActionThresholds,
+    pub paid_promotion: ActionThresholds,
+    pub self_promo: ActionThresholds,
+    pub questionnaire: ActionThresholds,
+    /// Used when the classifier gives no category, or one not listed here.
+    pub other: ActionThresholds,
+}
+
+impl Default for SpamActions {
+    fn default() -> Self {
+        SpamActions {
+            phishing: ActionThresholds::new(0.0, Some(0.7)),
+            scam: ActionThresholds::new(0.0, Some(0.7)),
+            paid_promotion: ActionThresholds::new(0.5, Some(0.9)),
+            self_promo: ActionThresholds::new(0.7, Some(0.95)),
+            questionnaire: ActionThresholds::new(0.6, None),
+            other: ActionThresholds::new(0.0, Some(0.8)),
+        }
+    }
+}
+
+impl SpamActions {
+    fn thresholds(&self, category: SpamCategory) -> &ActionThresholds {
+        match category {
+            SpamCategory::Phishing => &self.phishing,
+            SpamCategory::Scam => &self.scam,
+            SpamCategory::PaidPromotion => &self.paid_promotion,
+            SpamCategory::SelfPromo => &self.self_promo,
+            SpamCategory::Questionnaire => &self.questionnaire,
+            SpamCategory::Other => &self.other,
+        }
+    }
+
+    /// `None` when the verdict isn't spam, or isn't confident enough to act on.
+    pub fn action_for(&self, verdict: &Verdict) -> Option<SpamAction> {
+        if !verdict.is_spam {
+            return None;
+        }
+        self.thresholds(verdict.category.unwrap_or(SpamCategory::Other))
+            .action_for(verdict.confidence)
+    }
+
+    fn is_shadowed(&self, category: SpamCategory) -> bool {
+        self.thresholds(category).shadow
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        for category in SpamCategory::ALL {
+            self.thresholds(category).validate(category)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct BotConfig;
+
+impl TypeMapKey for BotConfig {
+    type Value = Arc<Config>;
+}
+
+fn non_zero_id(field: &str, value: u64) -> anyhow::Result<u64> {
+    if value == 0 {
+        bail!("`{field}` must be a Discord ID, not 0")
+    }
+    Ok(value)
+}
+
+/// List files already loaded, so guilds sharing a large list share one copy.
+type ListCache = HashMap<PathBuf, Arc<DomainList>>;
+
+fn load_domain_lists(
+    field: &str,
+    inline: &[String],
+    files: &[PathBuf],
+    cache: &mut ListCache,
+) -> anyhow::Result<DomainMatcher> {
+    let mut lists = vec![Arc::new(
+        DomainList::from_entries(inline).with_context(|| format!("Invalid entry in `{field}`"))?,
+    )];
+    for path in files {
+        let list = match cache.get(path) {
+            Some(list) => list.clone(),
+            None => {
+                let list = Arc::new(DomainList::load(path)?);
+                cache.insert(path.clone(), list.clone());
+                list
+            }
+        };
+        lists.push(list);
+    }
+    Ok(DomainMatcher::new(lists))
+}
+
+impl GuildConfig {
+    fn from_raw(
+        mut raw: RawGuildConfig,
+        llm: &Arc<Llm>,
+        default_classifier: &ClassifierConfig,
+        list_cache: &mut ListCache,
+    ) -> anyhow::Result<GuildConfig> {
+        let bot_channel = non_zero_id("bot_channel", raw.bot_channel)?;
+        let honey_pot_channel = non_zero_id("honey_pot_channel", raw.honey_pot_channel)?;
+        if bot_channel == honey_pot_channel {
+            bail!("`bot_channel` and `honey_pot_channel` must be different channels")
+        }
+        let websites = raw.vaguely_okay_websites.unwrap_or_else(|| {
+            DEFAULT_VAGUELY_OKAY_WEBSITES
+                .iter()
+                .map(|website| website.to_string())
+                .collect()
+        });
+        let allowlist = load_domain_lists(
+            "vaguely_okay_websites",
+            &websites,
+            &raw.allowlist_files,
+            list_cache,
+        )?;
+        let blocklist = load_domain_lists(
+            "blocked_websites",
+            &raw.blocked_websites,
+            &raw.blocklist_files,
+            list_cache,
+        )?;
+        raw.thresholds.validate()?;
+        if let Some(hours) = raw.thresholds.spam_timeout_hours {
+            warn!("`thresholds.spam_timeout_hours` is deprecated, set the timeouts in `strikes.ladder` instead");
+            raw.strikes.set_first_timeout(false, hours * 60);
+        }
+        if let Some(minutes) = raw.thresholds.blunder_timeout_minutes {
+            warn!("`thresholds.blunder_timeout_minutes` is deprecated, set the timeouts in `strikes.blunder_ladder` instead");
+            raw.strikes.set_first_timeout(true, minutes);
+        }
+        raw.spam_actions.validate()?;
+        raw.spam_waves.validate()?;
+        raw.strikes.validate()?;
+        raw.review.validate()?;
+        raw.few_shot.validate()?;
+        raw.trust.validate()?;
+        let classifier = raw
+            .classifier
+            .as_ref()
+            .unwrap_or(default_classifier)
+            .build(llm)
+            .context("Invalid `classifier` settings")?;
+        let spam_templates = SpamTemplates::new(
+            raw.thresholds.near_duplicate_bits,
+            Duration::from_secs(raw.thresholds.near_duplicate_hours.saturating_mul(3_600)),
+        );
+        let wave_detector = WaveDetector::new(raw.spam_waves, raw.thresholds.near_duplicate_bits);
+        Ok(GuildConfig {
+            bot_channel: ChannelId::new(bot_channel),
+            honey_pot_channel: ChannelId::new(honey_pot_channel),
+            blunder_emoji_id: EmojiId::new(non_zero_id("blunder_emoji_id", raw.blunder_emoji_id)?),
+            allowlist,
+            blocklist,
+            thresholds: raw.thresholds,
+            features: raw.features,
+            spam_actions: raw.spam_actions,
+            shadow: raw.shadow,
+            llm_failure: raw.llm_failure,
+            classifier,
+            spam_templates,
+            wave_detector,
+            strikes: raw.strikes,
+            review: raw.review,
+            examples: ExampleBank::new(raw.few_shot),
+            trust: Trust::new(raw.trust),
+        })
+    }
+
+    /// How far the author behind `signals` is trusted at `now`.
+    pub fn trust_tier(&self, signals: &TrustSignals, now: i64) -> TrustTier {
+        let join_window = chrono::Duration::minutes(self.thresholds.new_user_window_minutes);
+        self.trust.tier(signals, join_window, now)
+    }
+
+    /// Should `rule` only report what it would have done?
+    pub fn is_shadowed(&self, rule: Rule) -> bool {
+        self.shadow.all
+            || match rule {
+                Rule::HoneyPot => self.shadow.honey_pot,
+                Rule::Blocklist => self.shadow.blocklist,
+                Rule::SpamWave => self.shadow.spam_waves,
+                Rule::Classifier(category) => {
+                    self.shadow.spam_detection
+                        || category.is_some_and(|category| self.spam_actions.is_shadowed(category))
+                }
+            }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read config file {}", path.display()))?;
+        Config::from_toml(contents.as_str())
+            .with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    pub fn from_toml(contents: &str) -> anyhow::Result<Config> {
+        let raw: RawConfig = toml::from_str(contents)?;
+        if raw.model.trim().is_empty() {
+            bail!("`model` must not be empty")
+        }
+        if raw.guilds.is_empty() {
+            bail!("No guilds configured - add at least one `[guilds.<guild id>]` table")
+        }
+        let mut raw_guilds = Vec::with_capacity(raw.guilds.len());
+        let mut budgets = HashMap::new();
+        for (key, raw_guild) in raw.guilds {
+            let guild_id = key
+                .parse::<u64>()
+                .ok()
+                .filter(|id| *id != 0)
+                .map(GuildId::new)
+                .with_context(|| format!("`[guilds.{key}]` is not a valid guild ID"))?;
+            if let Some(budget) = &raw_guild.budget {
+                budget
+                    .validate()
+                    .with_context(|| format!("Invalid settings in `[guilds.{key}]`"))?;
+                budgets.insert(guild_id, budget.clone());
+            }
+            raw_guilds.push((key, guild_id, raw_guild));
+        }
+        let llm = Arc::new(
+            raw.llm
+                .build(raw.model.as_str())
+                .context("Invalid `llm` settings")?
+                .with_budgets(budgets),
+        );
+        let mut guilds = HashMap::with_capacity(raw_guilds.len());
+        let mut list_cache = ListCache::new();
+        for (key, guild_id, raw_guild) in raw_guilds {
+            let guild_config =
+                GuildConfig::from_raw(raw_guild, &llm, &raw.classifier, &mut list_cache)
+                    .with_context(|| format!("Invalid settings in `[guilds.{key}]`"))?;
+            info!(
+                "Guild {key}: {} allowlisted and {} blocklisted domains",
+                guild_config.allowlist.domain_count(),
+                guild_config.blocklist.domain_count()
+            );
+            guilds.insert(guild_id, Arc::new(guild_config));
+        }
+        Ok(Config {
+            spam_eater_id: UserId::new(non_zero_id("spam_eater_id", raw.spam_eater_id)?),
+            llm,
+            database_path: raw.database_path,
+            dataset_path: raw.dataset_path,
+            guilds,
+        })
+    }
+
+    pub fn guild(&self, guild_id: GuildId) -> Option<Arc<GuildConfig>> {
+        self.guilds.get(&guild_id).cloned()
+    }
+
+    pub fn guild_ids(&self) -> impl Iterator<Item = GuildId> + '_ {
+        self.guilds.keys().copied()
+    }
+}
+
+pub async fn get_config(ctx: &Context) -> Arc<Config> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<BotConfig>()
+        .expect("Expected BotConfig in TypeMap.")
+        .clone()
+}
+
+/// Returns `None` for DMs and for guilds missing from the config file.
+pub async fn get_guild_config(
+    ctx: &Context,
+    guild_id: Option<GuildId>,
+) -> Option<Arc<GuildConfig>> {
+    get_config(ctx).await.guild(guild_id?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strikes::Step;
+    use crate::usage::Feature;
+
+    const EXAMPLE: &str = r#"
+spam_eater_id = 1091478027264868422
+model = "gpt-4.1-mini"
+
+[guilds.889466095810011130]
+bot_channel = 1091681853603324047
+honey_pot_channel = 889466095810011137
+blunder_emoji_id = 1134914979078864926
+"#;
+
+    #[test]
+    fn parse_example() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let guild = config.guild(GuildId::new(889466095810011130)).unwrap();
+        assert_eq!(guild.honey_pot_channel, ChannelId::new(889466095810011137));
+        assert_eq!(
+            guild.allowlist.domain_count(),
+            DEFAULT_VAGUELY_OKAY_WEBSITES.len()
+        );
+        assert!(guild.allowlist.matches("gist.github.com"));
+        assert_eq!(guild.blocklist.domain_count(), 0);
+        assert!(config.guild(GuildId::new(1)).is_none());
+        assert!(guild.features.honey_pot);
+        assert_eq!(guild.thresholds.blunder_reactions, 4);
+    }
+
+    #[test]
+    fn parse_guild_overrides() {
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.thresholds]\nblunder_reactions = 6\n\n[guilds.889466095810011130.features]\nroadmaps = false\n"
+        );
+        let config = Config::from_toml(&contents).unwrap();
+        let guild = config.guild(GuildId::new(889466095810011130)).unwrap();
+        assert_eq!(guild.thresholds.blunder_reactions, 6);
+        assert_eq!(guild.thresholds.blunder_window_minutes, 15);
+        assert!(!guild.features.roadmaps);
+        assert!(guild.features.requests);
+        assert_eq!(
+            guild.llm_failure.spam_detection,
+            FailurePolicy::HoldForReview
+        );
+
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.llm_failure]\nspam_detection = \"fail_open\"\nrequests = \"fail_closed\"\n"
+        );
+        let guild = Config::from_toml(&contents)
+            .unwrap()
+            .guild(GuildId::new(889466095810011130))
+            .unwrap();
+        assert_eq!(guild.llm_failure.spam_detection, FailurePolicy::FailOpen);
+        assert_eq!(guild.llm_failure.requests, FailurePolicy::FailClosed);
+        assert_eq!(guild.llm_failure.roadmaps, FailurePolicy::FailOpen);
+
+        // Configs from before the strike ladder still load.
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.thresholds]\nspam_timeout_hours = 48\nblunder_timeout_minutes = 5\n"
+        );
+        let guild = Config::from_toml(&contents)
+            .unwrap()
+            .guild(GuildId::new(889466095810011130))
+            .unwrap();
+        assert_eq!(guild.strikes.ladder[1], Step::Timeout { minutes: 2_880 });
+        assert_eq!(
+            guild.strikes.blunder_ladder[0],
+            Step::Timeout { minutes: 5 }
+        );
+
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.budget]\ndaily_usd = 2.0\nroadmaps = 0.25\n"
+        );
+        let config = Config::from_toml(&contents).unwrap();
+        let budget = config
+            .llm
+            .usage()
+            .budget(GuildId::new(889466095810011130))
+            .unwrap();
+        assert_eq!(budget.limit(Feature::Roadmaps), 0.5);
+        assert_eq!(budget.limit(Feature::SpamDetection), 2.0);
+    }
+
+    #[test]
+    fn parse_domain_lists() {
+        let list_path =
+            std::env::temp_dir().join(format!("bse_config_blocklist-{}.txt", std::process::id()));
+        std::fs::write(&list_path, "0.0.0.0 steamcommunlty.com\n").unwrap();
+        let contents = EXAMPLE.replace(
+            "blunder_emoji_id = 1134914979078864926\n",
+            &format!(
+                "blunder_emoji_id = 1134914979078864926\nblocked_websites = [\"*.evil.ru\"]\nblocklist_files = [{:?}]\n",
+                list_path.display().to_string()
+            ),
+        );
+        let config = Config::from_toml(&contents).unwrap();
+        let guild = config.guild(GuildId::new(889466095810011130)).unwrap();
+        assert!(guild.blocklist.matches("login.evil.ru"));
+        assert!(guild.blocklist.matches("steamcommunlty.com"));
+        assert!(!guild.blocklist.matches("github.com"));
+        std::fs::remove_file(&list_path).unwrap();
+
+        let missing = EXAMPLE.replace(
+            "blunder_emoji_id = 1134914979078864926\n",
+            "blunder_emoji_id = 1134914979078864926\nblocklist_files = [\"does/not/exist.txt\"]\n",
+        );
+        assert!(Config::from_toml(&missing).is_err());
+    }
+
+    #[test]
+    fn shadows_rules() {
+        let guild = |extra: &str| {
+            Config::from_toml(&format!("{EXAMPLE}\n{extra}"))
+                .unwrap()
+                .guild(GuildId::new(889466095810011130))
+                .unwrap()
+        };
+        let default = guild("");
+        assert!(!default.is_shadowed(Rule::HoneyPot));
+        assert!(!default.is_shadowed(Rule::Classifier(Some(SpamCategory::Scam))));
+
+        let per_rule = guild(
+            "[guilds.889466095810011130.shadow]\nhoney_pot = true\n\
+             [guilds.889466095810011130.spam_actions.scam]\ndelete = 0.5\nshadow = true\n",
+        );
+        assert!(per_rule.is_shadowed(Rule::HoneyPot));
+        assert!(!per_rule.is_shadowed(Rule::Blocklist));
+        assert!(!per_rule.is_shadowed(Rule::SpamWave));
+        assert!(per_rule.is_shadowed(Rule::Classifier(Some(SpamCategory::Scam))));
+        assert!(!per_rule.is_shadowed(Rule::Classifier(Some(SpamCategory::Phishing))));
+        assert!(!per_rule.is_shadowed(Rule::Classifier(None)));
+
+        let everything = guild("[guilds.889466095810011130.shadow]\nall = true\n");
+        assert!(everything.is_shadowed(Rule::Blocklist));
+        assert!(everything.is_shadowed(Rule::SpamWave));
+        assert!(everything.is_shadowed(Rule::Classifier(None)));
+    }
+
+    #[test]
+    fn grades_spam_actions() {
+        let verdict = |category, confidence| Verdict {
+            is_spam: true,
+            confidence,
+            category: Some(category),
+            reason: String::new(),
+        };
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.spam_actions.self_promo]\nwarn = 0.2\ndelete = 0.8\n"
+        );
+        let config = Config::from_toml(&contents).unwrap();
+        let actions = &config
+            .guild(GuildId::new(889466095810011130))
+            .unwrap()
+            .spam_actions;
+        assert_eq!(
+            actions.action_for(&verdict(SpamCategory::SelfPromo, 0.1)),
+            None
+        );
+        assert_eq!(
+            actions.action_for(&verdict(SpamCategory::SelfPromo, 0.5)),
+            Some(SpamAction::Warn)
+        );
+        assert_eq!(
+            actions.action_for(&verdict(SpamCategory::SelfPromo, 0.99)),
+            Some(SpamAction::Delete)
+        );
+        assert_eq!(
+            actions.action_for(&verdict(SpamCategory::Phishing, 0.9)),
+            Some(SpamAction::Escalate)
+        );
+        assert_eq!(
+            actions.action_for(&Verdict {
+                is_spam: false,
+                ..verdict(SpamCategory::Phishing, 0.9)
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_classifier() {
+        let config = Config::from_toml(EXAMPLE).unwrap();
+        let guild = config.guild(GuildId::new(889466095810011130)).unwrap();
+        assert_eq!(guild.classifier.name(), "llm");
+
+        let contents = format!(
+            "{EXAMPLE}\n[guilds.889466095810011130.classifier]\nkind = \"chain\"\nstages = [{{ kind = \"heuristic\" }}, {{ kind = \"llm\" }}]\n"
+        );
+        let config = Config::from_toml(&contents).unwrap();
+        let guild = config.guild(GuildId::new(889466095810011130)).unwrap();
+        assert_eq!(guild.classifier.name(), "chain");
+    }
+
+    #[test]
+    fn rejects_invalid_entries() {
+        let invalid = [
+            EXAMPLE.replace("[guilds.889466095810011130]", "[guilds.staging]"),
+            EXAMPLE.replace("bot_channel = 1091681853603324047\n", ""),
+            EXAMPLE.replace("1134914979078864926", "0"),
+            EXAMPLE.replace("1091681853603324047", "889466095810011137"),
+            EXAMPLE.replace("model = \"gpt-4.1-mini\"", "model = \"\""),
+            format!("{EXAMPLE}vaguely_okay_websites = [\"https://github.com\"]\n"),
+            format!("{EXAMPLE}honeypot_channel = 1\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.thresholds]\nblunder_reactions = 0\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.thresholds]\nnear_duplicate_bits = 40\n"),
+            EXAMPLE.replace("[guilds.", "[llm.cache]\nttl_minutes = 0\n\n[guilds."),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.features]\nhoneypot = false\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.spam_waves]\nchannels = 1\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.thresholds]\nspam_timeout_hours = 0\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.strikes]\nladder = [{{ action = \"timeout\", minutes = 0 }}]\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.spam_waves]\naction = \"kick\"\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.review]\nquarantine_hours = 700\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.few_shot]\ntoken_budget = 0\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.trust]\nnew_account_days = -1\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.classifier]\nkind = \"vote\"\nmembers = []\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.classifier]\nkind = \"magic\"\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.llm_failure]\nspam_detection = \"panic\"\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.budget]\ndaily_usd = 0.0\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.budget]\ndaily_usd = 2.0\nroadmaps = 1.5\n"),
+            format!("{EXAMPLE}\n[guilds.889466095810011130.spam_actions.scam]\ndelete = 0.9\ntimeout = 0.5\n"),
+        ];
+        for contents in invalid {
+            assert!(
+                Config::from_toml(&contents).is_err(),
+                "Accepted: {contents}"
+            );
+        }
+    }
+}
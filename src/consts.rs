@@ -1,4 +1,5 @@
-pub(crate) const VAGUELY_OKAY_WEBSITES: [&str; 7] = [
+/// Used when a guild's config doesn't list its own `vaguely_okay_websites`.
+pub(crate) const DEFAULT_VAGUELY_OKAY_WEBSITES: [&str; 7] = [
     "github.com",
     "bitbucket.com",
     "stackoverflow.com",
@@ -7,10 +8,3 @@ pub(crate) const VAGUELY_OKAY_WEBSITES: [&str; 7] = [
     "mit.edu",
     "usc.edu",
 ];
-
-pub(crate) const BLUNDER_EMOJI_ID: u64 = 1134914979078864926;
-pub(crate) const BOT_CHANNEL: u64 = 1091681853603324047;
-pub(crate) const HONEY_POT_CHANNEL: u64 = 889466095810011137;
-pub(crate) const SPAM_EATER_ID: u64 = 1091478027264868422;
-
-pub(crate) const MODEL_USED: &str = "gpt-4.1-mini";
@@ -0,0 +1,51 @@
+use crate::classifier::SpamCategory;
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+/// One line of a labelled JSONL corpus, e.g.
+/// `{"content": "free nitro https://discord.gg/abc", "is_spam": true}`.
+/// The optional fields let `backtest` replay the rest of the pipeline.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LabelledMessage {
+    pub content: String,
+    pub is_spam: bool,
+    /// What kind of spam this is, for per-category metrics.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<SpamCategory>,
+    /// The author's earlier messages, oldest first.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub context: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_age_days: Option<i64>,
+    /// Unknown join dates count for nothing either way, as they do live.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub member_age_minutes: Option<i64>,
+    /// Messages the author had posted in the guild.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub mention_everyone: bool,
+}
+
+/// Reads a JSONL corpus, skipping blank lines.
+pub fn read_corpus(path: &Path) -> anyhow::Result<Vec<LabelledMessage>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Couldn't open corpus {}", path.display()))?;
+    let mut messages = vec![];
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        messages.push(serde_json::from_str(line.as_str()).with_context(|| {
+            format!(
+                "Invalid entry on line {} of {}",
+                line_number + 1,
+                path.display()
+            )
+        })?);
+    }
+    Ok(messages)
+}
@@ -0,0 +1,199 @@
+use anyhow::{bail, Context as _};
+use regex::RegexSet;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Hosts-file entries that point the machine at itself rather than block anything.
+const HOSTS_FILE_NAMES: [&str; 4] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+];
+
+/// A set of domains, loaded from config or a list file.
+///
+/// `example.com` covers the domain and all its subdomains, `*.example.com` only
+/// its subdomains, and any other `*` is a glob over the whole host. Plain and
+/// subdomain entries are looked up one label at a time, so a host costs a
+/// handful of hash lookups however long the list is.
+#[derive(Debug, Default)]
+pub struct DomainList {
+    domains: HashSet<String>,
+    subdomains_only: HashSet<String>,
+    globs: Vec<String>,
+    glob_set: Option<RegexSet>,
+}
+
+fn validate_domain(domain: &str) -> anyhow::Result<()> {
+    if domain.is_empty() || domain.starts_with('.') || domain.ends_with('.') {
+        bail!("`{domain}` isn't a domain")
+    }
+    if domain.contains("://") || domain.contains('/') {
+        bail!("`{domain}` should be a bare domain, e.g. `github.com`")
+    }
+    if let Some(c) = domain
+        .chars()
+        .find(|c| !(c.is_alphanumeric() || matches!(c, '.' | '-' | '_' | '*')))
+    {
+        bail!("`{domain}` contains `{c}`, which can't appear in a domain")
+    }
+    Ok(())
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    format!("^{}$", regex::escape(glob).replace(r"\*", "[a-z0-9._-]*"))
+}
+
+/// Pulls the domains out of one line of a plain list, hosts file or adblock-style list.
+fn domains_in_line(line: &str) -> Vec<&str> {
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() || line.starts_with('!') {
+        return vec![];
+    }
+    let mut fields = line.split_whitespace().peekable();
+    // Hosts files start each line with the address the names resolve to.
+    if fields
+        .peek()
+        .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+    {
+        fields.next();
+    }
+    fields
+        .map(|field| field.trim_start_matches("||").trim_end_matches('^'))
+        .filter(|field| !HOSTS_FILE_NAMES.contains(field))
+        .collect()
+}
+
+impl DomainList {
+    pub fn from_entries<S: AsRef<str>>(
+        entries: impl IntoIterator<Item = S>,
+    ) -> anyhow::Result<DomainList> {
+        let mut list = DomainList::default();
+        for entry in entries {
+            let entry = entry.as_ref().trim().to_lowercase();
+            validate_domain(entry.as_str())?;
+            if let Some(domain) = entry.strip_prefix("*.").filter(|rest| !rest.contains('*')) {
+                list.subdomains_only.insert(domain.to_string());
+            } else if entry.contains('*') {
+                list.globs.push(entry);
+            } else {
+                list.domains.insert(entry);
+            }
+        }
+        if !list.globs.is_empty() {
+            list.glob_set = Some(RegexSet::new(
+                list.globs.iter().map(|glob| glob_to_regex(glob)),
+            )?);
+        }
+        Ok(list)
+    }
+
+    /// Parses a list file: one domain per line, or hosts-file lines like `0.0.0.0 evil.example`.
+    pub fn parse(contents: &str) -> anyhow::Result<DomainList> {
+        let mut entries = vec![];
+        for (line_number, line) in contents.lines().enumerate() {
+            for domain in domains_in_line(line) {
+                validate_domain(domain.to_lowercase().as_str())
+                    .with_context(|| format!("Invalid entry on line {}", line_number + 1))?;
+                entries.push(domain);
+            }
+        }
+        DomainList::from_entries(entries)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<DomainList> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Couldn't read domain list {}", path.display()))?;
+        DomainList::parse(contents.as_str())
+            .with_context(|| format!("Invalid domain list {}", path.display()))
+    }
+
+    pub fn domain_count(&self) -> usize {
+        self.domains.len() + self.subdomains_only.len() + self.globs.len()
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_lowercase();
+        if self.domains.contains(&host) {
+            return true;
+        }
+        let mut suffix = host.as_str();
+        while let Some((_, parent)) = suffix.split_once('.') {
+            if self.domains.contains(parent) || self.subdomains_only.contains(parent) {
+                return true;
+            }
+            suffix = parent;
+        }
+        self.glob_set
+            .as_ref()
+            .is_some_and(|glob_set| glob_set.is_match(host.as_str()))
+    }
+}
+
+/// Several lists checked together, so a large shared list file is only loaded once.
+#[derive(Debug, Default, Clone)]
+pub struct DomainMatcher {
+    lists: Vec<Arc<DomainList>>,
+}
+
+impl DomainMatcher {
+    pub fn new(lists: Vec<Arc<DomainList>>) -> DomainMatcher {
+        DomainMatcher { lists }
+    }
+
+    pub fn domain_count(&self) -> usize {
+        self.lists.iter().map(|list| list.domain_count()).sum()
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        self.lists.iter().any(|list| list.matches(host))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_domains_and_wildcards() {
+        let list =
+            DomainList::from_entries(["github.com", "*.herokuapp.com", "discord-*.com"]).unwrap();
+        assert!(list.matches("github.com"));
+        assert!(list.matches("gist.github.com"));
+        assert!(!list.matches("github.com.evil.ru"));
+        assert!(!list.matches("notgithub.com"));
+        assert!(list.matches("phish.herokuapp.com"));
+        assert!(!list.matches("herokuapp.com"));
+        assert!(list.matches("discord-nitro.com"));
+        assert!(!list.matches("discord.com"));
+    }
+
+    #[test]
+    fn parses_list_formats() {
+        let list = DomainList::parse(
+            "# Community phishing list\n\
+             0.0.0.0 steamcommunlty.com\n\
+             127.0.0.1 localhost\n\
+             127.0.0.1 dlscord.gift dlscord-nitro.gift # two on one line\n\
+             \n\
+             free-nitro.xyz\n\
+             ||airdrop-claim.io^\n",
+        )
+        .unwrap();
+        assert_eq!(list.domain_count(), 5);
+        assert!(list.matches("steamcommunlty.com"));
+        assert!(list.matches("dlscord-nitro.gift"));
+        assert!(list.matches("login.free-nitro.xyz"));
+        assert!(list.matches("airdrop-claim.io"));
+        assert!(!list.matches("localhost"));
+    }
+
+    #[test]
+    fn rejects_urls() {
+        assert!(DomainList::from_entries(["https://github.com"]).is_err());
+        assert!(DomainList::parse("evil.com/path\n").is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use crate::clean_messages::clean_message;
+use crate::config::get_config;
+use crate::corpus::LabelledMessage;
+use crate::links::extract_hosts;
+use crate::review::{close_with_buttons, is_moderator, reply_privately};
+use crate::storage::{self, ActionKind, ModerationRecord};
+use crate::trust::TrustSignals;
+use crate::undo;
+use crate::user_info;
+use anyhow::{bail, Context as _};
+use serenity::all::{
+    ActionRowComponent, Button, ButtonKind, ButtonStyle, ComponentInteraction, Context,
+    CreateButton, GuildId, Mentionable, Message, MessageId, Timestamp, UserId,
+};
+use std::io::Write;
+use std::path::Path;
+use tracing::{error, info};
+
+/// Custom IDs of feedback buttons start with this, followed by the case ID and verdict.
+const CUSTOM_ID_PREFIX: &str = "feedback";
+
+/// What a moderator thought of an automated action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Feedback {
+    Correct,
+    /// A false positive, left as it is until someone presses Undo.
+    Incorrect,
+}
+
+impl Feedback {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Feedback::Correct => "correct",
+            Feedback::Incorrect => "incorrect",
+        }
+    }
+
+    fn button(&self, case_id: i64) -> CreateButton {
+        let (label, style) = match self {
+            Feedback::Correct => ("Correct", ButtonStyle::Success),
+            Feedback::Incorrect => ("Incorrect", ButtonStyle::Secondary),
+        };
+        CreateButton::new(format!("{CUSTOM_ID_PREFIX}:{case_id}:{}", self.as_str()))
+            .label(label)
+            .style(style)
+    }
+
+    /// Whether the message behind a case judged this way was spam. Approving it said
+    /// it wasn't, so confirming an approval labels it as not spam.
+    fn is_spam(&self, action: ActionKind) -> bool {
+        (*self == Feedback::Correct) != (action == ActionKind::Approve)
+    }
+}
+
+/// The buttons under a log entry that let moderators judge the action it reports.
+pub fn buttons(case_id: i64) -> Vec<CreateButton> {
+    vec![
+        Feedback::Correct.button(case_id),
+        Feedback::Incorrect.button(case_id),
+    ]
+}
+
+/// The case and verdict behind a feedback button, or `None` for other components.
+pub fn parse_custom_id(custom_id: &str) -> Option<(i64, Feedback)> {
+    let mut parts = custom_id.split(':');
+    if parts.next()? != CUSTOM_ID_PREFIX {
+        return None;
+    }
+    let case_id = parts.next()?.parse().ok()?;
+    let feedback = match parts.next()? {
+        "correct" => Feedback::Correct,
+        "incorrect" => Feedback::Incorrect,
+        _ => return None,
+    };
+    parts.next().is_none().then_some((case_id, feedback))
+}
+
+/// A message as a corpus entry, without anything that identifies its author.
+pub fn labelled_message(
+    content: &str,
+    is_spam: bool,
+    sent_at: i64,
+    author: &TrustSignals,
+    mention_everyone: bool,
+) -> LabelledMessage {
+    LabelledMessage {
+        content: clean_message(content),
+        is_spam,
+        category: None,
+        context: vec![],
+        account_age_days: author
+            .account_created_at
+            .map(|created_at| (sent_at - created_at) / 86_400),
+        member_age_minutes: author.joined_at.map(|joined_at| (sent_at - joined_at) / 60),
+        message_count: author.message_count,
+        mention_everyone,
+    }
+}
+
+/// Adds `example` as a line of the JSONL corpus at `path`, creating it if needed.
+pub fn append_example(path: &Path, example: &LabelledMessage) -> anyhow::Result<()> {
+    let mut line = serde_json::to_string(example)?;
+    line.push('\n');
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("Couldn't add to dataset {}", path.display()))
+}
+
+/// Stores a moderator's label for a message, once per message, adds it to the dataset
+/// and shows it to the classifier from now on. Returns `false` if the message was already
+/// labelled. Only the anonymised `example` is kept; `content` is read for its links.
+pub async fn save_label(
+    ctx: &Context,
+    (guild_id, message_id): (GuildId, Option<MessageId>),
+    moderator: UserId,
+    (content, example): (&str, &LabelledMessage),
+) -> anyhow::Result<bool> {
+    let hosts = extract_hosts(content);
+    if let Some(message_id) = message_id {
+        let now = Timestamp::now().unix_timestamp();
+        let storage = storage::get_storage(ctx).await;
+        let label = (example.content.as_str(), hosts.as_slice(), example.is_spam);
+        if !storage.add_label((guild_id, message_id), label, moderator, now)? {
+            return Ok(false);
+        }
+    }
+    let config = get_config(ctx).await;
+    if let Some(guild_config) = config.guild(guild_id) {
+        guild_config
+            .examples
+            .add(example.content.as_str(), hosts, example.is_spam);
+    }
+    append_example(&config.dataset_path, example)?;
+    Ok(true)
+}
+
+/// Labels the message behind a logged action as spam or not, for the dataset.
+pub async fn label_action(
+    ctx: &Context,
+    record: &ModerationRecord,
+    is_spam: bool,
+    moderator: UserId,
+) -> anyhow::Result<()> {
+    // Holds and waves may have kept no text, and there is nothing to learn from that.
+    if record.content.is_empty() {
+        return Ok(());
+    }
+    let sent_at = record
+        .message_id
+        .map_or(record.created_at, |id| id.created_at().unix_timestamp());
+    let author = TrustSignals {
+        account_created_at: Some(record.user_id.created_at().unix_timestamp()),
+        joined_at: user_info::get_user_join_date(ctx, record.guild_id, record.user_id).await,
+        message_count: storage::get_storage(ctx)
+            .await
+            .message_count((record.guild_id, record.user_id))
+            .ok(),
+        ..Default::default()
+    };
+    let example = labelled_message(
+        record.content.as_str(),
+        is_spam,
+        sent_at,
+        &author,
+        record.mention_everyone,
+    );
+    save_label(
+        ctx,
+        (record.guild_id, record.message_id),
+        moderator,
+        (record.content.as_str(), &example),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Records a moderator's click on a Correct or Incorrect button, without changing what
+/// the bot did.
+pub async fn handle_button(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    case_id: i64,
+    feedback: Feedback,
+) -> anyhow::Result<()> {
+    if !is_moderator(component) {
+        reply_privately(
+            ctx,
+            component,
+            "Only moderators can judge actions.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let storage = storage::get_storage(ctx).await;
+    let Some(record) = storage.moderation_action(case_id)? else {
+        bail!("No moderation action {case_id}")
+    };
+    let moderator = &component.user;
+    let now = Timestamp::now().unix_timestamp();
+    let correct = feedback == Feedback::Correct;
+    if !storage.judge_action(case_id, correct, moderator.id, now)? {
+        reply_privately(
+            ctx,
+            component,
+            "Another moderator already judged this one.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let is_spam = feedback.is_spam(record.action);
+    if let Err(e) = label_action(ctx, &record, is_spam, moderator.id).await {
+        error!("Failed to label case {case_id} due to {e}");
+    }
+    info!(
+        "Case {case_id} - {} marked {} by {}",
+        record.action.as_str(),
+        feedback.as_str(),
+        moderator.name
+    );
+    let outcome = match feedback {
+        Feedback::Correct => format!("Correct - confirmed by {}", moderator.mention()),
+        Feedback::Incorrect => format!("Incorrect - marked by {}", moderator.mention()),
+    };
+    // Judging an action doesn't reverse it, so Undo stays for that.
+    let buttons = if has_undo_button(&component.message, case_id) {
+        vec![undo::button(case_id)]
+    } else {
+        vec![]
+    };
+    close_with_buttons(ctx, component, "Feedback", outcome, buttons).await?;
+    Ok(())
+}
+
+fn has_undo_button(log: &Message, case_id: i64) -> bool {
+    log.components
+        .iter()
+        .flat_map(|row| &row.components)
+        .any(|component| match component {
+            ActionRowComponent::Button(Button {
+                data: ButtonKind::NonLink { custom_id, .. },
+                ..
+            }) => undo::parse_custom_id(custom_id) == Some(case_id),
+            _ => false,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::corpus::read_corpus;
+
+    #[test]
+    fn round_trips_custom_ids() {
+        for (button, feedback) in buttons(42)
+            .into_iter()
+            .zip([Feedback::Correct, Feedback::Incorrect])
+        {
+            let custom_id = serde_json::to_value(button).unwrap()["custom_id"]
+                .as_str()
+                .unwrap()
+                .to_string();
+            assert_eq!(parse_custom_id(custom_id.as_str()), Some((42, feedback)));
+        }
+        assert_eq!(parse_custom_id("feedback:42:maybe"), None);
+        assert_eq!(parse_custom_id("undo:42"), None);
+    }
+
+    #[test]
+    fn labels_by_what_was_decided() {
+        assert!(Feedback::Correct.is_spam(ActionKind::Delete));
+        assert!(!Feedback::Incorrect.is_spam(ActionKind::Delete));
+        assert!(!Feedback::Correct.is_spam(ActionKind::Approve));
+        assert!(Feedback::Incorrect.is_spam(ActionKind::Approve));
+    }
+
+    #[test]
+    fn writes_anonymised_examples() {
+        let path = std::env::temp_dir().join(format!("labelled-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sent_at = 1_700_000_000;
+        let spam = labelled_message(
+            "@everyone free nitro at https://dlscord-gift.com/claim",
+            true,
+            sent_at,
+            &TrustSignals {
+                account_created_at: Some(sent_at - 3 * 86_400),
+                joined_at: Some(sent_at - 600),
+                message_count: Some(2),
+                ..Default::default()
+            },
+            true,
+        );
+        append_example(&path, &spam).unwrap();
+        let ham = labelled_message(
+            "How do I pin a crate version?",
+            false,
+            sent_at,
+            &TrustSignals::default(),
+            false,
+        );
+        append_example(&path, &ham).unwrap();
+
+        let corpus = read_corpus(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(corpus.len(), 2);
+        assert!(corpus[0].is_spam);
+        assert!(!corpus[0].content.contains('@'));
+        assert!(!corpus[0].content.contains("https://"));
+        assert_eq!(corpus[0].account_age_days, Some(3));
+        assert_eq!(corpus[0].member_age_minutes, Some(10));
+        assert_eq!(corpus[0].message_count, Some(2));
+        assert!(corpus[0].mention_everyone);
+        assert!(!corpus[1].is_spam);
+        assert_eq!(corpus[1].member_age_minutes, None);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::fingerprint::normalise;
+use crate::links::extract_hosts;
+use anyhow::bail;
+use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// Labelled messages kept per guild before the oldest is dropped.
+pub const MAX_EXAMPLES: usize = 1_000;
+
+/// Rough size of a token, for fitting examples into the budget without a tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Longer examples are cut short, so one wall of text can't use up the budget.
+const MAX_EXAMPLE_CHARS: usize = 400;
+
+/// Examples less similar than this aren't worth their tokens.
+const MIN_SCORE: f32 = 0.15;
+
+/// Words shorter than this say little about a message.
+const MIN_WORD_CHARS: usize = 3;
+
+/// How many moderator-labelled messages go into the spam prompt, set under
+/// `[guilds.<guild id>.few_shot]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct FewShot {
+    /// 0 leaves the prompt's built-in examples alone.
+    pub max_examples: usize,
+    /// Tokens the examples may add to each spam check.
+    pub token_budget: usize,
+}
+
+impl Default for FewShot {
+    fn default() -> Self {
+        FewShot {
+            max_examples: 4,
+            token_budget: 300,
+        }
+    }
+}
+
+impl FewShot {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_examples > 0 && self.token_budget == 0 {
+            bail!("`few_shot.token_budget` must be positive while `max_examples` is")
+        }
+        Ok(())
+    }
+}
+
+/// A message moderators labelled as spam or not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Example {
+    pub content: String,
+    pub is_spam: bool,
+}
+
+impl Example {
+    /// Tokens this example takes up in the prompt, roughly.
+    fn tokens(&self) -> usize {
+        // The label line adds a few more.
+        self.content.len() / CHARS_PER_TOKEN + 8
+    }
+}
+
+#[derive(Debug)]
+struct Known {
+    example: Example,
+    words: HashSet<String>,
+    hosts: Vec<String>,
+}
+
+fn words(content: &str) -> HashSet<String> {
+    normalise(content)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_WORD_CHARS)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Confirmed spam and ham from a guild's moderators, to show the classifier what this
+/// server's spam campaigns look like.
+#[derive(Debug)]
+pub struct ExampleBank {
+    config: FewShot,
+    known: Mutex<VecDeque<Known>>,
+}
+
+impl ExampleBank {
+    pub fn new(config: FewShot) -> ExampleBank {
+        ExampleBank {
+            config,
+            known: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Adds a labelled message, replacing any earlier label for the same text. `content` is
+    /// anonymised, which mangles its links, so the domains it linked to come as `hosts`.
+    pub fn add(&self, content: &str, hosts: Vec<String>, is_spam: bool) {
+        let content: String = content.chars().take(MAX_EXAMPLE_CHARS).collect();
+        if content.trim().is_empty() {
+            return;
+        }
+        let mut known = self.known.lock().unwrap();
+        known.retain(|entry| entry.example.content != content);
+        if known.len() >= MAX_EXAMPLES {
+            known.pop_front();
+        }
+        known.push_back(Known {
+            words: words(&content),
+            hosts,
+            example: Example { content, is_spam },
+        });
+    }
+
+    /// The examples most like `content`, most similar first, within the token budget.
+    /// Similarity is shared words, and more so a shared linked domain.
+    pub fn select(&self, content: &str) -> Vec<Example> {
+        if self.config.max_examples == 0 {
+            return vec![];
+        }
+        let words = words(content);
+        let hosts = extract_hosts(content);
+        let known = self.known.lock().unwrap();
+        let mut scored: Vec<(f32, usize)> = known
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let shared = entry.words.intersection(&words).count();
+                let union = entry.words.union(&words).count();
+                let lexical = if union == 0 {
+                    0.0
+                } else {
+                    shared as f32 / union as f32
+                };
+                let domain = if entry.hosts.iter().any(|host| hosts.contains(host)) {
+                    1.0
+                } else {
+                    0.0
+                };
+                (lexical + domain, index)
+            })
+            .filter(|(score, _)| *score >= MIN_SCORE)
+            .collect();
+        // Most similar first, and the most recently labelled among equals.
+        scored.sort_by(|(a, a_index), (b, b_index)| b.total_cmp(a).then(b_index.cmp(a_index)));
+        let mut tokens = 0;
+        let mut chosen = vec![];
+        for (_, index) in scored {
+            let example = &known[index].example;
+            if tokens + example.tokens() > self.config.token_budget {
+                continue;
+            }
+            tokens += example.tokens();
+            chosen.push(example.clone());
+            if chosen.len() == self.config.max_examples {
+                break;
+            }
+        }
+        chosen
+    }
+}
+
+/// The examples as extra instructions for the spam prompt.
+pub fn render(examples: &[Example]) -> String {
+    let mut lines = vec![
+        "These messages from this server were checked by its moderators. Classify messages like them the same way."
+            .to_string(),
+    ];
+    for example in examples {
+        lines.push(format!("# Message\n{:?}", example.content));
+        lines.push(
+            if example.is_spam {
+                "Moderators' verdict: spam."
+            } else {
+                "Moderators' verdict: not spam."
+            }
+            .to_string(),
+        );
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_similar_examples_within_budget() {
+        let bank = ExampleBank::new(FewShot::default());
+        let nitro = "Claim your free Discord Nitro now at dlscord-gift/claim";
+        let nitro_hosts = vec!["dlscord-gift.com".to_string()];
+        bank.add(nitro, nitro_hosts.clone(), true);
+        bank.add(
+            "Looking for paid survey participants, DM me for details",
+            vec![],
+            true,
+        );
+        bank.add(
+            "Has anyone compared polars and pandas for large joins?",
+            vec![],
+            false,
+        );
+        bank.add(&"long ".repeat(1_000), vec![], true);
+
+        let chosen = bank.select("Free nitro for everyone, claim it fast");
+        assert_eq!(chosen.len(), 1);
+        assert!(chosen[0].content.contains("Nitro"));
+
+        // A shared domain counts even without shared words.
+        let chosen = bank.select("https://dlscord-gift.com/x");
+        assert_eq!(chosen.len(), 1);
+        assert!(chosen[0].is_spam);
+
+        let chosen = bank.select("Is polars faster than pandas for joins?");
+        assert_eq!(chosen.len(), 1);
+        assert!(!chosen[0].is_spam);
+        assert!(bank.select("hello").is_empty());
+
+        // Relabelling replaces the earlier label.
+        bank.add(
+            "Has anyone compared polars and pandas for large joins?",
+            vec![],
+            true,
+        );
+        assert!(bank.select("Is polars faster than pandas for joins?")[0].is_spam);
+
+        let tight = ExampleBank::new(FewShot {
+            max_examples: 4,
+            token_budget: 10,
+        });
+        tight.add(nitro, nitro_hosts, true);
+        assert!(tight
+            .select("Free nitro for everyone, claim it fast")
+            .is_empty());
+    }
+
+    #[test]
+    fn renders_verdicts() {
+        let rendered = render(&[Example {
+            content: "free \"nitro\"".to_string(),
+            is_spam: true,
+        }]);
+        assert!(rendered.ends_with("# Message\n\"free \\\"nitro\\\"\"\nModerators' verdict: spam."));
+    }
+}
@@ -0,0 +1,89 @@
+/// Characters per shingle when fingerprinting.
+const SHINGLE_CHARS: usize = 4;
+
+/// Lowercases, drops zero-width characters and collapses whitespace, so trivially
+/// altered copies of a message compare equal.
+pub fn normalise(content: &str) -> String {
+    content
+        .chars()
+        .filter(|c| !matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}'))
+        .flat_map(char::to_lowercase)
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is guaranteed to stay the same between builds.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// 64-bit SimHash over character shingles of already normalised text. Texts that differ
+/// in a few characters get fingerprints that differ in a few bits.
+pub fn simhash(normalised: &str) -> u64 {
+    let chars: Vec<char> = normalised.chars().collect();
+    let mut weights = [0i32; 64];
+    let shingles: Vec<String> = if chars.len() <= SHINGLE_CHARS {
+        vec![normalised.to_string()]
+    } else {
+        chars
+            .windows(SHINGLE_CHARS)
+            .map(|window| window.iter().collect())
+            .collect()
+    };
+    for shingle in shingles {
+        let hash = fnv1a(shingle.as_bytes());
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            *weight += if hash >> bit & 1 == 1 { 1 } else { -1 };
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |fingerprint, (bit, _)| fingerprint | 1 << bit)
+}
+
+/// How many bits two fingerprints differ in.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalises_trivial_changes() {
+        assert_eq!(
+            normalise("  FREE\u{200B} Nitro\n\nhttps://Dlscord.gift  "),
+            "free nitro https://dlscord.gift"
+        );
+    }
+
+    #[test]
+    fn near_copies_are_close() {
+        let template = simhash(&normalise(
+            "Hey! I'm giving away my Discord Nitro, claim it here before it's gone: https://dlscord-gift.com/claim",
+        ));
+        let variant = simhash(&normalise(
+            "Hey!! I'm giving away my Discord Nitro, claim it here before its gone: https://dlscord-gift.net/claim",
+        ));
+        let unrelated = simhash(&normalise(
+            "Does anyone know why cargo check is slower than cargo build on this workspace?",
+        ));
+        assert!(
+            distance(template, variant) <= 10,
+            "{}",
+            distance(template, variant)
+        );
+        assert!(
+            distance(template, unrelated) > 20,
+            "{}",
+            distance(template, unrelated)
+        );
+    }
+}
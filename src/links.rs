@@ -0,0 +1,167 @@
+use crate::domain_lists::DomainMatcher;
+use lazy_static::lazy_static;
+use regex::Regex;
+use url::Url;
+
+/// TLDs we'll recognise without a scheme. Kept short so file names like
+/// `main.rs` or `model.pt` aren't mistaken for links.
+const SCHEMELESS_TLDS: [&str; 52] = [
+    "com", "net", "org", "gg", "io", "co", "me", "xyz", "ru", "info", "biz", "top", "link",
+    "click", "site", "online", "shop", "store", "app", "dev", "ly", "to", "cc", "tk", "ml", "ga",
+    "cf", "gq", "uk", "de", "edu", "gov", "ai", "tv", "us", "live", "gift", "gifts", "fun", "pro",
+    "club", "vip", "cn", "in", "fr", "jp", "br", "pl", "nl", "eu", "au", "ca",
+];
+
+lazy_static! {
+    /// `hxxp://`, `hxxps://` and friends.
+    static ref OBFUSCATED_SCHEME: Regex = Regex::new(r"(?i)\bh(?:xx|\*\*|tt)p(s?)\s*(?:\[:\]|:)\s*//").unwrap();
+    /// `[.]`, `(.)`, `[dot]`, `(dot)` and `{dot}` always stand for a dot.
+    static ref BRACKETED_DOT: Regex = Regex::new(r"(?i)\s*(?:\[\.\]|\(\.\)|\{\.\}|\[dot\]|\(dot\)|\{dot\})\s*").unwrap();
+    /// A bare ` dot ` only counts when a known TLD follows and ends the link, so prose survives.
+    static ref SPOKEN_DOT: Regex = Regex::new(&format!(
+        r"(?i)\b([a-z0-9][a-z0-9-]*)\s+dot\s+({})(/|$|[,!?)]|\.\s|\.$)",
+        SCHEMELESS_TLDS.join("|")
+    ))
+    .unwrap();
+    static ref SCHEME_URL: Regex = Regex::new(r"(?i)\bhttps?://[^\s<>]+").unwrap();
+    static ref SCHEMELESS_URL: Regex = Regex::new(&format!(
+        r"(?i)\b(?:[a-z0-9](?:[a-z0-9-]{{0,61}}[a-z0-9])?\.)+(?:{})\b(?:[/:?#][^\s<>]*)?",
+        SCHEMELESS_TLDS.join("|")
+    ))
+    .unwrap();
+}
+
+/// Undo the usual tricks for sneaking links past filters.
+fn deobfuscate(text: &str) -> String {
+    let text = OBFUSCATED_SCHEME.replace_all(text, "http$1://");
+    let text = BRACKETED_DOT.replace_all(&text, ".");
+    SPOKEN_DOT
+        .replace_all(&text, |caps: &regex::Captures| {
+            if ["the", "a", "an"].contains(&caps[1].to_lowercase().as_str()) {
+                caps[0].to_string()
+            } else {
+                format!("{}.{}{}", &caps[1], &caps[2], &caps[3])
+            }
+        })
+        .to_string()
+}
+
+fn host_of(url: &str) -> Option<String> {
+    let host = Url::parse(url)
+        .ok()?
+        .host_str()?
+        .trim_end_matches('.')
+        .to_lowercase();
+    (!host.is_empty()).then_some(host)
+}
+
+/// Every distinct host linked to in `text`, with or without a scheme.
+pub fn extract_hosts(text: &str) -> Vec<String> {
+    let text = deobfuscate(text);
+    let mut hosts: Vec<String> = vec![];
+    let mut push = |host: Option<String>| {
+        if let Some(host) = host {
+            if !hosts.contains(&host) {
+                hosts.push(host);
+            }
+        }
+    };
+    let mut covered: Vec<(usize, usize)> = vec![];
+    for found in SCHEME_URL.find_iter(&text) {
+        covered.push((found.start(), found.end()));
+        push(host_of(found.as_str()));
+    }
+    for found in SCHEMELESS_URL.find_iter(&text) {
+        let inside_scheme_url = covered
+            .iter()
+            .any(|(start, end)| found.start() >= *start && found.end() <= *end);
+        // Skip e-mail addresses and anything already read as part of a full URL.
+        let is_email = text[..found.start()].ends_with('@');
+        if !inside_scheme_url && !is_email {
+            push(host_of(format!("http://{}", found.as_str()).as_str()));
+        }
+    }
+    hosts
+}
+
+/// Hosts linked from `text` that `allowlist` doesn't cover.
+pub fn suspicious_hosts(text: &str, allowlist: &DomainMatcher) -> Vec<String> {
+    extract_hosts(text)
+        .into_iter()
+        .filter(|host| !allowlist.matches(host))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain_lists::DomainList;
+    use std::sync::Arc;
+
+    fn allowed() -> DomainMatcher {
+        DomainMatcher::new(vec![Arc::new(
+            DomainList::from_entries(["github.com", "stackoverflow.com"]).unwrap(),
+        )])
+    }
+
+    #[test]
+    fn matches_hosts_not_substrings() {
+        let test_cases = vec![
+            ("https://github.com/rust-lang/rust", vec![]),
+            ("https://gist.github.com/abc", vec![]),
+            (
+                "https://github.com.evil.ru/login",
+                vec!["github.com.evil.ru"],
+            ),
+            ("https://evil.ru/?github.com", vec!["evil.ru"]),
+            ("https://github.com@evil.ru/", vec!["evil.ru"]),
+            ("https://notgithub.com", vec!["notgithub.com"]),
+            ("HTTPS://GitHub.com./x", vec![]),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                suspicious_hosts(input, &allowed()),
+                expected,
+                "Failed on message: {}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn finds_schemeless_and_obfuscated_links() {
+        let test_cases = vec![
+            ("join discord.gg/xyz for free nitro", vec!["discord.gg"]),
+            ("go to hxxps://evil[.]ru/claim", vec!["evil.ru"]),
+            ("steam-gift dot com", vec!["steam-gift.com"]),
+            ("free-nitro(dot)xyz/claim", vec!["free-nitro.xyz"]),
+            ("two links: https://a.io and b.io", vec!["a.io", "b.io"]),
+        ];
+        for (input, expected) in test_cases {
+            assert_eq!(
+                suspicious_hosts(input, &allowed()),
+                expected,
+                "Failed on message: {}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_ordinary_prose() {
+        let test_cases = vec![
+            "the dot com bubble burst in 2000",
+            "I remember the dot com.",
+            "see main.rs and model.pt, then import pandas.DataFrame",
+            "email me at someone@gmail.com",
+            "I read about http in a book",
+        ];
+        for input in test_cases {
+            assert!(
+                suspicious_hosts(input, &allowed()).is_empty(),
+                "Failed on message: {}",
+                input
+            );
+        }
+    }
+}
@@ -0,0 +1,872 @@
+use crate::cassette::Cassette;
+use crate::fingerprint::normalise;
+use crate::reply_cache::{CacheConfig, ReplyCache};
+use crate::resilience::{BreakerConfig, BreakerEvent, CircuitBreaker, RetryConfig};
+use crate::structured::{parse_reply, StructuredReply};
+use crate::usage::{Budget, Feature, Price, TokenUsage, UsageLedger};
+use crate::utilities::user_message;
+use anyhow::{anyhow, bail, Context as _};
+use openai::chat::{
+    ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionResponseFormat,
+};
+use openai::Credentials;
+use serde::Deserialize;
+use serenity::all::GuildId;
+use serenity::async_trait;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Used when `[llm]` doesn't set `timeout_seconds`.
+const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+
+fn default_api_key_env() -> String {
+    "OPENAI_KEY".to_string()
+}
+
+/// Why a structured reply couldn't be had.
+#[derive(Debug)]
+pub enum LlmError {
+    /// No usable reply: the request failed, timed out or came back empty.
+    Transport(anyhow::Error),
+    /// The model replied, but not with the JSON asked for, even after a repair attempt.
+    Parse { reply: String, error: String },
+    /// Recent calls kept failing, so the circuit breaker isn't letting any through for now.
+    Unavailable { retry_in: Duration },
+    /// The guild has spent its daily budget for this feature.
+    OverBudget { feature: Feature },
+}
+
+impl Display for LlmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LlmError::Transport(e) => write!(f, "LLM request failed: {e}"),
+            LlmError::Parse { reply, error } => {
+                write!(f, "Couldn't parse LLM reply ({error}): {reply}")
+            }
+            LlmError::Unavailable { retry_in } => write!(
+                f,
+                "LLM calls are paused after repeated failures, for {}s more",
+                retry_in.as_secs()
+            ),
+            LlmError::OverBudget { feature } => {
+                write!(f, "Today's LLM budget for {} is spent", feature.as_str())
+            }
+        }
+    }
+}
+
+impl std::error::Error for LlmError {}
+
+/// Everything the bot asks a chat model to do. Each can use its own model and limits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LlmTask {
+    Spam,
+    Request,
+    Verify,
+    RoadmapDetect,
+    RoadmapCreate,
+}
+
+impl LlmTask {
+    pub const ALL: [LlmTask; 5] = [
+        LlmTask::Spam,
+        LlmTask::Request,
+        LlmTask::Verify,
+        LlmTask::RoadmapDetect,
+        LlmTask::RoadmapCreate,
+    ];
+
+    /// The name used for `[llm.<task>]` and in usage totals.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LlmTask::Spam => "spam",
+            LlmTask::Request => "request",
+            LlmTask::Verify => "verify",
+            LlmTask::RoadmapDetect => "roadmap_detect",
+            LlmTask::RoadmapCreate => "roadmap_create",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<LlmTask> {
+        LlmTask::ALL.into_iter().find(|task| task.as_str() == name)
+    }
+}
+
+/// A model's reply and what it cost.
+#[derive(Debug, Clone, Default)]
+pub struct Completion {
+    /// The first choice's content, if the model returned any.
+    pub content: Option<String>,
+    /// Zero when the server doesn't report usage.
+    pub usage: TokenUsage,
+}
+
+/// A single chat completion, ready to send.
+#[derive(Debug, Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatCompletionMessage>,
+    pub max_tokens: Option<u64>,
+    pub timeout: Duration,
+    /// Ask for a JSON object, on servers that support it.
+    pub json: bool,
+}
+
+#[async_trait]
+pub trait LlmClient: Send + Sync + Debug {
+    async fn complete(&self, request: &ChatRequest) -> anyhow::Result<Completion>;
+}
+
+/// Any server speaking the OpenAI chat completions API: OpenAI itself, llama.cpp
+/// server, vLLM, Ollama and so on.
+#[derive(Debug)]
+pub struct OpenAiCompatible {
+    /// Empty for OpenAI's own endpoint.
+    base_url: String,
+    api_key_env: String,
+    /// Whether the server accepts `response_format: json_object`.
+    json_mode: bool,
+}
+
+impl OpenAiCompatible {
+    pub fn new(base_url: String, api_key_env: String, json_mode: bool) -> OpenAiCompatible {
+        OpenAiCompatible {
+            base_url,
+            api_key_env,
+            json_mode,
+        }
+    }
+
+    fn credentials(&self) -> anyhow::Result<Credentials> {
+        let api_key = match std::env::var(self.api_key_env.as_str()) {
+            Ok(api_key) => api_key,
+            // Self-hosted servers usually don't check the key.
+            Err(_) if !self.base_url.is_empty() => String::new(),
+            Err(_) => bail!("Expected an API key in `{}`", self.api_key_env),
+        };
+        Ok(Credentials::new(api_key, self.base_url.as_str()))
+    }
+}
+
+#[async_trait]
+impl LlmClient for OpenAiCompatible {
+    async fn complete(&self, request: &ChatRequest) -> anyhow::Result<Completion> {
+        let mut builder = ChatCompletion::builder(request.model.as_str(), request.messages.clone())
+            .credentials(self.credentials()?);
+        if let Some(max_tokens) = request.max_tokens {
+            builder = builder.max_tokens(max_tokens);
+        }
+        if request.json && self.json_mode {
+            builder = builder.response_format(ChatCompletionResponseFormat::json_object());
+        }
+        let chat_completion = tokio::time::timeout(request.timeout, builder.create())
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "No reply from {} within {:?}",
+                    request.model,
+                    request.timeout
+                )
+            })??;
+        Ok(Completion {
+            content: chat_completion
+                .choices
+                .first()
+                .and_then(|choice| choice.message.content.clone()),
+            usage: chat_completion
+                .usage
+                .map(|usage| TokenUsage {
+                    prompt_tokens: usage.prompt_tokens.into(),
+                    completion_tokens: usage.completion_tokens.into(),
+                })
+                .unwrap_or_default(),
+        })
+    }
+}
+
+/// Per-task overrides, set under `[llm.<task>]`.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TaskConfig {
+    pub model: Option<String>,
+    pub timeout_seconds: Option<u64>,
+    pub max_tokens: Option<u64>,
+}
+
+/// Where and how to reach the chat model, set under `[llm]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LlmConfig {
+    /// API root of an OpenAI-compatible server, e.g. `http://localhost:8080/v1/`.
+    /// Leave unset for OpenAI.
+    pub base_url: Option<String>,
+    /// Environment variable holding the API key.
+    pub api_key_env: String,
+    pub timeout_seconds: u64,
+    pub max_tokens: Option<u64>,
+    /// Request `response_format: json_object` for replies that must be JSON. Turn off
+    /// for servers that reject it.
+    pub json_mode: bool,
+    pub retry: RetryConfig,
+    pub breaker: BreakerConfig,
+    pub cache: CacheConfig,
+    /// Per-model prices, keyed by model name, for spend totals and caps.
+    pub prices: HashMap<String, Price>,
+    pub spam: TaskConfig,
+    pub request: TaskConfig,
+    pub verify: TaskConfig,
+    pub roadmap_detect: TaskConfig,
+    pub roadmap_create: TaskConfig,
+}
+
+impl Default for LlmConfig {
+    fn default() -> Self {
+        LlmConfig {
+            base_url: None,
+            api_key_env: default_api_key_env(),
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            max_tokens: None,
+            json_mode: true,
+            retry: RetryConfig::default(),
+            breaker: BreakerConfig::default(),
+            cache: CacheConfig::default(),
+            prices: HashMap::new(),
+            spam: TaskConfig::default(),
+            request: TaskConfig::default(),
+            verify: TaskConfig::default(),
+            roadmap_detect: TaskConfig::default(),
+            roadmap_create: TaskConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct TaskSettings {
+    model: String,
+    timeout: Duration,
+    max_tokens: Option<u64>,
+}
+
+/// The chat model client plus the settings for each task. Every call is retried with
+/// backoff and goes through one circuit breaker.
+#[derive(Debug)]
+pub struct Llm {
+    client: Arc<dyn LlmClient>,
+    retry: RetryConfig,
+    breaker: CircuitBreaker,
+    usage: UsageLedger,
+    /// Replies to `complete_json_cached`, so repeated messages cost one call.
+    cache: ReplyCache,
+    spam: TaskSettings,
+    request: TaskSettings,
+    verify: TaskSettings,
+    roadmap_detect: TaskSettings,
+    roadmap_create: TaskSettings,
+}
+
+impl LlmConfig {
+    /// `model` is the top-level default for tasks that don't name their own.
+    pub fn build(&self, model: &str) -> anyhow::Result<Llm> {
+        if let Some(base_url) = &self.base_url {
+            url::Url::parse(base_url)
+                .with_context(|| format!("`llm.base_url` `{base_url}` isn't a URL"))?;
+        }
+        let client = OpenAiCompatible::new(
+            self.base_url.clone().unwrap_or_default(),
+            self.api_key_env.clone(),
+            self.json_mode,
+        );
+        self.build_with_client(model, Cassette::from_env(Arc::new(client))?)
+    }
+
+    pub fn build_with_client(
+        &self,
+        model: &str,
+        client: Arc<dyn LlmClient>,
+    ) -> anyhow::Result<Llm> {
+        let settings = |name: &str, task: &TaskConfig| -> anyhow::Result<TaskSettings> {
+            let timeout_seconds = task.timeout_seconds.unwrap_or(self.timeout_seconds);
+            if timeout_seconds == 0 {
+                bail!("`llm.{name}.timeout_seconds` must be positive")
+            }
+            if task.max_tokens.or(self.max_tokens) == Some(0) {
+                bail!("`llm.{name}.max_tokens` must be positive")
+            }
+            let model = task.model.clone().unwrap_or_else(|| model.to_string());
+            if model.trim().is_empty() {
+                bail!("`llm.{name}.model` must not be empty")
+            }
+            Ok(TaskSettings {
+                model,
+                timeout: Duration::from_secs(timeout_seconds),
+                max_tokens: task.max_tokens.or(self.max_tokens),
+            })
+        };
+        self.retry.validate()?;
+        self.breaker.validate()?;
+        self.cache.validate()?;
+        for (model, price) in &self.prices {
+            if price.prompt_per_million < 0.0 || price.completion_per_million < 0.0 {
+                bail!("`llm.prices.\"{model}\"` must not be negative")
+            }
+        }
+        let llm = Llm {
+            client,
+            retry: self.retry.clone(),
+            breaker: CircuitBreaker::new(&self.breaker),
+            usage: UsageLedger::new(self.prices.clone()),
+            cache: ReplyCache::new(&self.cache),
+            spam: settings("spam", &self.spam)?,
+            request: settings("request", &self.request)?,
+            verify: settings("verify", &self.verify)?,
+            roadmap_detect: settings("roadmap_detect", &self.roadmap_detect)?,
+            roadmap_create: settings("roadmap_create", &self.roadmap_create)?,
+        };
+        if !self.prices.is_empty() {
+            for task in LlmTask::ALL {
+                if !llm.usage.has_price(llm.model(task)) {
+                    warn!(
+                        "No price for {}, so {} calls won't count towards spend caps",
+                        llm.model(task),
+                        task.as_str()
+                    );
+                }
+            }
+        }
+        Ok(llm)
+    }
+}
+
+impl Llm {
+    fn settings(&self, task: LlmTask) -> &TaskSettings {
+        match task {
+            LlmTask::Spam => &self.spam,
+            LlmTask::Request => &self.request,
+            LlmTask::Verify => &self.verify,
+            LlmTask::RoadmapDetect => &self.roadmap_detect,
+            LlmTask::RoadmapCreate => &self.roadmap_create,
+        }
+    }
+
+    pub fn model(&self, task: LlmTask) -> &str {
+        self.settings(task).model.as_str()
+    }
+
+    /// Sets each guild's daily spend cap.
+    pub fn with_budgets(mut self, budgets: HashMap<GuildId, Budget>) -> Llm {
+        self.usage.set_budgets(budgets);
+        self
+    }
+
+    /// Tokens and spend so far.
+    pub fn usage(&self) -> &UsageLedger {
+        &self.usage
+    }
+
+    /// Whether `guild_id` is still within its budget for `feature` today.
+    pub fn within_budget(&self, guild_id: GuildId, feature: Feature) -> bool {
+        self.usage
+            .allows(chrono::Utc::now().date_naive(), guild_id, feature)
+    }
+
+    /// Hears whenever the circuit breaker trips or recovers.
+    pub fn breaker_events(&self) -> broadcast::Receiver<BreakerEvent> {
+        self.breaker.subscribe()
+    }
+
+    fn request(
+        &self,
+        task: LlmTask,
+        messages: Vec<ChatCompletionMessage>,
+        json: bool,
+    ) -> ChatRequest {
+        let settings = self.settings(task);
+        ChatRequest {
+            model: settings.model.clone(),
+            messages,
+            max_tokens: settings.max_tokens,
+            timeout: settings.timeout,
+            json,
+        }
+    }
+
+    /// Sends `request` for `guild_id`, retrying failures with exponential backoff. Calls
+    /// that still fail count towards tripping the circuit breaker, and the tokens used by
+    /// those that succeed are billed to the guild.
+    async fn send(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        request: ChatRequest,
+    ) -> Result<Option<String>, LlmError> {
+        let feature = Feature::of(task);
+        if guild_id.is_some_and(|guild_id| !self.within_budget(guild_id, feature)) {
+            return Err(LlmError::OverBudget { feature });
+        }
+        if let Err(retry_in) = self.breaker.allow(Instant::now()) {
+            return Err(LlmError::Unavailable { retry_in });
+        }
+        let mut retry = 0;
+        loop {
+            match self.client.complete(&request).await {
+                Ok(completion) => {
+                    self.breaker.record_success();
+                    self.usage.record(
+                        chrono::Utc::now().date_naive(),
+                        guild_id,
+                        task,
+                        request.model.as_str(),
+                        completion.usage,
+                    );
+                    return Ok(completion.content);
+                }
+                Err(e) if retry < self.retry.max_retries => {
+                    let backoff = self.retry.backoff(retry);
+                    warn!(
+                        "Request to {} failed ({e}), retrying in {backoff:?}",
+                        request.model
+                    );
+                    tokio::time::sleep(backoff).await;
+                    retry += 1;
+                }
+                Err(e) => {
+                    self.breaker
+                        .record_failure(Instant::now(), e.to_string().as_str());
+                    return Err(LlmError::Transport(e));
+                }
+            }
+        }
+    }
+
+    /// Sends `messages` with `task`'s model and limits, on behalf of `guild_id`.
+    pub async fn complete(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        messages: Vec<ChatCompletionMessage>,
+    ) -> Result<Option<String>, LlmError> {
+        self.send(task, guild_id, self.request(task, messages, false))
+            .await
+    }
+
+    /// Sends `messages` and parses the reply as `T`. A reply that can't be parsed is
+    /// sent back once with what was wrong, so the model can correct it.
+    pub async fn complete_json<T: StructuredReply>(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        messages: Vec<ChatCompletionMessage>,
+    ) -> Result<T, LlmError> {
+        self.complete_json_reply(task, guild_id, messages)
+            .await
+            .map(|(parsed, _)| parsed)
+    }
+
+    /// Like `complete_json`, but reuses the reply to an earlier request for the same guild
+    /// with the same task, model and system messages whose `content` normalises to the same
+    /// text. Only `content` and the system messages are compared, so what else the messages
+    /// say mustn't change the answer much.
+    pub async fn complete_json_cached<T: StructuredReply>(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        content: &str,
+        messages: Vec<ChatCompletionMessage>,
+    ) -> Result<T, LlmError> {
+        // Every system message, as guilds add their own, such as few-shot examples.
+        let system_prompts: Vec<&str> = messages
+            .iter()
+            .filter(|message| matches!(message.role, ChatCompletionMessageRole::System))
+            .filter_map(|message| message.content.as_deref())
+            .collect();
+        let request = serde_json::to_string(&(
+            task.as_str(),
+            self.model(task),
+            guild_id.map(|guild_id| guild_id.get()),
+            system_prompts,
+            normalise(content),
+        ))
+        .expect("Strings always serialise");
+        let key: String = Sha256::digest(request.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        if let Some(reply) = self.cache.get(key.as_str(), Instant::now()) {
+            if let Ok(parsed) = parse_reply::<T>(reply.as_str()) {
+                return Ok(parsed);
+            }
+        }
+        let (parsed, reply) = self.complete_json_reply(task, guild_id, messages).await?;
+        self.cache.insert(key, reply, Instant::now());
+        Ok(parsed)
+    }
+
+    /// The parsed reply and the text it was parsed from.
+    async fn complete_json_reply<T: StructuredReply>(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        mut messages: Vec<ChatCompletionMessage>,
+    ) -> Result<(T, String), LlmError> {
+        let reply = self
+            .complete_json_once(task, guild_id, messages.clone())
+            .await?;
+        let error = match parse_reply::<T>(reply.as_str()) {
+            Ok(parsed) => return Ok((parsed, reply)),
+            Err(error) => error,
+        };
+        warn!("Asking {task:?} model to repair its reply ({error}): {reply}");
+        messages.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::Assistant,
+            content: Some(reply),
+            ..Default::default()
+        });
+        messages.push(user_message(format!(
+            "{error}. Reply again with only a JSON object shaped like {}, with no other text.",
+            T::describe()
+        )));
+        let reply = self.complete_json_once(task, guild_id, messages).await?;
+        match parse_reply::<T>(reply.as_str()) {
+            Ok(parsed) => Ok((parsed, reply)),
+            Err(error) => Err(LlmError::Parse { reply, error }),
+        }
+    }
+
+    async fn complete_json_once(
+        &self,
+        task: LlmTask,
+        guild_id: Option<GuildId>,
+        messages: Vec<ChatCompletionMessage>,
+    ) -> Result<String, LlmError> {
+        match self
+            .send(task, guild_id, self.request(task, messages, true))
+            .await?
+        {
+            Some(reply) => Ok(reply),
+            None => Err(LlmError::Transport(anyhow!(
+                "The model sent an empty reply"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn tasks_fall_back_to_defaults() {
+        let config: LlmConfig = toml::from_str(
+            "max_tokens = 512\n[spam]\nmodel = \"qwen2.5:7b\"\nmax_tokens = 64\ntimeout_seconds = 5\n",
+        )
+        .unwrap();
+        let llm = config.build("gpt-4.1-mini").unwrap();
+        assert_eq!(llm.model(LlmTask::Spam), "qwen2.5:7b");
+        assert_eq!(llm.spam.max_tokens, Some(64));
+        assert_eq!(llm.spam.timeout, Duration::from_secs(5));
+        assert_eq!(llm.model(LlmTask::RoadmapCreate), "gpt-4.1-mini");
+        assert_eq!(llm.roadmap_create.max_tokens, Some(512));
+        assert_eq!(
+            llm.roadmap_create.timeout,
+            Duration::from_secs(DEFAULT_TIMEOUT_SECONDS)
+        );
+
+        let invalid: LlmConfig = toml::from_str("[verify]\ntimeout_seconds = 0\n").unwrap();
+        assert!(invalid.build("gpt-4.1-mini").is_err());
+        let invalid: LlmConfig = toml::from_str("base_url = \"localhost\"\n").unwrap();
+        assert!(invalid.build("gpt-4.1-mini").is_err());
+    }
+
+    /// Replies with each of `replies` in turn, keeping the requests it was sent.
+    #[derive(Debug, Default)]
+    struct Scripted {
+        replies: Mutex<Vec<String>>,
+        requests: Mutex<Vec<ChatRequest>>,
+    }
+
+    #[async_trait]
+    impl LlmClient for Scripted {
+        async fn complete(&self, request: &ChatRequest) -> anyhow::Result<Completion> {
+            self.requests.lock().unwrap().push(request.clone());
+            Ok(Completion {
+                content: Some(self.replies.lock().unwrap().remove(0)),
+                usage: TokenUsage {
+                    prompt_tokens: 100,
+                    completion_tokens: 10,
+                },
+            })
+        }
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct Answer {
+        answer: bool,
+    }
+
+    impl StructuredReply for Answer {
+        const FIELDS: &'static [crate::structured::Field] = &[crate::structured::Field::required(
+            "answer",
+            crate::structured::FieldKind::Bool,
+        )];
+    }
+
+    #[tokio::test]
+    async fn repairs_unparseable_replies_once() {
+        let client = Arc::new(Scripted {
+            replies: Mutex::new(vec![
+                "I'd say yes!".to_string(),
+                "```json\n{\"answer\": \"yes\"}\n```".to_string(),
+            ]),
+            ..Default::default()
+        });
+        let llm = LlmConfig::default()
+            .build_with_client("gpt-4.1-mini", client.clone())
+            .unwrap();
+        let answer: Answer = llm
+            .complete_json(
+                LlmTask::Verify,
+                None,
+                vec![user_message("Well?".to_string())],
+            )
+            .await
+            .unwrap();
+        assert!(answer.answer);
+        let requests = client.requests.lock().unwrap().clone();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.json));
+        let repair = requests[1]
+            .messages
+            .last()
+            .unwrap()
+            .content
+            .as_ref()
+            .unwrap();
+        assert!(repair.contains("no JSON object"));
+        assert!(repair.contains(r#"{"answer": boolean}"#));
+
+        let client = Arc::new(Scripted {
+            replies: Mutex::new(vec!["no".to_string(), "still no".to_string()]),
+            ..Default::default()
+        });
+        let llm = LlmConfig::default()
+            .build_with_client("gpt-4.1-mini", client)
+            .unwrap();
+        let error = llm
+            .complete_json::<Answer>(
+                LlmTask::Verify,
+                None,
+                vec![user_message("Well?".to_string())],
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LlmError::Parse { .. }));
+    }
+
+    fn system_message(content: &str) -> ChatCompletionMessage {
+        ChatCompletionMessage {
+            role: ChatCompletionMessageRole::System,
+            ..user_message(content.to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_replies_by_normalised_content() {
+        let client = Arc::new(Scripted {
+            replies: Mutex::new(vec![
+                r#"{"answer": true}"#.to_string(),
+                r#"{"answer": false}"#.to_string(),
+                r#"{"answer": true}"#.to_string(),
+                r#"{"answer": false}"#.to_string(),
+            ]),
+            ..Default::default()
+        });
+        let llm = LlmConfig::default()
+            .build_with_client("gpt-4.1-mini", client.clone())
+            .unwrap();
+        let ask = |guild_id: u64, examples: &'static str, content: &'static str| {
+            llm.complete_json_cached::<Answer>(
+                LlmTask::Spam,
+                Some(GuildId::new(guild_id)),
+                content,
+                vec![
+                    system_message("Is this spam?"),
+                    system_message(examples),
+                    user_message(content.to_string()),
+                ],
+            )
+        };
+        assert!(ask(1, "", "Free Nitro").await.unwrap().answer);
+        assert!(ask(1, "", "  free\u{200B} NITRO ").await.unwrap().answer);
+        assert!(!ask(1, "", "Is this free?").await.unwrap().answer);
+        assert_eq!(client.requests.lock().unwrap().len(), 2);
+        // Neither another guild nor new examples get an old verdict.
+        assert!(ask(2, "", "Free Nitro").await.unwrap().answer);
+        assert!(
+            !ask(1, "Free Nitro: spam", "Free Nitro")
+                .await
+                .unwrap()
+                .answer
+        );
+        assert_eq!(client.requests.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn bills_guilds_and_enforces_budgets() {
+        let guild_id = GuildId::new(1);
+        let client = Arc::new(Scripted {
+            replies: Mutex::new(vec!["hi".to_string(), "hi again".to_string()]),
+            ..Default::default()
+        });
+        let config: LlmConfig = toml::from_str(
+            "[prices.\"gpt-4.1-mini\"]\nprompt_per_million = 1.0\ncompletion_per_million = 10.0\n",
+        )
+        .unwrap();
+        let llm = config
+            .build_with_client("gpt-4.1-mini", client)
+            .unwrap()
+            .with_budgets(HashMap::from([(
+                guild_id,
+                toml::from_str("daily_usd = 0.0002\nrequests = 1.0").unwrap(),
+            )]));
+        let messages = || vec![user_message("hi".to_string())];
+
+        llm.complete(LlmTask::Request, Some(guild_id), messages())
+            .await
+            .unwrap();
+        // 100 prompt and 10 completion tokens cost $0.0002, the whole budget.
+        let today = llm.usage().today(chrono::Utc::now().date_naive());
+        assert_eq!(today.len(), 1);
+        assert_eq!(today[0].0.task, LlmTask::Request);
+        assert_eq!(today[0].1.prompt_tokens, 100);
+        assert!((today[0].1.cost_usd - 0.0002).abs() < 1e-12);
+        let error = llm
+            .complete(LlmTask::Request, Some(guild_id), messages())
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            LlmError::OverBudget {
+                feature: Feature::Requests
+            }
+        ));
+        // Calls outside the guild aren't capped.
+        llm.complete(LlmTask::Request, None, messages())
+            .await
+            .unwrap();
+    }
+
+    /// Fails the first `failures` requests, then says "ok".
+    #[derive(Debug)]
+    struct Flaky {
+        failures: Mutex<u32>,
+    }
+
+    #[async_trait]
+    impl LlmClient for Flaky {
+        async fn complete(&self, _request: &ChatRequest) -> anyhow::Result<Completion> {
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                bail!("503 Service Unavailable")
+            }
+            Ok(Completion {
+                content: Some("ok".to_string()),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_then_trips_the_breaker() {
+        let config: LlmConfig = toml::from_str(
+            "[retry]\nmax_retries = 2\ninitial_backoff_ms = 1\n[breaker]\nfailures = 1\ncooldown_seconds = 60\n",
+        )
+        .unwrap();
+        let flaky = |failures| {
+            Arc::new(Flaky {
+                failures: Mutex::new(failures),
+            })
+        };
+        let messages = || vec![user_message("hi".to_string())];
+
+        let llm = config.build_with_client("gpt-4.1-mini", flaky(2)).unwrap();
+        let reply = llm
+            .complete(LlmTask::Request, None, messages())
+            .await
+            .unwrap();
+        assert_eq!(reply.as_deref(), Some("ok"));
+
+        let llm = config.build_with_client("gpt-4.1-mini", flaky(3)).unwrap();
+        let mut events = llm.breaker_events();
+        let error = llm
+            .complete(LlmTask::Request, None, messages())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LlmError::Transport(_)));
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            BreakerEvent::Tripped { failures: 1, .. }
+        ));
+        // The client would answer now, but the breaker doesn't ask it.
+        let error = llm
+            .complete(LlmTask::Request, None, messages())
+            .await
+            .unwrap_err();
+        assert!(matches!(error, LlmError::Unavailable { .. }));
+    }
+
+    /// Answers one chat completion request like an OpenAI-compatible server would,
+    /// handing back the raw request it received.
+    async fn stand_in_server(reply: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}/v1/", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 4096];
+            // Read until the JSON body has been closed off.
+            while !String::from_utf8_lossy(&request).trim_end().ends_with('}') {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let body = format!(
+                "{{\"choices\": [{{\"index\": 0, \"finish_reason\": \"stop\", \"message\": {{\"role\": \"assistant\", \"content\": {}}}}}]}}",
+                serde_json::to_string(reply).unwrap()
+            );
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        (base_url, handle)
+    }
+
+    #[tokio::test]
+    async fn talks_to_compatible_servers() {
+        let (base_url, server) = stand_in_server("hello from llama.cpp").await;
+        let config: LlmConfig = toml::from_str(&format!(
+            "base_url = \"{base_url}\"\napi_key_env = \"BSE_TEST_UNSET_KEY\"\n[request]\nmodel = \"local-model\"\nmax_tokens = 32\n"
+        ))
+        .unwrap();
+        let llm = config.build("gpt-4.1-mini").unwrap();
+        let reply = llm
+            .complete(LlmTask::Request, None, vec![user_message("hi".to_string())])
+            .await
+            .unwrap();
+        assert_eq!(reply.as_deref(), Some("hello from llama.cpp"));
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /v1/chat/completions"));
+        assert!(request.contains("\"model\":\"local-model\""));
+        assert!(request.contains("\"max_tokens\":32"));
+    }
+}
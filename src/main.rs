@@ -1,39 +1,73 @@
 use crate::chunking::chunk_string;
+use crate::classifier::{ClassifierInput, NaiveBayes, SpamCategory, Verdict};
 use crate::clean_messages::clean_message;
-use crate::consts::{BLUNDER_EMOJI_ID, BOT_CHANNEL, HONEY_POT_CHANNEL, SPAM_EATER_ID};
+use crate::config::{
+    BotConfig, Config, FailurePolicy, GuildConfig, Rule, SpamAction, DEFAULT_CONFIG_PATH,
+};
+use crate::llm::{LlmError, LlmTask};
 use crate::request::answer_request;
 use crate::roadmaps::{create_roadmap, is_message_roadmap_request};
-use crate::spam_detection::classify_message_spam;
+use crate::spam_waves::{Post, WaveAction, WaveKind};
+use crate::storage::{ActionKind, ModerationRecord, Storage, Store};
+use crate::strikes::{Offence, Step};
+use crate::trust::{TrustSignals, TrustTier};
+use crate::usage::Feature;
 use crate::user_info::retrieve_user_context;
+use anyhow::Context as _;
 use chrono::Duration;
+use clap::{Parser, Subcommand};
 use dotenv::dotenv;
-#[allow(deprecated)]
-use openai::set_key;
-use serenity::all::{EmojiId, Mention, Reaction, ReactionType, Timestamp};
+use serenity::all::{Http, Interaction, Mention, Reaction, ReactionType, Role, RoleId, Timestamp};
 use serenity::async_trait;
 use serenity::builder::CreateMessage;
 use serenity::model::channel::Message;
 use serenity::model::event::MessageUpdateEvent;
 use serenity::model::gateway::Ready;
-use serenity::model::id::{ChannelId, UserId};
+use serenity::model::id::{ChannelId, GuildId};
 use serenity::prelude::*;
-use std::collections::HashMap;
 use std::env;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{error, info};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use user_info::{UserContext, UserJoinDate};
 
+mod backtest;
+mod cassette;
 mod chunking;
+mod classifier;
 mod clean_messages;
+mod commands;
+mod config;
 mod consts;
+mod corpus;
+mod domain_lists;
+mod feedback;
+mod few_shot;
+mod fingerprint;
+mod links;
+mod llm;
 mod messaging;
+mod purge;
+mod reply_cache;
 mod request;
+mod resilience;
+mod review;
 mod roadmaps;
 mod spam_detection;
+mod spam_templates;
+mod spam_waves;
+mod storage;
+mod strikes;
+mod structured;
+mod trust;
+mod undo;
+mod usage;
 mod user_info;
 mod utilities;
 struct Handler;
@@ -41,32 +75,141 @@ struct Handler;
 #[derive(Debug)]
 enum MessageClassification {
     Normal,
-    MaybeSpam,
-    DefinitelySpam(String),
+    /// Couldn't be checked, for the given reason.
+    MaybeSpam(String),
+    /// `Rule` is sure enough to act, and `SpamAction` is how harshly.
+    DefinitelySpam(Verdict, SpamAction, Rule),
+}
+
+/// What the pipeline looks at in a message, so it can also run over a corpus without Discord.
+struct Candidate<'a> {
+    author_name: &'a str,
+    content: &'a str,
+    mention_everyone: bool,
+    trust: TrustSignals,
+    context: Vec<String>,
+    guild_id: Option<GuildId>,
 }
 
 async fn is_message_suspicious(
+    guild_config: &GuildConfig,
     message: &Message,
-    user_join_date: Option<i64>,
+    trust: TrustSignals,
+    context: Vec<String>,
+) -> MessageClassification {
+    classify_candidate(
+        guild_config,
+        Candidate {
+            author_name: message.author.name.as_str(),
+            content: message.content.as_str(),
+            mention_everyone: message.mention_everyone,
+            trust,
+            context,
+            guild_id: message.guild_id,
+        },
+    )
+    .await
+}
+
+/// Runs the blocklist, link and trust pre-filter, then the classifier.
+async fn classify_candidate(
+    guild_config: &GuildConfig,
+    candidate: Candidate<'_>,
 ) -> MessageClassification {
-    if (messaging::is_suspicious_url(message.content.as_str()) | message.mention_everyone)
-        && messaging::is_new_user(user_join_date)
+    let blocked_hosts: Vec<String> = links::extract_hosts(candidate.content)
+        .into_iter()
+        .filter(|host| guild_config.blocklist.matches(host))
+        .collect();
+    if !blocked_hosts.is_empty() {
+        // Known-bad domains are acted on whoever posts them, without asking the classifier.
+        let verdict = Verdict {
+            is_spam: true,
+            confidence: 1.0,
+            category: Some(SpamCategory::Phishing),
+            reason: format!("links to blocklisted {}", blocked_hosts.join(", ")),
+        };
+        return MessageClassification::DefinitelySpam(
+            verdict,
+            SpamAction::Escalate,
+            Rule::Blocklist,
+        );
+    }
+    if let Some(mut verdict) = guild_config
+        .spam_templates
+        .find(candidate.content, Instant::now())
     {
-        // TODO: Track the context of user messages
-        match classify_message_spam(message.content.clone(), vec![]).await {
-            Ok(classification) => {
-                if classification.is_spam {
-                    MessageClassification::DefinitelySpam(classification.reason)
-                } else {
+        // Copies of spam already removed get the same treatment, whoever posts them.
+        if let Some(action) = guild_config.spam_actions.action_for(&verdict) {
+            verdict.reason = format!("copy of removed spam ({})", verdict.reason);
+            let rule = Rule::Classifier(verdict.category);
+            return MessageClassification::DefinitelySpam(verdict, action, rule);
+        }
+    }
+    let suspicious_hosts = links::suspicious_hosts(candidate.content, &guild_config.allowlist);
+    let now = Timestamp::now().unix_timestamp();
+    let tier = guild_config.trust_tier(&candidate.trust, now);
+    let worth_checking = match tier {
+        TrustTier::New => !suspicious_hosts.is_empty() || candidate.mention_everyone,
+        // Established and trusted members only get checked for the classic lure, a link
+        // sent to everyone, which is also what a compromised account tends to post.
+        TrustTier::Member | TrustTier::Trusted => {
+            !suspicious_hosts.is_empty() && candidate.mention_everyone
+        }
+    };
+    if worth_checking {
+        if !suspicious_hosts.is_empty() {
+            info!(
+                "Message from {} user {} links to {}",
+                tier.as_str(),
+                candidate.author_name,
+                suspicious_hosts.join(", ")
+            );
+        }
+        let input = ClassifierInput {
+            content: candidate.content.to_string(),
+            context: candidate.context,
+            account_age: candidate
+                .trust
+                .account_created_at
+                .map(|created_at| Duration::seconds(now - created_at)),
+            member_age: candidate
+                .trust
+                .joined_at
+                .map(|joined_at| Duration::seconds(now - joined_at)),
+            message_count: candidate.trust.message_count,
+            trust: Some(tier),
+            guild_id: candidate.guild_id,
+            examples: guild_config.examples.select(candidate.content),
+        };
+        match guild_config.classifier.classify(&input).await {
+            Ok(verdict) => match guild_config.spam_actions.action_for(&verdict) {
+                Some(action) => {
+                    let rule = Rule::Classifier(verdict.category);
+                    MessageClassification::DefinitelySpam(verdict, action, rule)
+                }
+                None => {
                     info!(
-                        "Message ({}) hit filter, not considered suspicious due to {}",
-                        clean_message(message.content.as_str()),
-                        classification.reason
+                        "Message ({}) hit filter, not acted on due to {} (spam: {})",
+                        clean_message(candidate.content),
+                        verdict.summary(),
+                        verdict.is_spam
                     );
                     MessageClassification::Normal
                 }
+            },
+            Err(e) => {
+                match e.downcast_ref::<LlmError>() {
+                    Some(LlmError::Parse { .. }) => error!(
+                        "Classifier {} couldn't make sense of the model's reply: {e}",
+                        guild_config.classifier.name()
+                    ),
+                    _ => error!(
+                        "Classifier {} failed due to {e}",
+                        guild_config.classifier.name()
+                    ),
+                }
+                MessageClassification::MaybeSpam(format!("Spam detection was unavailable: {e}"))
             }
-            Err(_) => MessageClassification::MaybeSpam,
         }
     } else {
         MessageClassification::Normal
@@ -88,7 +231,12 @@ async fn reply_chunked(
     Ok(())
 }
 
-async fn handle_request(ctx: &Context, message: &Message) -> anyhow::Result<()> {
+async fn handle_request(
+    ctx: &Context,
+    config: &Config,
+    guild_id: GuildId,
+    message: &Message,
+) -> anyhow::Result<()> {
     // If the request is replying to someone, assume the reply target is the correct query.
     let maybe_query_author = match message.referenced_message {
         Some(ref referenced_message) => Some((
@@ -117,8 +265,8 @@ async fn handle_request(ctx: &Context, message: &Message) -> anyhow::Result<()>
 
     if let Some((query, context, author)) = maybe_query_author {
         // Ensure BSE doesn't reply to itself.
-        if author.id != SPAM_EATER_ID {
-            if let Some(response) = answer_request(query, context).await? {
+        if author.id != config.spam_eater_id {
+            if let Some(response) = answer_request(&config.llm, guild_id, query, context).await? {
                 reply_chunked(ctx, author.mention(), message.channel_id, response).await?;
             }
         }
@@ -126,13 +274,20 @@ async fn handle_request(ctx: &Context, message: &Message) -> anyhow::Result<()>
     Ok(())
 }
 
-async fn handle_roadmap(ctx: &Context, message: &Message) -> anyhow::Result<()> {
-    if is_message_roadmap_request(message.content.clone(), vec![])
+async fn handle_roadmap(
+    ctx: &Context,
+    config: &Config,
+    guild_id: GuildId,
+    message: &Message,
+) -> anyhow::Result<()> {
+    if is_message_roadmap_request(&config.llm, guild_id, message.content.clone(), vec![])
         .await?
         .is_roadmap
     {
-        let user_context = retrieve_user_context(ctx, message).await;
-        let created_roadmap = create_roadmap(message.content.clone(), user_context).await?;
+        let user_context =
+            retrieve_user_context(ctx, guild_id, message, Duration::minutes(1)).await;
+        let created_roadmap =
+            create_roadmap(&config.llm, guild_id, message.content.clone(), user_context).await?;
         reply_chunked(
             ctx,
             message.author.mention(),
@@ -164,42 +319,451 @@ async fn handle_ask(ctx: &Context, message: &Message) -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Carries out `policy` when `error` means the LLM couldn't help with `feature`. Other
+/// errors have already been logged and are left at that.
+async fn handle_llm_failure(
+    ctx: &Context,
+    guild_config: &GuildConfig,
+    message: &Message,
+    feature: &str,
+    policy: FailurePolicy,
+    error: &anyhow::Error,
+) {
+    if error.downcast_ref::<LlmError>().is_none() {
+        return;
+    }
+    let result = match policy {
+        FailurePolicy::FailOpen => return,
+        FailurePolicy::FailClosed => messaging::reply_unavailable(ctx, message, feature).await,
+        FailurePolicy::HoldForReview => {
+            messaging::log_held_request(ctx, guild_config.bot_channel, message, feature).await
+        }
+    };
+    if let Err(e) = result {
+        error!("Failed to report that {feature} couldn't be handled due to {e}")
+    }
+}
+
+/// Posts to every guild's bot channel whenever the LLM circuit breaker trips or recovers.
+async fn report_breaker_events(http: Arc<Http>, config: Arc<Config>) {
+    let mut events = config.llm.breaker_events();
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        error!("LLM circuit breaker: {event:?}");
+        for guild_id in config.guild_ids() {
+            let Some(guild_config) = config.guild(guild_id) else {
+                continue;
+            };
+            if let Err(e) = messaging::log_breaker_event(
+                &http,
+                guild_config.bot_channel,
+                &event,
+                guild_config.llm_failure.spam_detection,
+            )
+            .await
+            {
+                error!("Failed to report circuit breaker to guild {guild_id} due to {e}")
+            }
+        }
+    }
+}
+
+/// Shows a new message to the spam wave detector. If it completes or continues a wave,
+/// every copy is removed and each member in it dealt with once, and `true` is returned.
+async fn handle_spam_wave(ctx: &Context, guild_config: &GuildConfig, message: &Message) -> bool {
+    let Some(guild_id) = message.guild_id else {
+        return false;
+    };
+    let tier = guild_config.trust_tier(
+        &user_info::trust_signals(ctx, guild_id, message).await,
+        message.timestamp.unix_timestamp(),
+    );
+    let post = Post {
+        user_id: message.author.id,
+        channel_id: message.channel_id,
+        message_id: message.id,
+        new_user: tier == TrustTier::New,
+        trusted: tier == TrustTier::Trusted,
+        sent_at: message.timestamp.unix_timestamp(),
+    };
+    let Some(wave) = guild_config
+        .wave_detector
+        .observe(post, message.content.as_str())
+    else {
+        return false;
+    };
+    let waves = guild_config.wave_detector.config();
+    if guild_config.is_shadowed(Rule::SpamWave) {
+        info!(
+            "Shadow mode - spam wave ({:?}) - {}",
+            wave.kind,
+            message.content.as_str()
+        );
+        if !wave.users.is_empty() {
+            if let Err(e) = messaging::log_shadow_spam_wave(
+                ctx,
+                guild_config.bot_channel,
+                message.content.as_str(),
+                &wave,
+                waves.window_seconds,
+            )
+            .await
+            {
+                error!("Failed to log spam wave due to {e}");
+            }
+        }
+        return false;
+    }
+    info!(
+        "Spam wave ({:?}) - {} copies from {} members - {}",
+        wave.kind,
+        wave.copies.len(),
+        wave.users.len(),
+        message.content.as_str()
+    );
+    let mut deleted = 0;
+    for copy in &wave.copies {
+        match ctx
+            .http
+            .delete_message(copy.channel_id, copy.message_id, Some("Spam wave"))
+            .await
+        {
+            Ok(()) => deleted += 1,
+            Err(e) => info!(
+                "Couldn't delete spam wave copy {} due to {e}",
+                copy.message_id
+            ),
+        }
+    }
+    if wave.users.is_empty() {
+        return true;
+    }
+    let reason = match wave.kind {
+        WaveKind::CrossChannel => format!(
+            "posted the same message in {} channels within {}s",
+            wave.channels, waves.window_seconds
+        ),
+        WaveKind::NewUsers => format!(
+            "one of {} new members posting the same message within {}s",
+            wave.users.len(),
+            waves.window_seconds
+        ),
+    };
+    // The wave's own action is the least each member gets, and repeat offenders climb
+    // the ladder from there.
+    let floor = match waves.action {
+        WaveAction::Timeout => Step::Timeout {
+            minutes: waves.timeout_hours * 60,
+        },
+        WaveAction::Ban => Step::Ban,
+    };
+    for user_id in &wave.users {
+        let copy = wave
+            .copies
+            .iter()
+            .find(|copy| copy.user_id == *user_id)
+            .unwrap_or(&post);
+        let mut escalation = strikes::strike(
+            ctx,
+            &guild_config.strikes,
+            (guild_id, *user_id),
+            Some(copy.message_id),
+            Offence::SpamWave,
+            true,
+        )
+        .await;
+        escalation.step = escalation.step.harsher(floor);
+        let case_id = storage::record_action(
+            ctx,
+            ModerationRecord {
+                guild_id,
+                user_id: *user_id,
+                channel_id: copy.channel_id,
+                message_id: Some(copy.message_id),
+                action: ActionKind::SpamWave,
+                reason: Some(reason.clone()),
+                content: message.content.clone(),
+                created_at: Timestamp::now().unix_timestamp(),
+                mention_everyone: message.mention_everyone,
+            },
+        )
+        .await;
+        let log = messaging::log_spam_wave(
+            ctx,
+            guild_config.bot_channel,
+            message.content.as_str(),
+            copy,
+            reason.as_str(),
+            (deleted, &escalation),
+            case_id,
+        )
+        .await
+        .inspect_err(|e| error!("Failed to log spam wave due to {e}"))
+        .ok();
+        if let Err(e) = messaging::punish_logged(
+            ctx,
+            log,
+            (guild_id, *user_id),
+            escalation.step,
+            "Spam wave",
+            0,
+        )
+        .await
+        {
+            error!("Failed to deal with {user_id} after a spam wave due to {e:#}");
+        }
+    }
+    true
+}
+
+/// Removes what the author of `message` posted in the guild's purge window before it.
+async fn purge_and_log(ctx: &Context, guild_config: &GuildConfig, message: &Message) {
+    let Some(guild_id) = message.guild_id else {
+        return;
+    };
+    let window = Duration::minutes(guild_config.thresholds.purge_window_minutes);
+    if window.is_zero() {
+        return;
+    }
+    let since =
+        Timestamp::from_unix_timestamp(message.timestamp.unix_timestamp() - window.num_seconds())
+            .unwrap_or(message.timestamp);
+    let purged =
+        purge::purge_recent_messages(ctx, guild_id, message.author.id, since, &[message.id]).await;
+    info!(
+        "Purged {} messages from {} across {} channels, {} cut short",
+        purged.messages, message.author.name, purged.channels, purged.cut_short
+    );
+    if purged.messages > 0 || purged.cut_short > 0 {
+        if let Err(e) = messaging::log_purge(
+            ctx,
+            guild_config.bot_channel,
+            message.author.name.as_str(),
+            purged,
+            window,
+        )
+        .await
+        {
+            error!("Failed to log purge due to {e}");
+        }
+    }
+}
+
 async fn handle_message(ctx: Context, message: Message) {
-    match is_message_suspicious(
-        &message,
-        user_info::get_user_join_date(&ctx, &message.author).await,
-    )
-    .await
-    {
+    let Some(guild_id) = message.guild_id else {
+        return;
+    };
+    let config = config::get_config(&ctx).await;
+    let Some(guild_config) = config.guild(guild_id) else {
+        return;
+    };
+    let features = &guild_config.features;
+    let classification = if features.spam_detection {
+        is_message_suspicious(
+            &guild_config,
+            &message,
+            user_info::trust_signals(&ctx, guild_id, &message).await,
+            retrieve_user_context(
+                &ctx,
+                guild_id,
+                &message,
+                Duration::minutes(guild_config.thresholds.context_window_minutes),
+            )
+            .await,
+        )
+        .await
+    } else {
+        MessageClassification::Normal
+    };
+    let spam_policy = guild_config.llm_failure.spam_detection;
+    match classification {
         MessageClassification::Normal => {}
-        MessageClassification::MaybeSpam => {
+        MessageClassification::MaybeSpam(_) if spam_policy == FailurePolicy::FailOpen => {
+            info!(
+                "Couldn't check message - letting it through - {}",
+                message.content.as_str()
+            );
+        }
+        MessageClassification::MaybeSpam(_) if guild_config.is_shadowed(Rule::Classifier(None)) => {
+            info!(
+                "Shadow mode - would remove message - likely spam - {}",
+                message.content.as_str()
+            );
+            messaging::log_shadow_actions(
+                &ctx,
+                guild_config.bot_channel,
+                &message,
+                None,
+                true,
+                None,
+            )
+            .await
+            .unwrap();
+        }
+        MessageClassification::MaybeSpam(reason) if spam_policy == FailurePolicy::HoldForReview => {
+            info!(
+                "Holding message for review - couldn't check it - {}",
+                message.content.as_str()
+            );
+            match review::hold_for_review(&ctx, &guild_config, &message, reason.as_str()).await {
+                Ok(()) => {
+                    storage::record_action(
+                        &ctx,
+                        ModerationRecord::for_message(
+                            guild_id,
+                            &message,
+                            ActionKind::Hold,
+                            Some(reason.as_str()),
+                        ),
+                    )
+                    .await;
+                }
+                Err(e) => error!("Failed to hold message for review due to {e:#}"),
+            }
+        }
+        MessageClassification::MaybeSpam(_) => {
             info!(
                 "Removing message - likely spam - {}",
                 message.content.as_str()
             );
-            messaging::remove_message_and_log(&ctx, message.clone())
-                .await
-                .unwrap()
+            let case_id = storage::record_action(
+                &ctx,
+                ModerationRecord::for_message(guild_id, &message, ActionKind::Delete, None),
+            )
+            .await;
+            messaging::remove_message_and_log(
+                &ctx,
+                guild_config.bot_channel,
+                message.clone(),
+                case_id,
+            )
+            .await
+            .unwrap();
         }
-        MessageClassification::DefinitelySpam(reason) => {
+        MessageClassification::DefinitelySpam(verdict, action, rule)
+            if guild_config.is_shadowed(rule) =>
+        {
+            let reason = verdict.summary();
+            let escalation = strikes::strike(
+                &ctx,
+                &guild_config.strikes,
+                (guild_id, message.author.id),
+                Some(message.id),
+                Offence::Spam(action),
+                false,
+            )
+            .await;
             info!(
-                "Removing message - definitely spam - {}",
+                "Shadow mode - spam ({:?}) - {} - {} - {}",
+                action,
+                escalation.describe(),
+                reason.as_str(),
                 message.content.as_str()
             );
-            messaging::remove_warn_timeout_and_log(&ctx, message.clone(), reason.as_str())
-                .await
-                .unwrap()
+            messaging::log_shadow_actions(
+                &ctx,
+                guild_config.bot_channel,
+                &message,
+                Some(reason.as_str()),
+                action != SpamAction::Warn,
+                Some(&escalation),
+            )
+            .await
+            .unwrap();
+        }
+        MessageClassification::DefinitelySpam(verdict, action, _) => {
+            let reason = verdict.summary();
+            let deleted = action != SpamAction::Warn;
+            let escalation = strikes::strike(
+                &ctx,
+                &guild_config.strikes,
+                (guild_id, message.author.id),
+                Some(message.id),
+                Offence::Spam(action),
+                true,
+            )
+            .await;
+            info!(
+                "Spam ({:?}) - {} - {} - {}",
+                action,
+                escalation.describe(),
+                reason.as_str(),
+                message.content.as_str()
+            );
+            let case_id = storage::record_action(
+                &ctx,
+                ModerationRecord::for_message(
+                    guild_id,
+                    &message,
+                    ActionKind::for_step(escalation.step, deleted),
+                    Some(format!("{reason}; {}", escalation.describe()).as_str()),
+                ),
+            )
+            .await;
+            if let Err(e) = messaging::enforce_and_log(
+                &ctx,
+                guild_config.bot_channel,
+                &message,
+                reason.as_str(),
+                deleted,
+                &escalation,
+                case_id,
+            )
+            .await
+            {
+                error!(
+                    "Failed to act on spam from {} due to {e:#}",
+                    message.author.name
+                );
+            }
+            if escalation.step != Step::Warn {
+                // Purging takes a request per channel, so the gateway isn't kept waiting.
+                let (ctx, guild_config, message) =
+                    (ctx.clone(), guild_config.clone(), message.clone());
+                tokio::spawn(async move { purge_and_log(&ctx, &guild_config, &message).await });
+            }
+            if deleted {
+                guild_config
+                    .spam_templates
+                    .add(&message.content, &verdict, Instant::now());
+            }
         }
     }
-    if messaging::is_message_request(&message) {
-        if let Err(e) = handle_request(&ctx, &message).await {
-            error!("Failed to create reply due to {e}")
+    if features.requests && messaging::is_message_request(&message, config.spam_eater_id) {
+        if !config.llm.within_budget(guild_id, Feature::Requests) {
+            info!("Ignoring request - today's budget for requests is spent");
+        } else if let Err(e) = handle_request(&ctx, &config, guild_id, &message).await {
+            error!("Failed to create reply due to {e}");
+            handle_llm_failure(
+                &ctx,
+                &guild_config,
+                &message,
+                "a request",
+                guild_config.llm_failure.requests,
+                &e,
+            )
+            .await;
         }
-    } else if messaging::message_discusses_roadmaps(&message) {
-        if let Err(e) = handle_roadmap(&ctx, &message).await {
-            error!("Failed to create Roadmap due to {e}")
+    } else if features.roadmaps && messaging::message_discusses_roadmaps(&message) {
+        if !config.llm.within_budget(guild_id, Feature::Roadmaps) {
+            info!("Ignoring roadmap question - today's budget for roadmaps is spent");
+        } else if let Err(e) = handle_roadmap(&ctx, &config, guild_id, &message).await {
+            error!("Failed to create Roadmap due to {e}");
+            handle_llm_failure(
+                &ctx,
+                &guild_config,
+                &message,
+                "a roadmap",
+                guild_config.llm_failure.roadmaps,
+                &e,
+            )
+            .await;
         }
-    } else if messaging::is_message_ask(&message) {
+    } else if features.ask && messaging::is_message_ask(&message) {
         if let Err(e) = handle_ask(&ctx, &message).await {
             error!("Failed to link to don't ask to ask due to {e}")
         }
@@ -209,20 +773,94 @@ async fn handle_message(ctx: Context, message: Message) {
 #[async_trait]
 impl EventHandler for Handler {
     async fn message(&self, ctx: Context, msg: Message) {
-        if msg.channel_id != ChannelId::from(BOT_CHANNEL)
-            && msg.author.id != UserId::from(SPAM_EATER_ID)
-        {
-            if msg.channel_id == ChannelId::from(HONEY_POT_CHANNEL) {
-                info!("Received message in Honeypot channel - removing");
-                messaging::delete_message(&ctx, &msg).await.unwrap();
-                messaging::log_ban(&ctx, msg.author.name.as_str())
+        let Some(guild_id) = msg.guild_id else {
+            return;
+        };
+        let Some(guild_config) = config::get_guild_config(&ctx, msg.guild_id).await else {
+            return;
+        };
+        let spam_eater_id = config::get_config(&ctx).await.spam_eater_id;
+        if msg.channel_id != guild_config.bot_channel && msg.author.id != spam_eater_id {
+            if guild_config.features.honey_pot && msg.channel_id == guild_config.honey_pot_channel {
+                let shadowed = guild_config.is_shadowed(Rule::HoneyPot);
+                let escalation = strikes::strike(
+                    &ctx,
+                    &guild_config.strikes,
+                    (guild_id, msg.author.id),
+                    Some(msg.id),
+                    Offence::HoneyPot,
+                    !shadowed,
+                )
+                .await;
+                if shadowed {
+                    info!(
+                        "Shadow mode - would {} for posting in Honeypot channel",
+                        escalation.step.name()
+                    );
+                    messaging::log_shadow_honey_pot(
+                        &ctx,
+                        guild_config.bot_channel,
+                        msg.author.name.as_str(),
+                        &escalation,
+                    )
                     .await
                     .unwrap();
-                messaging::ban_user(&ctx, &msg.guild_id.unwrap(), &msg.author.id)
+                } else {
+                    info!(
+                        "Received message in Honeypot channel - removing - {}",
+                        escalation.describe()
+                    );
+                    if let Err(e) = messaging::delete_message(&ctx, &msg).await {
+                        error!("Failed to delete honeypot post due to {e}");
+                    }
+                    let action = match escalation.step {
+                        Step::Ban => ActionKind::HoneyPotBan,
+                        step => ActionKind::for_step(step, true),
+                    };
+                    let case_id = storage::record_action(
+                        &ctx,
+                        ModerationRecord::for_message(
+                            guild_id,
+                            &msg,
+                            action,
+                            Some(
+                                format!(
+                                    "Posted in the honeypot channel; {}",
+                                    escalation.describe()
+                                )
+                                .as_str(),
+                            ),
+                        ),
+                    )
+                    .await;
+                    let log = messaging::log_honey_pot(
+                        &ctx,
+                        guild_config.bot_channel,
+                        &msg,
+                        &escalation,
+                        case_id,
+                    )
                     .await
-                    .unwrap();
+                    .inspect_err(|e| error!("Failed to log honeypot post due to {e}"))
+                    .ok();
+                    if let Err(e) = messaging::punish_logged(
+                        &ctx,
+                        log,
+                        (guild_id, msg.author.id),
+                        escalation.step,
+                        "Spam Channel honeypot",
+                        guild_config.thresholds.honey_pot_delete_message_days,
+                    )
+                    .await
+                    {
+                        error!(
+                            "Failed to deal with {} after the honeypot due to {e:#}",
+                            msg.author.name
+                        );
+                    }
+                }
             }
-            user_info::update_user_context(&ctx, &msg).await;
+            user_info::update_user_context(&ctx, guild_id, &msg).await;
             match msg.member {
                 None => {
                     error!("Couldn't find MemberInfo for {:?}", msg.author);
@@ -230,10 +868,16 @@ impl EventHandler for Handler {
                 Some(ref member_info) => {
                     user_info::update_user_join_date(
                         &ctx,
+                        guild_id,
                         &msg.author,
                         member_info.joined_at.unwrap().unix_timestamp(),
                     )
                     .await;
+                    if guild_config.features.spam_waves
+                        && handle_spam_wave(&ctx, &guild_config, &msg).await
+                    {
+                        return;
+                    }
                     handle_message(ctx, msg).await;
                 }
             }
@@ -253,20 +897,27 @@ impl EventHandler for Handler {
     }
 
     async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let Some(guild_config) = config::get_guild_config(&ctx, reaction.guild_id).await else {
+            return;
+        };
+        if !guild_config.features.blunder_timeouts {
+            return;
+        }
+        let thresholds = &guild_config.thresholds;
         if let ReactionType::Custom {
             animated: _,
             id,
             name: _,
         } = &reaction.emoji
         {
-            if *id == BLUNDER_EMOJI_ID {
+            if *id == guild_config.blunder_emoji_id {
                 if let Ok(reacting_users) = reaction
                     .channel_id
                     .reaction_users(
                         &ctx,
                         reaction.message_id,
-                        EmojiId::new(BLUNDER_EMOJI_ID),
-                        Some(5),
+                        guild_config.blunder_emoji_id,
+                        Some(thresholds.blunder_reactions),
                         None,
                     )
                     .await
@@ -276,28 +927,93 @@ impl EventHandler for Handler {
                         reacting_users.len(),
                         reaction.message_id
                     );
-                    if reacting_users.len() >= 4 {
-                        let timeout_until = Timestamp::from_unix_timestamp(
-                            reaction.message_id.created_at().unix_timestamp()
-                                + Duration::minutes(15).num_seconds(),
+                    let message_age = Timestamp::now().unix_timestamp()
+                        - reaction.message_id.created_at().unix_timestamp();
+                    if reacting_users.len() >= usize::from(thresholds.blunder_reactions)
+                        && message_age
+                            < Duration::minutes(thresholds.blunder_window_minutes).num_seconds()
+                    {
+                        let (Some(guild_id), Some(author_id)) =
+                            (reaction.guild_id, reaction.message_author_id)
+                        else {
+                            return;
+                        };
+                        // Every reaction past the threshold lands here too.
+                        match storage::get_storage(&ctx)
+                            .await
+                            .has_strike_for(guild_id, reaction.message_id)
+                        {
+                            Ok(false) => {}
+                            Ok(true) => return,
+                            Err(e) => {
+                                error!(
+                                    "Couldn't check strikes for {} due to {e}",
+                                    reaction.message_id
+                                );
+                                return;
+                            }
+                        }
+                        let escalation = strikes::strike(
+                            &ctx,
+                            &guild_config.strikes,
+                            (guild_id, author_id),
+                            Some(reaction.message_id),
+                            Offence::Blunder,
+                            true,
                         )
-                        .unwrap();
-                        if timeout_until > Timestamp::now() {
-                            info!(
-                                "Timing out {} until {}",
-                                &reaction.message_author_id.unwrap(),
-                                timeout_until
-                            );
-                            if let Err(e) = messaging::timeout_user(
+                        .await;
+                        info!("Blunder by {} - {}", author_id, escalation.describe());
+                        let reason = format!("{} blunder reactions", reacting_users.len());
+                        if escalation.step == Step::Warn {
+                            if let Err(e) =
+                                messaging::warn_for_blunder(&ctx, reaction.channel_id, author_id)
+                                    .await
+                            {
+                                error!("Failed to warn user due to {e}")
+                            }
+                        }
+                        if let Err(e) = messaging::punish(
+                            &ctx,
+                            guild_id,
+                            author_id,
+                            escalation.step,
+                            reason.as_str(),
+                            0,
+                        )
+                        .await
+                        {
+                            error!("Failed to {} user due to {e}", escalation.step.name())
+                        } else {
+                            if let Err(e) = messaging::log_blunder(
                                 &ctx,
-                                &reaction.guild_id.unwrap(),
-                                &reaction.message_author_id.unwrap(),
-                                timeout_until,
+                                guild_config.bot_channel,
+                                author_id,
+                                reacting_users.len(),
+                                &escalation,
                             )
                             .await
                             {
-                                error!("Failed to timeout user due to {e}")
+                                error!("Failed to log blunder due to {e}")
                             }
+                            let action = match escalation.step {
+                                Step::Timeout { .. } => ActionKind::BlunderTimeout,
+                                step => ActionKind::for_step(step, false),
+                            };
+                            storage::record_action(
+                                &ctx,
+                                ModerationRecord {
+                                    guild_id,
+                                    user_id: author_id,
+                                    channel_id: reaction.channel_id,
+                                    message_id: Some(reaction.message_id),
+                                    action,
+                                    reason: Some(format!("{reason}; {}", escalation.describe())),
+                                    content: String::new(),
+                                    created_at: Timestamp::now().unix_timestamp(),
+                                    mention_everyone: false,
+                                },
+                            )
+                            .await;
                         }
                     }
                 }
@@ -305,15 +1021,115 @@ impl EventHandler for Handler {
         }
     }
 
-    async fn ready(&self, _: Context, ready: Ready) {
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(command) => {
+                if let Err(e) = commands::handle(&ctx, &command).await {
+                    error!("Failed to answer /{} due to {e}", command.data.name)
+                }
+            }
+            Interaction::Component(component) => {
+                let custom_id = component.data.custom_id.as_str();
+                if let Some((case_id, decision)) = review::parse_custom_id(custom_id) {
+                    if let Err(e) = review::handle_button(&ctx, &component, case_id, decision).await
+                    {
+                        error!("Failed to review case {case_id} due to {e}")
+                    }
+                } else if let Some((case_id, verdict)) = feedback::parse_custom_id(custom_id) {
+                    if let Err(e) =
+                        feedback::handle_button(&ctx, &component, case_id, verdict).await
+                    {
+                        error!("Failed to record feedback on case {case_id} due to {e}")
+                    }
+                } else if let Some(case_id) = undo::parse_custom_id(custom_id) {
+                    if let Err(e) = undo::handle_button(&ctx, &component, case_id).await {
+                        error!("Failed to undo case {case_id} due to {e}")
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    async fn ready(&self, ctx: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
+        let config = config::get_config(&ctx).await;
+        commands::register(&ctx, config.guild_ids()).await;
+        for guild_id in config.guild_ids() {
+            if let Err(e) = trust::load_moderator_roles(&ctx, guild_id).await {
+                error!("Failed to load moderator roles due to {e:#}");
+            }
+        }
+    }
+
+    async fn guild_role_create(&self, ctx: Context, new: Role) {
+        if let Err(e) = trust::load_moderator_roles(&ctx, new.guild_id).await {
+            error!("Failed to reload moderator roles due to {e:#}");
+        }
+    }
+
+    async fn guild_role_update(&self, ctx: Context, _old: Option<Role>, new: Role) {
+        if let Err(e) = trust::load_moderator_roles(&ctx, new.guild_id).await {
+            error!("Failed to reload moderator roles due to {e:#}");
+        }
+    }
+
+    async fn guild_role_delete(
+        &self,
+        ctx: Context,
+        guild_id: GuildId,
+        _removed_role_id: RoleId,
+        _removed_role: Option<Role>,
+    ) {
+        if let Err(e) = trust::load_moderator_roles(&ctx, guild_id).await {
+            error!("Failed to reload moderator roles due to {e:#}");
+        }
+    }
+}
+
+/// Today's LLM usage as JSON, for `GET /stats/cost`.
+fn cost_stats_json(config: &Config) -> String {
+    let today = chrono::Utc::now().date_naive();
+    let rows = config.llm.usage().today(today);
+    let total_cost_usd: f64 = rows.iter().map(|(_, usage)| usage.cost_usd).sum();
+    let usage: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|(key, usage)| {
+            serde_json::json!({
+                "guild_id": key.guild_id.map(|guild_id| guild_id.to_string()),
+                "task": key.task.as_str(),
+                "model": key.model,
+                "calls": usage.calls,
+                "prompt_tokens": usage.prompt_tokens,
+                "completion_tokens": usage.completion_tokens,
+                "cost_usd": usage.cost_usd,
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "day": today.to_string(),
+        "total_cost_usd": total_cost_usd,
+        "usage": usage,
+    })
+    .to_string()
+}
+
+/// Writes LLM usage through to the store every minute.
+async fn save_usage_periodically(config: Arc<Config>, storage: Arc<Storage>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Err(e) = storage.save_usage(config.llm.usage()) {
+            error!("Failed to save LLM usage due to {e}")
+        }
     }
 }
 
-async fn start_health_check() -> Result<(), Box<dyn std::error::Error>> {
+async fn start_health_check(config: Arc<Config>) -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind("0.0.0.0:8080").await?;
     loop {
         let (mut socket, _) = listener.accept().await?;
+        let config = config.clone();
         tokio::spawn(async move {
             let mut buffer = [0; 1024];
 
@@ -326,6 +1142,13 @@ async fn start_health_check() -> Result<(), Box<dyn std::error::Error>> {
                     if request.starts_with("GET /health_check HTTP/1.1") {
                         let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
                         let _ = socket.write_all(response.as_bytes()).await;
+                    } else if request.starts_with("GET /stats/cost HTTP/1.1") {
+                        let body = cost_stats_json(&config);
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                            body.len()
+                        );
+                        let _ = socket.write_all(response.as_bytes()).await;
                     }
                 }
                 Err(e) => eprintln!("Failed to read from socket: {:?}", e),
@@ -334,6 +1157,96 @@ async fn start_health_check() -> Result<(), Box<dyn std::error::Error>> {
     }
 }
 
+#[derive(Parser)]
+#[command(about = "A Discord bot that finds and removes spam")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Connect to Discord and start moderating (the default)
+    Run,
+    /// Train the offline spam model from a labelled JSONL corpus
+    Train {
+        /// JSONL file with one `{"content": ..., "is_spam": ...}` object per line
+        #[arg(long)]
+        corpus: PathBuf,
+        /// Where to write the trained model
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Replay a labelled JSONL corpus through the pre-filter and classifier, without Discord
+    Backtest {
+        /// JSONL corpus; entries may also carry `category`, `context`,
+        /// `account_age_days`, `member_age_minutes`, `message_count` and `mention_everyone`
+        #[arg(long)]
+        corpus: PathBuf,
+        /// Config to take the guild's lists, thresholds and classifier from,
+        /// instead of `BSE_CONFIG` or `config.toml`
+        #[arg(long)]
+        config: Option<PathBuf>,
+        /// Guild whose settings to use, if the config has more than one
+        #[arg(long)]
+        guild: Option<u64>,
+        /// Save per-message results here, to diff later runs against
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// Results saved by an earlier run to diff against
+        #[arg(long)]
+        previous: Option<PathBuf>,
+    },
+}
+
+fn train_model(corpus: &Path, output: &Path) -> anyhow::Result<()> {
+    let messages = corpus::read_corpus(corpus)?;
+    let model = NaiveBayes::train(&messages)?;
+    model.save(output)?;
+    let (spam, ham) = model.documents();
+    println!(
+        "Trained on {spam} spam and {ham} non-spam messages, saved to {}",
+        output.display()
+    );
+    Ok(())
+}
+
+/// `BSE_CONFIG` if set, otherwise `config.toml` in the working directory.
+fn default_config_path() -> PathBuf {
+    PathBuf::from(env::var("BSE_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string()))
+}
+
+async fn run_backtest(
+    corpus: &Path,
+    config: &Path,
+    guild: Option<u64>,
+    output: Option<&Path>,
+    previous: Option<&Path>,
+) -> anyhow::Result<()> {
+    let config = Config::load(config)?;
+    let guild_id = match guild {
+        Some(guild) => GuildId::new(guild),
+        None => {
+            let guild_ids: Vec<GuildId> = config.guild_ids().collect();
+            match guild_ids.as_slice() {
+                [guild_id] => *guild_id,
+                _ => anyhow::bail!("The config has several guilds - choose one with --guild"),
+            }
+        }
+    };
+    let guild_config = config
+        .guild(guild_id)
+        .with_context(|| format!("Guild {guild_id} isn't in the config"))?;
+    let previous = previous.map(backtest::load_results).transpose()?;
+    let messages = corpus::read_corpus(corpus)?;
+    let results = backtest::run(&guild_config, &messages).await;
+    backtest::print_report(&results, previous.as_deref());
+    if let Some(output) = output {
+        backtest::save_results(output, &results)?;
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     dotenv().ok();
@@ -341,11 +1254,68 @@ async fn main() {
         .with(fmt::layer())
         .with(EnvFilter::from_default_env())
         .init();
+    match Cli::parse().command.unwrap_or(Command::Run) {
+        Command::Run => run_bot().await,
+        Command::Train { corpus, output } => {
+            train_model(&corpus, &output).expect("Failed to train spam model")
+        }
+        Command::Backtest {
+            corpus,
+            config,
+            guild,
+            output,
+            previous,
+        } => run_backtest(
+            &corpus,
+            &config.unwrap_or_else(default_config_path),
+            guild,
+            output.as_deref(),
+            previous.as_deref(),
+        )
+        .await
+        .expect("Failed to run backtest"),
+    }
+}
+
+async fn run_bot() {
     // Configure the client with your Discord bot token in the environment.
     let token = env::var("DISCORD_TOKEN").expect("Expected a token in the environment");
-    let openai_key = env::var("OPENAI_KEY").expect("Expected an OpenAI Key in the environment");
-    #[allow(deprecated)]
-    set_key(openai_key);
+    let config = Config::load(&default_config_path()).expect("Failed to load configuration");
+    let storage = Storage::open(&config.database_path).expect("Failed to open database");
+    let join_dates = storage
+        .load_join_dates()
+        .expect("Failed to load join dates");
+    let user_contexts = storage
+        .load_user_contexts()
+        .expect("Failed to load message history");
+    let mut examples = 0;
+    for guild_id in config.guild_ids() {
+        let guild_config = config.guild(guild_id).unwrap();
+        for (content, hosts, is_spam) in storage
+            .labelled_examples(guild_id, few_shot::MAX_EXAMPLES)
+            .expect("Failed to load labelled examples")
+        {
+            guild_config.examples.add(content.as_str(), hosts, is_spam);
+            examples += 1;
+        }
+    }
+    let today = chrono::Utc::now().date_naive();
+    config.llm.usage().restore(
+        today,
+        storage.load_usage(today).expect("Failed to load LLM usage"),
+    );
+    info!(
+        "Using {} for spam, {} for requests and {} for roadmaps",
+        config.llm.model(LlmTask::Spam),
+        config.llm.model(LlmTask::Request),
+        config.llm.model(LlmTask::RoadmapCreate)
+    );
+    info!(
+        "Loaded {} join dates, {} message histories and {examples} labelled examples from {}",
+        join_dates.len(),
+        user_contexts.len(),
+        config.database_path.display()
+    );
     // Set gateway intents, which decides what events the bot will be notified about
     let intents = GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::MESSAGE_CONTENT
@@ -357,14 +1327,21 @@ async fn main() {
         .await
         .expect("Err creating client");
 
+    let config = Arc::new(config);
+    let storage = Arc::new(storage);
+    tokio::spawn(report_breaker_events(client.http.clone(), config.clone()));
+    tokio::spawn(save_usage_periodically(config.clone(), storage.clone()));
+
     {
         let mut data = client.data.write().await;
-        data.insert::<UserJoinDate>(Arc::new(RwLock::new(HashMap::default())));
-        data.insert::<UserContext>(Arc::new(RwLock::new(HashMap::default())));
+        data.insert::<BotConfig>(config.clone());
+        data.insert::<Store>(storage);
+        data.insert::<UserJoinDate>(Arc::new(RwLock::new(join_dates)));
+        data.insert::<UserContext>(Arc::new(RwLock::new(user_contexts)));
     }
 
     tokio::spawn(async {
-        if let Err(e) = start_health_check().await {
+        if let Err(e) = start_health_check(config).await {
             eprintln!("Health check service failed: {}", e);
         }
     });
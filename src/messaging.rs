@@ -1,28 +1,38 @@
 use crate::clean_messages::clean_message;
-use crate::consts::{BOT_CHANNEL, SPAM_EATER_ID, VAGUELY_OKAY_WEBSITES};
-use chrono::{Duration, TimeZone, Utc};
+use crate::config::FailurePolicy;
+use crate::feedback;
+use crate::purge::Purged;
+use crate::resilience::BreakerEvent;
+use crate::spam_waves::{Post, Wave, WaveKind};
+use crate::strikes::{Escalation, Step};
+use crate::undo;
+use anyhow::{bail, Context as _};
+use chrono::Duration;
 use serenity::all::{
-    ChannelId, Context, CreateMessage, GuildId, Mentionable, Message, Timestamp, User, UserId,
+    ChannelId, Context, CreateActionRow, CreateAllowedMentions, CreateEmbed, CreateEmbedFooter,
+    CreateMessage, CreateWebhook, EditMember, EditMessage, ExecuteWebhook, GuildId, Http,
+    HttpError, Mentionable, Message, Timestamp, User, UserId,
 };
 
-pub fn is_suspicious_url(path: &str) -> bool {
-    path.contains("http")
-        // Not in any of our approved websites
-        && (!(VAGUELY_OKAY_WEBSITES
-        .iter()
-        .any(|website| path.contains(website))))
-}
+/// Discord's error code for a member who isn't in the guild.
+pub const UNKNOWN_MEMBER: isize = 10_007;
+/// Discord's error code for a message that was deleted.
+pub const UNKNOWN_MESSAGE: isize = 10_008;
+/// Discord's error code for a user who isn't banned.
+pub const UNKNOWN_BAN: isize = 10_026;
 
-/// Was the user's account created in the last hour?
-pub fn is_new_user(timestamp: Option<i64>) -> bool {
-    if let Some(time) = timestamp {
-        let diff: Duration = Utc::now() - Utc.timestamp_millis_opt(time * 1_000).unwrap();
-        diff.num_hours() <= 1
-    } else {
-        true
-    }
+/// Whether Discord refused a request with JSON error `code`.
+pub fn is_discord_error(error: &serenity::Error, code: isize) -> bool {
+    matches!(
+        error,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if response.error.code == code
+    )
 }
 
+/// Name of the webhook restored messages are posted through.
+const RESTORE_WEBHOOK_NAME: &str = "Spam Eater restores";
+
 async fn warn_user_generic(
     ctx: &Context,
     channel_id: ChannelId,
@@ -52,6 +62,21 @@ async fn warn_user_with_reason(
     .await
 }
 
+async fn warn_user_without_removal(
+    ctx: &Context,
+    channel_id: ChannelId,
+    user: &User,
+    reason: &str,
+) -> serenity::Result<Message> {
+    warn_user_with_message(
+        ctx,
+        channel_id,
+        user,
+        format!("please keep adverts and self-promotion out of the server - your last message looks like `{reason}`"),
+    )
+    .await
+}
+
 async fn warn_user_with_message(
     ctx: &Context,
     channel_id: ChannelId,
@@ -69,44 +94,303 @@ async fn warn_user_with_message(
         .await
 }
 
+fn format_reason(reason: Option<&str>) -> String {
+    match reason {
+        None => "".to_string(),
+        Some(reason) => format!(" because `{}`", reason),
+    }
+}
+
+fn describe_actions(deleted: bool, escalation: Option<&Escalation>) -> String {
+    match escalation {
+        None if !deleted => "left it alone".to_string(),
+        None => "deleted it".to_string(),
+        Some(escalation) => format!(
+            "{}{} - {}",
+            if deleted { "deleted it and " } else { "" },
+            escalation.step.describe(),
+            escalation.describe()
+        ),
+    }
+}
+
+/// A log entry about an action taken against a message's author.
+fn action_embed(title: &str, message: &Message, what_was_done: String) -> CreateEmbed {
+    CreateEmbed::new()
+        .title(title)
+        .description(clean_message(message.content.as_str()))
+        .field(
+            "Author",
+            format!("{} ({})", message.author.id.mention(), message.author.name),
+            true,
+        )
+        .field("Channel", message.channel_id.mention().to_string(), true)
+        .field("What I did", what_was_done, false)
+        .colour(0xE7_4C_3C)
+}
+
+/// Posts `embed` to the bot channel, with buttons to judge or undo the action if it was
+/// recorded.
+async fn log_with_buttons(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    mut embed: CreateEmbed,
+    case_id: Option<i64>,
+) -> serenity::Result<Message> {
+    let mut log = CreateMessage::new();
+    if let Some(case_id) = case_id {
+        embed = embed.footer(CreateEmbedFooter::new(format!("Case {case_id}")));
+        let mut buttons = feedback::buttons(case_id);
+        buttons.push(undo::button(case_id));
+        log = log.components(vec![CreateActionRow::Buttons(buttons)]);
+    }
+    bot_channel.send_message(&ctx.http, log.embed(embed)).await
+}
+
+/// Takes `step` against a member whose case is already logged, adding it to the log
+/// entry if Discord refuses.
+pub async fn punish_logged(
+    ctx: &Context,
+    log: Option<Message>,
+    (guild_id, user_id): (GuildId, UserId),
+    step: Step,
+    reason: &str,
+    delete_message_days: u8,
+) -> anyhow::Result<()> {
+    let Err(e) = punish(ctx, guild_id, user_id, step, reason, delete_message_days).await else {
+        return Ok(());
+    };
+    let failure = format!("Couldn't {}: {e}", step.name());
+    if let Some(mut log) = log {
+        note_failure(ctx, &mut log, failure.as_str()).await?;
+    }
+    bail!(failure)
+}
+
+/// Adds what the bot couldn't do after all to its log entry.
+async fn note_failure(ctx: &Context, log: &mut Message, failure: &str) -> serenity::Result<()> {
+    let Some(embed) = log.embeds.first().cloned() else {
+        return Ok(());
+    };
+    let embed = CreateEmbed::from(embed).field("Failed", failure, false);
+    log.edit(&ctx.http, EditMessage::new().embed(embed)).await
+}
+
 async fn log_actions(
     ctx: &Context,
-    content: &str,
-    author_name: &str,
+    bot_channel: ChannelId,
+    message: &Message,
     reason: Option<&str>,
-    timeout: bool,
+    deleted: bool,
+    escalation: Option<&Escalation>,
+    case_id: Option<i64>,
 ) -> serenity::Result<Message> {
-    let formatted_reason = match reason {
-        None => "".to_string(),
-        Some(reason) => format!(" because `{}`", reason),
-    };
-    let actions_taken = if timeout {
-        " and timed them out until tomorrow"
-    } else {
-        ""
+    let mut embed = action_embed(
+        "Suspicious message",
+        message,
+        describe_actions(deleted, escalation),
+    );
+    if let Some(reason) = reason {
+        embed = embed.field("Reason", reason, false);
     }
-    .to_string();
-    ChannelId::from(BOT_CHANNEL)
+    log_with_buttons(ctx, bot_channel, embed, case_id).await
+}
+
+/// Reports what a rule in shadow mode would have done, without warning, deleting or timing out.
+pub async fn log_shadow_actions(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    message: &Message,
+    reason: Option<&str>,
+    deleted: bool,
+    escalation: Option<&Escalation>,
+) -> serenity::Result<Message> {
+    bot_channel
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().content(format!(
+                "Hey bot team! I found '{}' from {} suspicious{}. I'm in shadow mode, so I left it alone, but I would have {}.",
+                clean_message(message.content.as_str()),
+                message.author.name,
+                format_reason(reason),
+                describe_actions(deleted, escalation)
+            )),
+        )
+        .await
+}
+
+/// Asks the bot channel to pick up a `feature` request the bot couldn't answer.
+pub async fn log_held_request(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    message: &Message,
+    feature: &str,
+) -> serenity::Result<Message> {
+    bot_channel
         .send_message(
             &ctx.http,
             CreateMessage::new().content(format!(
-                "Hey bot team! I found '{}' from {} suspicious{}, so I deleted it{}. :)",
+                "Hey bot team! {} asked for {feature} but I can't reach the language model right now - could one of you pick it up? {}",
+                message.author.name,
+                message.link()
+            )),
+        )
+        .await
+}
+
+/// Tells the author the bot can't do `feature` for them right now.
+pub async fn reply_unavailable(
+    ctx: &Context,
+    message: &Message,
+    feature: &str,
+) -> serenity::Result<Message> {
+    warn_user_with_message(
+        ctx,
+        message.channel_id,
+        &message.author,
+        format!("sorry, I can't do {feature} right now - please try again later."),
+    )
+    .await
+}
+
+/// Tells a guild's bot team the circuit breaker around the LLM tripped or recovered.
+pub async fn log_breaker_event(
+    http: &Http,
+    bot_channel: ChannelId,
+    event: &BreakerEvent,
+    spam_policy: FailurePolicy,
+) -> serenity::Result<Message> {
+    let content = match event {
+        BreakerEvent::Tripped {
+            failures,
+            cooldown,
+            error,
+        } => format!(
+            "Hey bot team! The language model failed {failures} times in a row (`{error}`), so I've stopped asking it for {}s at a time. Until it's back, messages I'd have checked are {}.",
+            cooldown.as_secs(),
+            spam_policy.describe()
+        ),
+        BreakerEvent::Recovered => {
+            "Hey bot team! The language model is answering again, so I'm back to checking messages.".to_string()
+        }
+    };
+    bot_channel
+        .send_message(http, CreateMessage::new().content(content))
+        .await
+}
+
+/// One entry for a spam wave in shadow mode, however many copies and members it took.
+pub async fn log_shadow_spam_wave(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    content: &str,
+    wave: &Wave,
+    window_seconds: i64,
+) -> serenity::Result<Message> {
+    let members: Vec<String> = wave
+        .users
+        .iter()
+        .map(|user| user.mention().to_string())
+        .collect();
+    let who = match wave.kind {
+        WaveKind::CrossChannel => members.join(", "),
+        WaveKind::NewUsers => format!("{} new members ({})", members.len(), members.join(", ")),
+    };
+    bot_channel
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().content(format!(
+                "Hey bot team! {who} posted '{}' {} times across {} channels within {window_seconds}s, but I'm in shadow mode, so I left it alone.",
                 clean_message(content),
+                wave.copies.len(),
+                wave.channels
+            )),
+        )
+        .await
+}
+
+/// An entry for one member dealt with for a spam wave, so each can be judged or undone.
+pub async fn log_spam_wave(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    content: &str,
+    copy: &Post,
+    reason: &str,
+    (deleted, escalation): (usize, &Escalation),
+    case_id: Option<i64>,
+) -> serenity::Result<Message> {
+    let embed = CreateEmbed::new()
+        .title("Spam wave")
+        .description(clean_message(content))
+        .field("Author", copy.user_id.mention().to_string(), true)
+        .field("Channel", copy.channel_id.mention().to_string(), true)
+        .field(
+            "What I did",
+            format!(
+                "deleted {deleted} copies and {} - {}",
+                escalation.step.describe(),
+                escalation.describe()
+            ),
+            false,
+        )
+        .field("Reason", reason, false)
+        .colour(0xE7_4C_3C);
+    log_with_buttons(ctx, bot_channel, embed, case_id).await
+}
+
+pub async fn log_honey_pot(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    message: &Message,
+    escalation: &Escalation,
+    case_id: Option<i64>,
+) -> serenity::Result<Message> {
+    let embed = action_embed(
+        "Posted in the honeypot",
+        message,
+        format!(
+            "deleted it and {} - {}",
+            escalation.step.describe(),
+            escalation.describe()
+        ),
+    );
+    log_with_buttons(ctx, bot_channel, embed, case_id).await
+}
+
+pub async fn log_shadow_honey_pot(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    author_name: &str,
+    escalation: &Escalation,
+) -> serenity::Result<Message> {
+    bot_channel
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().content(format!(
+                "Hey bot team! '{}' posted in THE CHANNEL. I'm in shadow mode, so I left them alone, but I would have deleted it and {} - {}.",
                 author_name,
-                formatted_reason,
-                actions_taken
+                escalation.step.describe(),
+                escalation.describe()
             )),
         )
         .await
 }
 
-pub async fn log_ban(ctx: &Context, author_name: &str) -> serenity::Result<Message> {
-    ChannelId::from(BOT_CHANNEL)
+pub async fn log_blunder(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    user_id: UserId,
+    reactions: usize,
+    escalation: &Escalation,
+) -> serenity::Result<Message> {
+    bot_channel
         .send_message(
             &ctx.http,
             CreateMessage::new().content(format!(
-                "Hey bot team! '{}' posted in THE CHANNEL, so I deleted them :)",
-                author_name
+                "Hey bot team! {reactions} members marked a message from {} as a blunder, so I {} - {}.",
+                user_id.mention(),
+                escalation.step.describe(),
+                escalation.describe()
             )),
         )
         .await
@@ -116,9 +400,15 @@ pub async fn delete_message(ctx: &Context, message: &Message) -> serenity::Resul
     message.delete(&ctx.http).await
 }
 
-pub async fn ban_user(ctx: &Context, guild_id: &GuildId, user: &UserId) -> serenity::Result<()> {
+pub async fn ban_user(
+    ctx: &Context,
+    guild_id: &GuildId,
+    user: &UserId,
+    delete_message_days: u8,
+    reason: &str,
+) -> serenity::Result<()> {
     guild_id
-        .ban_with_reason(&ctx.http, user, 7, "Spam Channel honeypot")
+        .ban_with_reason(&ctx.http, user, delete_message_days, reason)
         .await
 }
 
@@ -135,7 +425,80 @@ pub(crate) async fn timeout_user(
         .await
 }
 
-pub async fn remove_message_and_log(ctx: &Context, message: Message) -> anyhow::Result<()> {
+/// Ends a member's timeout early.
+pub async fn lift_timeout(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+) -> serenity::Result<()> {
+    guild_id
+        .edit_member(&ctx.http, user_id, EditMember::new().enable_communication())
+        .await?;
+    Ok(())
+}
+
+/// Puts a removed message back in its channel through the bot's webhook, under its
+/// author's name and avatar, labelled as restored. Mentions in it don't ping anyone.
+pub async fn repost_restored(
+    ctx: &Context,
+    channel_id: ChannelId,
+    user_id: UserId,
+    author_name: &str,
+    content: &str,
+) -> anyhow::Result<()> {
+    // Threads don't have webhooks of their own, so post through their parent's.
+    let channel = channel_id
+        .to_channel(&ctx.http)
+        .await?
+        .guild()
+        .context("Only guild messages can be restored")?;
+    let (webhook_channel, thread) = match channel.thread_metadata {
+        Some(_) => (
+            channel.parent_id.context("Thread without a parent")?,
+            Some(channel_id),
+        ),
+        None => (channel_id, None),
+    };
+    let existing = webhook_channel
+        .webhooks(&ctx.http)
+        .await?
+        .into_iter()
+        .find(|webhook| {
+            webhook.token.is_some() && webhook.name.as_deref() == Some(RESTORE_WEBHOOK_NAME)
+        });
+    let webhook = match existing {
+        Some(webhook) => webhook,
+        None => {
+            webhook_channel
+                .create_webhook(&ctx.http, CreateWebhook::new(RESTORE_WEBHOOK_NAME))
+                .await?
+        }
+    };
+    let mut restored = ExecuteWebhook::new()
+        .content(content)
+        .username(format!("{author_name} (restored)"))
+        .allowed_mentions(CreateAllowedMentions::new());
+    if let Some(avatar_url) = user_id
+        .to_user(ctx)
+        .await
+        .ok()
+        .and_then(|user| user.avatar_url())
+    {
+        restored = restored.avatar_url(avatar_url);
+    }
+    if let Some(thread) = thread {
+        restored = restored.in_thread(thread);
+    }
+    webhook.execute(&ctx.http, false, restored).await?;
+    Ok(())
+}
+
+pub async fn remove_message_and_log(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    message: Message,
+    case_id: Option<i64>,
+) -> anyhow::Result<()> {
     warn_user_generic(ctx, message.channel_id, &message.author).await?;
     ctx.http
         .delete_message(
@@ -144,63 +507,165 @@ pub async fn remove_message_and_log(ctx: &Context, message: Message) -> anyhow::
             Some("Updated message with banned content"),
         )
         .await?;
-    log_actions(
-        ctx,
-        message.content.as_str(),
-        message.author.name.as_str(),
-        None,
-        false,
-    )
-    .await?;
+    log_actions(ctx, bot_channel, &message, None, true, None, case_id).await?;
     Ok(())
 }
 
-pub async fn remove_warn_timeout_and_log(
+/// Asks the author of a message members marked as a blunder to take more care.
+pub async fn warn_for_blunder(
     ctx: &Context,
-    message: Message,
+    channel_id: ChannelId,
+    user_id: UserId,
+) -> serenity::Result<Message> {
+    channel_id
+        .send_message(
+            &ctx.http,
+            CreateMessage::new().content(format!(
+                "Hi {}, several members flagged your last message - please take a bit more care.",
+                user_id.mention()
+            )),
+        )
+        .await
+}
+
+/// Takes a ladder `step` against a member. Warnings are posted separately, since what
+/// they say depends on what happened to the message.
+pub async fn punish(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    step: Step,
     reason: &str,
+    delete_message_days: u8,
+) -> serenity::Result<()> {
+    match step {
+        Step::Warn => Ok(()),
+        Step::Timeout { minutes } => {
+            timeout_user(
+                ctx,
+                &guild_id,
+                &user_id,
+                Timestamp::from_unix_timestamp(
+                    Timestamp::now().unix_timestamp() + Duration::minutes(minutes).num_seconds(),
+                )
+                .unwrap(),
+            )
+            .await
+        }
+        Step::Kick => guild_id.kick_with_reason(&ctx.http, user_id, reason).await,
+        Step::Ban => ban_user(ctx, &guild_id, &user_id, delete_message_days, reason).await,
+    }
+}
+
+/// Logs the case, then warns the author, removes the message if `delete` and takes the
+/// ladder step against them. Whatever of that fails is added to the log entry, which is
+/// posted first so the case can be undone either way.
+pub async fn enforce_and_log(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    message: &Message,
+    reason: &str,
+    delete: bool,
+    escalation: &Escalation,
+    case_id: Option<i64>,
 ) -> anyhow::Result<()> {
-    warn_user_with_reason(ctx, message.channel_id, &message.author, reason).await?;
-    ctx.http
-        .delete_message(
-            message.channel_id,
-            message.id,
-            Some("Message with banned content"),
-        )
-        .await?;
-    timeout_user(
+    let log = log_actions(
         ctx,
-        &message.guild_id.unwrap(),
-        &message.author.id,
-        Timestamp::from_unix_timestamp(
-            Timestamp::now().unix_timestamp() + Duration::days(1).num_seconds(),
-        )
-        .unwrap(),
+        bot_channel,
+        message,
+        Some(reason),
+        delete,
+        Some(escalation),
+        case_id,
     )
-    .await?;
-    log_actions(
+    .await;
+    let mut failures: Vec<String> = vec![];
+    // Members about to be kicked or banned won't read a warning.
+    if matches!(escalation.step, Step::Warn | Step::Timeout { .. }) {
+        let warned = if delete {
+            warn_user_with_reason(ctx, message.channel_id, &message.author, reason).await
+        } else {
+            warn_user_without_removal(ctx, message.channel_id, &message.author, reason).await
+        };
+        if let Err(e) = warned {
+            failures.push(format!("Couldn't warn them: {e}"));
+        }
+    }
+    if delete {
+        if let Err(e) = ctx
+            .http
+            .delete_message(
+                message.channel_id,
+                message.id,
+                Some("Message with banned content"),
+            )
+            .await
+        {
+            failures.push(format!("Couldn't delete it: {e}"));
+        }
+    }
+    if let Err(e) = punish(
         ctx,
-        message.content.as_str(),
-        message.author.name.as_str(),
-        Some(reason),
-        true,
+        message.guild_id.unwrap(),
+        message.author.id,
+        escalation.step,
+        reason,
+        0,
     )
-    .await?;
+    .await
+    {
+        failures.push(format!("Couldn't {}: {e}", escalation.step.name()));
+    }
+    let mut log = match log {
+        Ok(log) => log,
+        Err(e) if failures.is_empty() => return Err(e).context("Couldn't log the case"),
+        Err(e) => bail!("Couldn't log the case due to {e}. {}", failures.join(". ")),
+    };
+    if !failures.is_empty() {
+        let failure = failures.join("\n");
+        note_failure(ctx, &mut log, failure.as_str()).await?;
+        bail!("{}", failures.join(". "))
+    }
     Ok(())
 }
 
+/// Reports the member's earlier messages removed after they were timed out for spam.
+pub async fn log_purge(
+    ctx: &Context,
+    bot_channel: ChannelId,
+    author_name: &str,
+    purged: Purged,
+    window: Duration,
+) -> serenity::Result<Message> {
+    let mut content = format!(
+        "Hey bot team! I also removed {} messages {} sent in the last {} minutes, across {} channels.",
+        purged.messages,
+        author_name,
+        window.num_minutes(),
+        purged.channels
+    );
+    if purged.cut_short > 0 {
+        content = format!(
+            "{content} {} busy channels had more messages than I could search, so some may be left.",
+            purged.cut_short
+        );
+    }
+    bot_channel
+        .send_message(&ctx.http, CreateMessage::new().content(content))
+        .await
+}
+
 pub fn message_discusses_roadmaps(message: &Message) -> bool {
     message.content.to_lowercase().contains("roadmap")
         | message.content.to_lowercase().contains("road map")
 }
 
-pub fn is_message_request(message: &Message) -> bool {
-    let spam_eater_user_id = UserId::from(SPAM_EATER_ID);
+pub fn is_message_request(message: &Message, spam_eater_id: UserId) -> bool {
     message
         .mentions
         .iter()
         .map(|user| user.id)
-        .any(|id| id == spam_eater_user_id)
+        .any(|id| id == spam_eater_id)
         | message.content.to_lowercase().starts_with("!request")
 }
 
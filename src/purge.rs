@@ -0,0 +1,215 @@
+use crate::storage::get_storage;
+use serenity::all::{
+    ChannelId, ChannelType, Context, GetMessages, GuildId, MessageId, Timestamp, UserId,
+};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::{error, info};
+
+/// Most messages Discord returns, or bulk deletes, per request. Each channel searched
+/// costs one request for its latest messages, so a purge stays bounded however busy the
+/// channels are.
+const MESSAGES_PER_REQUEST: u8 = 100;
+
+/// What a purge removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Purged {
+    pub messages: usize,
+    pub channels: usize,
+    /// Channels with more messages since the window began than one request returns,
+    /// where older ones may have been missed.
+    pub cut_short: usize,
+}
+
+/// Where to look for messages that weren't tracked: channels the member was seen
+/// posting in first, then every text channel and active thread with a message since
+/// `since`.
+fn channels_to_search(
+    tracked: &[(ChannelId, MessageId)],
+    listed: impl IntoIterator<Item = (ChannelId, Option<MessageId>)>,
+    since: Timestamp,
+) -> Vec<ChannelId> {
+    let active = listed.into_iter().filter_map(|(channel_id, last_message)| {
+        last_message
+            .is_some_and(|message_id| message_id.created_at() >= since)
+            .then_some(channel_id)
+    });
+    let mut channels: Vec<ChannelId> = vec![];
+    for channel_id in tracked
+        .iter()
+        .map(|(channel_id, _)| *channel_id)
+        .chain(active)
+    {
+        if !channels.contains(&channel_id) {
+            channels.push(channel_id);
+        }
+    }
+    channels
+}
+
+/// Groups messages by channel, without duplicates or those in `except`.
+fn by_channel(
+    found: impl IntoIterator<Item = (ChannelId, MessageId)>,
+    except: &[MessageId],
+) -> BTreeMap<ChannelId, BTreeSet<MessageId>> {
+    let mut channels: BTreeMap<ChannelId, BTreeSet<MessageId>> = BTreeMap::new();
+    for (channel_id, message_id) in found {
+        if !except.contains(&message_id) {
+            channels.entry(channel_id).or_default().insert(message_id);
+        }
+    }
+    channels
+}
+
+/// The member's messages among the latest in the channels they were seen in, and in
+/// the guild's other text channels and active threads, and how many channels had more
+/// since `since` than were fetched.
+async fn fetch_recent(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    since: Timestamp,
+    tracked: &[(ChannelId, MessageId)],
+) -> (Vec<(ChannelId, MessageId)>, usize) {
+    let mut listed = match guild_id.channels(&ctx.http).await {
+        Ok(channels) => channels
+            .into_values()
+            .filter(|channel| matches!(channel.kind, ChannelType::Text | ChannelType::News))
+            .map(|channel| (channel.id, channel.last_message_id))
+            .collect(),
+        Err(e) => {
+            error!("Couldn't list channels in {guild_id} to purge due to {e}");
+            vec![]
+        }
+    };
+    match guild_id.get_active_threads(&ctx.http).await {
+        Ok(threads) => listed.extend(
+            threads
+                .threads
+                .iter()
+                .map(|thread| (thread.id, thread.last_message_id)),
+        ),
+        Err(e) => error!("Couldn't list threads in {guild_id} to purge due to {e}"),
+    }
+    let mut found = vec![];
+    let mut cut_short = 0;
+    for channel_id in channels_to_search(tracked, listed, since) {
+        // Channels the bot can't read are skipped.
+        let Ok(messages) = channel_id
+            .messages(&ctx.http, GetMessages::new().limit(MESSAGES_PER_REQUEST))
+            .await
+        else {
+            continue;
+        };
+        // Newest first, so a full page whose oldest is still in the window may not be all.
+        if messages.len() == usize::from(MESSAGES_PER_REQUEST)
+            && messages
+                .last()
+                .is_some_and(|message| message.timestamp >= since)
+        {
+            cut_short += 1;
+        }
+        found.extend(
+            messages
+                .iter()
+                .filter(|message| message.author.id == user_id && message.timestamp >= since)
+                .map(|message| (channel_id, message.id)),
+        );
+    }
+    (found, cut_short)
+}
+
+/// Bulk deletes `user_id`'s messages sent at or after `since`, found from tracked history
+/// and the latest messages in every text channel and active thread posted in since.
+/// Messages in `except` are left alone, since they've already been dealt with.
+pub async fn purge_recent_messages(
+    ctx: &Context,
+    guild_id: GuildId,
+    user_id: UserId,
+    since: Timestamp,
+    except: &[MessageId],
+) -> Purged {
+    let mut found = match get_storage(ctx)
+        .await
+        .recent_messages((guild_id, user_id), since.unix_timestamp())
+    {
+        Ok(tracked) => tracked,
+        Err(e) => {
+            error!("Couldn't load tracked messages for {user_id} due to {e}");
+            vec![]
+        }
+    };
+    let (fetched, cut_short) = fetch_recent(ctx, guild_id, user_id, since, &found).await;
+    found.extend(fetched);
+    let mut purged = Purged {
+        cut_short,
+        ..Default::default()
+    };
+    for (channel_id, message_ids) in by_channel(found, except) {
+        let message_ids: Vec<MessageId> = message_ids.into_iter().collect();
+        let mut removed = 0;
+        for chunk in message_ids.chunks(usize::from(MESSAGES_PER_REQUEST)) {
+            if channel_id.delete_messages(&ctx.http, chunk).await.is_ok() {
+                removed += chunk.len();
+                continue;
+            }
+            // One message already gone fails the whole batch, so try them one by one.
+            for message_id in chunk {
+                match channel_id.delete_message(&ctx.http, message_id).await {
+                    Ok(()) => removed += 1,
+                    Err(e) => info!("Couldn't purge {message_id} in {channel_id} due to {e}"),
+                }
+            }
+        }
+        if removed > 0 {
+            purged.messages += removed;
+            purged.channels += 1;
+        }
+    }
+    purged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn searches_tracked_channels_first() {
+        let tracked = [
+            (ChannelId::new(1), MessageId::new(10)),
+            (ChannelId::new(2), MessageId::new(20)),
+            (ChannelId::new(1), MessageId::new(11)),
+        ];
+        let since = Timestamp::from_unix_timestamp(1_700_000_000).unwrap();
+        let posted_at = |seconds: i64| {
+            let millis = (since.unix_timestamp() + seconds) * 1_000 - 1_420_070_400_000;
+            Some(MessageId::new((millis as u64) << 22))
+        };
+        let listed = [
+            (ChannelId::new(2), posted_at(60)),
+            (ChannelId::new(100), posted_at(60)),
+            (ChannelId::new(101), posted_at(-60)),
+            (ChannelId::new(102), None),
+            (ChannelId::new(103), posted_at(0)),
+        ];
+        let channels = channels_to_search(&tracked, listed, since);
+        assert_eq!(channels, [1, 2, 100, 103].map(ChannelId::new));
+    }
+
+    #[test]
+    fn groups_without_duplicates() {
+        let at = |channel: u64, message: u64| (ChannelId::new(channel), MessageId::new(message));
+        let grouped = by_channel(
+            [at(1, 10), at(2, 20), at(1, 11), at(1, 10), at(2, 21)],
+            &[MessageId::new(21)],
+        );
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(
+            grouped[&ChannelId::new(1)],
+            BTreeSet::from([MessageId::new(10), MessageId::new(11)])
+        );
+        assert_eq!(
+            grouped[&ChannelId::new(2)],
+            BTreeSet::from([MessageId::new(20)])
+        );
+    }
+}
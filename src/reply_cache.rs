@@ -0,0 +1,98 @@
+use anyhow::bail;
+use hashlink::LruCache;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many LLM replies to remember, set under `[llm.cache]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Replies kept before the least recently used is dropped. 0 turns the cache off.
+    pub capacity: usize,
+    pub ttl_minutes: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            capacity: 5_000,
+            ttl_minutes: 60,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.ttl_minutes == 0 {
+            bail!("`llm.cache.ttl_minutes` must be positive")
+        }
+        Ok(())
+    }
+}
+
+/// Replies that parsed, by request key, dropped once older than the TTL.
+#[derive(Debug)]
+pub struct ReplyCache {
+    ttl: Duration,
+    /// `None` when the cache is off.
+    entries: Option<Mutex<LruCache<String, (String, Instant)>>>,
+}
+
+impl ReplyCache {
+    pub fn new(config: &CacheConfig) -> ReplyCache {
+        ReplyCache {
+            ttl: Duration::from_secs(config.ttl_minutes * 60),
+            entries: (config.capacity > 0).then(|| Mutex::new(LruCache::new(config.capacity))),
+        }
+    }
+
+    pub fn get(&self, key: &str, now: Instant) -> Option<String> {
+        let mut entries = self.entries.as_ref()?.lock().unwrap();
+        match entries.get(key) {
+            Some((reply, stored_at)) if now.duration_since(*stored_at) < self.ttl => {
+                Some(reply.clone())
+            }
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: String, reply: String, now: Instant) {
+        if let Some(entries) = &self.entries {
+            entries.lock().unwrap().insert(key, (reply, now));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_stale_and_least_recent() {
+        let cache = ReplyCache::new(&CacheConfig {
+            capacity: 2,
+            ttl_minutes: 1,
+        });
+        let start = Instant::now();
+        cache.insert("a".to_string(), "1".to_string(), start);
+        cache.insert("b".to_string(), "2".to_string(), start);
+        assert_eq!(cache.get("a", start).as_deref(), Some("1"));
+        // "b" is now the least recently used.
+        cache.insert("c".to_string(), "3".to_string(), start);
+        assert!(cache.get("b", start).is_none());
+        assert_eq!(cache.get("c", start).as_deref(), Some("3"));
+        assert!(cache.get("a", start + Duration::from_secs(61)).is_none());
+
+        let off = ReplyCache::new(&CacheConfig {
+            capacity: 0,
+            ttl_minutes: 1,
+        });
+        off.insert("a".to_string(), "1".to_string(), start);
+        assert!(off.get("a", start).is_none());
+    }
+}
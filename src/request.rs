@@ -1,19 +1,28 @@
-use crate::consts::MODEL_USED;
+use crate::llm::{Llm, LlmTask};
+use crate::structured::{Field, FieldKind, StructuredReply};
 use crate::utilities;
 use anyhow::bail;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
 use serde::Deserialize;
+use serenity::all::GuildId;
 use tracing::info;
 
 static REQUEST_PROMPT: &str = include_str!("../prompts/request.txt");
 static VERIFY_PROMPT: &str = include_str!("../prompts/verify.txt");
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Debug)]
 struct VerifyReply {
     reason: String,
     answers_correctly: bool,
 }
 
+impl StructuredReply for VerifyReply {
+    const FIELDS: &'static [Field] = &[
+        Field::required("reason", FieldKind::String),
+        Field::required("answers_correctly", FieldKind::Bool),
+    ];
+}
+
 fn system_message_request() -> ChatCompletionMessage {
     ChatCompletionMessage {
         role: ChatCompletionMessageRole::System,
@@ -43,44 +52,46 @@ fn system_message_verify(question: String) -> anyhow::Result<ChatCompletionMessa
     })
 }
 
-async fn create_reply(message: String, context: Vec<String>) -> anyhow::Result<String> {
-    let chat_completion = ChatCompletion::builder(
-        MODEL_USED,
-        utilities::build_message(message, context, system_message_request(), 0, 1024),
-    )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
+async fn create_reply(
+    llm: &Llm,
+    guild_id: GuildId,
+    message: String,
+    context: Vec<String>,
+) -> anyhow::Result<String> {
+    let messages = utilities::build_message(message, context, system_message_request(), 0, 1024);
+    if let Some(content) = llm
+        .complete(LlmTask::Request, Some(guild_id), messages)
+        .await?
+    {
         Ok(content)
     } else {
         bail!("No reply from ChatGPT")
     }
 }
 
-async fn verify_request(request: String, reply: String) -> anyhow::Result<VerifyReply> {
-    let chat_completion = ChatCompletion::builder(
-        MODEL_USED,
-        utilities::build_message(
-            reply,
-            vec![],
-            system_message_verify(request.clone())?,
-            0,
-            1024,
-        ),
-    )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        info!("Generated Verification - {}", content.as_str());
-        Ok(serde_json::from_str(content.as_str())?)
-    } else {
-        bail!("No reply from ChatGPT")
-    }
+async fn verify_request(
+    llm: &Llm,
+    guild_id: GuildId,
+    request: String,
+    reply: String,
+) -> anyhow::Result<VerifyReply> {
+    let messages = utilities::build_message(
+        reply,
+        vec![],
+        system_message_verify(request.clone())?,
+        0,
+        1024,
+    );
+    let verification: VerifyReply = llm
+        .complete_json(LlmTask::Verify, Some(guild_id), messages)
+        .await?;
+    info!("Generated Verification - {:?}", verification);
+    Ok(verification)
 }
 
 pub(crate) async fn answer_request(
+    llm: &Llm,
+    guild_id: GuildId,
     request: String,
     context: Option<String>,
 ) -> anyhow::Result<Option<String>> {
@@ -92,9 +103,10 @@ pub(crate) async fn answer_request(
         request.as_str(),
         &context
     );
-    let unverified_reply = create_reply(request.clone(), vec![]).await?;
+    let unverified_reply = create_reply(llm, guild_id, request.clone(), vec![]).await?;
     info!("Generated unverified reply {}", unverified_reply.as_str(),);
-    let response_verification = verify_request(request, unverified_reply.clone()).await?;
+    let response_verification =
+        verify_request(llm, guild_id, request, unverified_reply.clone()).await?;
     if response_verification.answers_correctly {
         info!(
             "Verified reply due to {}",
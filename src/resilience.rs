@@ -0,0 +1,221 @@
+use anyhow::bail;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+
+/// How failed chat completions are retried, set under `[llm.retry]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Extra attempts after the first one fails.
+    pub max_retries: u32,
+    /// Wait before the first retry; each later retry waits twice as long.
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 5_000,
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.initial_backoff_ms > self.max_backoff_ms {
+            bail!("`llm.retry.initial_backoff_ms` must not be more than `max_backoff_ms`")
+        }
+        Ok(())
+    }
+
+    /// How long to wait before retry number `retry`, counting from 0.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff_ms
+            .saturating_mul(1 << retry.min(16))
+            .min(self.max_backoff_ms);
+        Duration::from_millis(backoff)
+    }
+}
+
+/// When to stop calling a failing model, set under `[llm.breaker]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BreakerConfig {
+    /// Calls in a row that must fail, after retries, to trip the breaker.
+    pub failures: u32,
+    /// How long calls fail straight away once tripped, before one is let through to
+    /// see whether the model is back.
+    pub cooldown_seconds: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        BreakerConfig {
+            failures: 5,
+            cooldown_seconds: 60,
+        }
+    }
+}
+
+impl BreakerConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.failures == 0 || self.cooldown_seconds == 0 {
+            bail!("`llm.breaker.failures` and `cooldown_seconds` must be positive")
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakerEvent {
+    /// `failures` calls in a row failed, the last with `error`.
+    Tripped {
+        failures: u32,
+        cooldown: Duration,
+        error: String,
+    },
+    /// A call succeeded after the breaker tripped.
+    Recovered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// The cooldown is over and one call is finding out whether the model is back.
+    HalfOpen,
+}
+
+/// Counts failed calls and, after too many in a row, fails calls straight away for a
+/// while so an outage doesn't make every message wait out its retries.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: Mutex<State>,
+    events: broadcast::Sender<BreakerEvent>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &BreakerConfig) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: config.failures,
+            cooldown: Duration::from_secs(config.cooldown_seconds),
+            state: Mutex::new(State::Closed { failures: 0 }),
+            events: broadcast::channel(16).0,
+        }
+    }
+
+    /// Hears about every trip and recovery.
+    pub fn subscribe(&self) -> broadcast::Receiver<BreakerEvent> {
+        self.events.subscribe()
+    }
+
+    /// `Err` with how long until a call is let through again, if calls should fail
+    /// straight away.
+    pub fn allow(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now >= until => {
+                *state = State::HalfOpen;
+                Ok(())
+            }
+            State::Open { until } => Err(until - now),
+            State::HalfOpen => Err(Duration::ZERO),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            let _ = self.events.send(BreakerEvent::Recovered);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self, now: Instant, error: &str) {
+        let mut state = self.state.lock().unwrap();
+        *state = match *state {
+            State::Closed { failures } if failures + 1 >= self.failure_threshold => {
+                let _ = self.events.send(BreakerEvent::Tripped {
+                    failures: failures + 1,
+                    cooldown: self.cooldown,
+                    error: error.to_string(),
+                });
+                State::Open {
+                    until: now + self.cooldown,
+                }
+            }
+            State::Closed { failures } => State::Closed {
+                failures: failures + 1,
+            },
+            // Still down; everyone already knows.
+            State::Open { .. } | State::HalfOpen => State::Open {
+                until: now + self.cooldown,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        let retry = RetryConfig::default();
+        assert_eq!(retry.backoff(0), Duration::from_millis(500));
+        assert_eq!(retry.backoff(2), Duration::from_millis(2_000));
+        assert_eq!(retry.backoff(30), Duration::from_millis(5_000));
+    }
+
+    #[test]
+    fn trips_and_recovers() {
+        let breaker = CircuitBreaker::new(&BreakerConfig {
+            failures: 2,
+            cooldown_seconds: 60,
+        });
+        let mut events = breaker.subscribe();
+        let start = Instant::now();
+        breaker.record_failure(start, "timed out");
+        assert_eq!(breaker.allow(start), Ok(()));
+        breaker.record_failure(start, "timed out");
+        assert_eq!(
+            events.try_recv().unwrap(),
+            BreakerEvent::Tripped {
+                failures: 2,
+                cooldown: Duration::from_secs(60),
+                error: "timed out".to_string(),
+            }
+        );
+        assert_eq!(
+            breaker.allow(start + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+
+        // One trial call after the cooldown; it fails, so wait again without a new notice.
+        let later = start + Duration::from_secs(61);
+        assert_eq!(breaker.allow(later), Ok(()));
+        assert!(breaker.allow(later).is_err());
+        breaker.record_failure(later, "timed out");
+        assert!(breaker.allow(later + Duration::from_secs(1)).is_err());
+        assert!(events.try_recv().is_err());
+
+        let much_later = later + Duration::from_secs(61);
+        assert_eq!(breaker.allow(much_later), Ok(()));
+        breaker.record_success();
+        assert_eq!(events.try_recv().unwrap(), BreakerEvent::Recovered);
+        assert_eq!(breaker.allow(much_later), Ok(()));
+    }
+}
@@ -0,0 +1,471 @@
+use crate::clean_messages::clean_message;
+use crate::config::GuildConfig;
+use crate::messaging;
+use crate::storage::{self, ActionKind, ModerationRecord, ReviewCase};
+use crate::user_info;
+use anyhow::{bail, Context as _};
+use chrono::Duration;
+use serde::Deserialize;
+use serenity::all::{
+    ButtonStyle, ComponentInteraction, Context, CreateActionRow, CreateButton, CreateEmbed,
+    CreateEmbedFooter, CreateInteractionResponse, CreateInteractionResponseMessage, CreateMessage,
+    Mentionable, Message, Permissions, Timestamp,
+};
+use tracing::{error, info};
+
+/// Custom IDs of review buttons start with this, followed by the case ID and decision.
+const CUSTOM_ID_PREFIX: &str = "review";
+
+/// Longest reason shown in an embed field, which Discord caps at 1024 characters.
+const MAX_REASON_CHARS: usize = 1_000;
+
+/// How held messages are dealt with, set under `[guilds.<guild id>.review]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReviewConfig {
+    /// Times the author out until a moderator decides, for at most this many hours.
+    /// 0 only hides the message.
+    pub quarantine_hours: i64,
+    /// How long the Timeout button times the author out for.
+    pub timeout_hours: i64,
+}
+
+impl Default for ReviewConfig {
+    fn default() -> Self {
+        ReviewConfig {
+            quarantine_hours: 0,
+            timeout_hours: 24,
+        }
+    }
+}
+
+impl ReviewConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        // Discord refuses timeouts longer than 28 days.
+        if !(0..=28 * 24).contains(&self.quarantine_hours)
+            || !(1..=28 * 24).contains(&self.timeout_hours)
+        {
+            bail!("`review.quarantine_hours` and `timeout_hours` must be at most 672 (28 days), and `timeout_hours` positive")
+        }
+        Ok(())
+    }
+}
+
+/// What a moderator decided about a held message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Not spam, so the message is put back.
+    Approve,
+    Delete,
+    Timeout,
+    Ban,
+}
+
+impl Decision {
+    pub const ALL: [Decision; 4] = [
+        Decision::Approve,
+        Decision::Delete,
+        Decision::Timeout,
+        Decision::Ban,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Decision::Approve => "approve",
+            Decision::Delete => "delete",
+            Decision::Timeout => "timeout",
+            Decision::Ban => "ban",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Decision> {
+        Decision::ALL
+            .into_iter()
+            .find(|decision| decision.as_str() == name)
+    }
+
+    fn button(&self, case_id: i64) -> CreateButton {
+        let (label, style) = match self {
+            Decision::Approve => ("Approve", ButtonStyle::Success),
+            Decision::Delete => ("Delete", ButtonStyle::Secondary),
+            Decision::Timeout => ("Timeout", ButtonStyle::Primary),
+            Decision::Ban => ("Ban", ButtonStyle::Danger),
+        };
+        CreateButton::new(custom_id(case_id, *self))
+            .label(label)
+            .style(style)
+    }
+
+    /// What was done, e.g. `Approved and restored`.
+    fn describe(&self) -> &'static str {
+        match self {
+            Decision::Approve => "Approved and restored",
+            Decision::Delete => "Deleted",
+            Decision::Timeout => "Deleted and timed out",
+            Decision::Ban => "Deleted and banned",
+        }
+    }
+}
+
+fn custom_id(case_id: i64, decision: Decision) -> String {
+    format!("{CUSTOM_ID_PREFIX}:{case_id}:{}", decision.as_str())
+}
+
+/// The case and decision behind a review button, or `None` for other components.
+pub fn parse_custom_id(custom_id: &str) -> Option<(i64, Decision)> {
+    let mut parts = custom_id.split(':');
+    if parts.next()? != CUSTOM_ID_PREFIX {
+        return None;
+    }
+    let case_id = parts.next()?.parse().ok()?;
+    let decision = Decision::from_name(parts.next()?)?;
+    parts.next().is_none().then_some((case_id, decision))
+}
+
+/// Roughly how long ago, e.g. `3 days`.
+fn describe_age(age: Duration) -> String {
+    let (count, unit) = if age.num_days() > 0 {
+        (age.num_days(), "day")
+    } else if age.num_hours() > 0 {
+        (age.num_hours(), "hour")
+    } else {
+        (age.num_minutes().max(0), "minute")
+    };
+    if count == 1 {
+        format!("1 {unit}")
+    } else {
+        format!("{count} {unit}s")
+    }
+}
+
+fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn case_embed(
+    case: &ReviewCase,
+    account_age: Duration,
+    member_age: Option<Duration>,
+) -> CreateEmbed {
+    CreateEmbed::new()
+        .title("Held for review")
+        .description(clean_message(case.content.as_str()))
+        .field(
+            "Author",
+            format!("{} ({})", case.user_id.mention(), case.author_name),
+            true,
+        )
+        .field("Channel", case.channel_id.mention().to_string(), true)
+        .field("Account age", describe_age(account_age), true)
+        .field(
+            "Member for",
+            member_age.map_or("unknown".to_string(), describe_age),
+            true,
+        )
+        .field(
+            "Reason",
+            truncate(case.reason.as_str(), MAX_REASON_CHARS),
+            false,
+        )
+        .footer(CreateEmbedFooter::new(format!("Case {}", case.id)))
+        .colour(0xE6_7E_22)
+}
+
+/// Posts `message` to the bot channel for a moderator to approve, delete, time out or ban,
+/// then hides it, quarantining its author if the guild asks for it. Nothing is hidden
+/// unless the case was posted, so a message can't be lost without a way to bring it back.
+pub async fn hold_for_review(
+    ctx: &Context,
+    guild_config: &GuildConfig,
+    message: &Message,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let guild_id = message
+        .guild_id
+        .context("Only guild messages can be held")?;
+    let mut case = ReviewCase {
+        id: 0,
+        guild_id,
+        channel_id: message.channel_id,
+        message_id: message.id,
+        user_id: message.author.id,
+        author_name: message.author.name.clone(),
+        content: message.content.clone(),
+        reason: reason.to_string(),
+        quarantined: false,
+        created_at: Timestamp::now().unix_timestamp(),
+        mention_everyone: message.mention_everyone,
+    };
+    let storage = storage::get_storage(ctx).await;
+    case.id = storage.open_review(&case)?;
+    let now = case.created_at;
+    let account_age = Duration::seconds(now - message.author.id.created_at().unix_timestamp());
+    let member_age = user_info::get_user_join_date(ctx, guild_id, message.author.id)
+        .await
+        .map(|joined_at| Duration::seconds(now - joined_at));
+    guild_config
+        .bot_channel
+        .send_message(
+            &ctx.http,
+            CreateMessage::new()
+                .embed(case_embed(&case, account_age, member_age))
+                .components(vec![CreateActionRow::Buttons(
+                    Decision::ALL
+                        .iter()
+                        .map(|decision| decision.button(case.id))
+                        .collect(),
+                )]),
+        )
+        .await
+        .context("Couldn't post the case, so the message was left up")?;
+    ctx.http
+        .delete_message(message.channel_id, message.id, Some("Held for review"))
+        .await?;
+    let quarantine = guild_config.review.quarantine_hours;
+    if quarantine > 0 {
+        let until = Timestamp::from_unix_timestamp(
+            Timestamp::now().unix_timestamp() + Duration::hours(quarantine).num_seconds(),
+        )
+        .unwrap();
+        match messaging::timeout_user(ctx, &guild_id, &message.author.id, until).await {
+            Ok(_) => storage.mark_quarantined(case.id)?,
+            Err(e) => error!("Failed to quarantine {} due to {e}", message.author.id),
+        }
+    }
+    Ok(())
+}
+
+/// Whether whoever pressed a button may moderate messages.
+pub(crate) fn is_moderator(component: &ComponentInteraction) -> bool {
+    component
+        .member
+        .as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.contains(Permissions::MANAGE_MESSAGES))
+}
+
+pub(crate) async fn reply_privately(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    content: String,
+) -> serenity::Result<()> {
+    component
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(content)
+                    .ephemeral(true),
+            ),
+        )
+        .await
+}
+
+async fn apply(
+    ctx: &Context,
+    guild_config: &GuildConfig,
+    case: &ReviewCase,
+    decision: Decision,
+    moderator: &str,
+) -> anyhow::Result<()> {
+    match decision {
+        Decision::Approve => {
+            messaging::repost_restored(
+                ctx,
+                case.channel_id,
+                case.user_id,
+                case.author_name.as_str(),
+                case.content.as_str(),
+            )
+            .await?;
+            if case.quarantined {
+                messaging::lift_timeout(ctx, case.guild_id, case.user_id).await?;
+            }
+        }
+        Decision::Delete if case.quarantined => {
+            messaging::lift_timeout(ctx, case.guild_id, case.user_id).await?;
+        }
+        Decision::Delete => {}
+        Decision::Timeout => {
+            messaging::timeout_user(
+                ctx,
+                &case.guild_id,
+                &case.user_id,
+                Timestamp::from_unix_timestamp(
+                    Timestamp::now().unix_timestamp()
+                        + Duration::hours(guild_config.review.timeout_hours).num_seconds(),
+                )
+                .unwrap(),
+            )
+            .await?;
+        }
+        Decision::Ban => {
+            messaging::ban_user(
+                ctx,
+                &case.guild_id,
+                &case.user_id,
+                1,
+                format!("Held message confirmed as spam by {moderator}").as_str(),
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Carries out a moderator's click on a review button.
+pub async fn handle_button(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    case_id: i64,
+    decision: Decision,
+) -> anyhow::Result<()> {
+    let Some(guild_config) = crate::config::get_guild_config(ctx, component.guild_id).await else {
+        bail!("Review button pressed outside a configured guild")
+    };
+    if !is_moderator(component) {
+        reply_privately(
+            ctx,
+            component,
+            "Only moderators can review held messages.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let storage = storage::get_storage(ctx).await;
+    let Some(case) = storage.review_case(case_id)? else {
+        bail!("No review case {case_id}")
+    };
+    let moderator = &component.user;
+    let now = Timestamp::now().unix_timestamp();
+    // Claimed before acting, so two moderators clicking at once can't both act.
+    if !storage.decide_review(case_id, decision, moderator.id, now)? {
+        reply_privately(
+            ctx,
+            component,
+            "Another moderator already decided this one.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    if let Err(e) = apply(ctx, &guild_config, &case, decision, moderator.name.as_str()).await {
+        storage.reopen_review(case_id)?;
+        reply_privately(
+            ctx,
+            component,
+            format!("Couldn't {} this one: {e}", decision.as_str()),
+        )
+        .await?;
+        return Ok(());
+    }
+    info!(
+        "Case {case_id} - {} by {}",
+        decision.describe(),
+        moderator.name
+    );
+    let action = match decision {
+        Decision::Approve => ActionKind::Approve,
+        Decision::Delete => ActionKind::Delete,
+        Decision::Timeout => ActionKind::DeleteAndTimeout,
+        Decision::Ban => ActionKind::Ban,
+    };
+    storage::record_action(
+        ctx,
+        ModerationRecord {
+            guild_id: case.guild_id,
+            user_id: case.user_id,
+            channel_id: case.channel_id,
+            message_id: Some(case.message_id),
+            action,
+            reason: Some(format!(
+                "Reviewed by {} ({}): {}",
+                moderator.name, moderator.id, case.reason
+            )),
+            content: case.content.clone(),
+            created_at: now,
+            mention_everyone: case.mention_everyone,
+        },
+    )
+    .await;
+    close_with(
+        ctx,
+        component,
+        "Decision",
+        format!("{} by {}", decision.describe(), moderator.mention()),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Adds what was done to the log entry a button was on, and takes its buttons away.
+pub(crate) async fn close_with(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    name: &str,
+    value: String,
+) -> serenity::Result<()> {
+    close_with_buttons(ctx, component, name, value, vec![]).await
+}
+
+/// Like `close_with`, but leaves `buttons` in place of the ones there were.
+pub(crate) async fn close_with_buttons(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    name: &str,
+    value: String,
+    buttons: Vec<CreateButton>,
+) -> serenity::Result<()> {
+    let components = if buttons.is_empty() {
+        vec![]
+    } else {
+        vec![CreateActionRow::Buttons(buttons)]
+    };
+    let embed = component
+        .message
+        .embeds
+        .first()
+        .cloned()
+        .map_or_else(CreateEmbed::new, CreateEmbed::from)
+        .field(name, value, false);
+    component
+        .create_response(
+            &ctx.http,
+            CreateInteractionResponse::UpdateMessage(
+                CreateInteractionResponseMessage::new()
+                    .embed(embed)
+                    .components(components),
+            ),
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_custom_ids() {
+        for decision in Decision::ALL {
+            assert_eq!(
+                parse_custom_id(custom_id(42, decision).as_str()),
+                Some((42, decision))
+            );
+        }
+        assert_eq!(parse_custom_id("review:42:kick"), None);
+        assert_eq!(parse_custom_id("review:x:ban"), None);
+        assert_eq!(parse_custom_id("undo:42:ban"), None);
+        assert_eq!(parse_custom_id("review:42:ban:1"), None);
+    }
+
+    #[test]
+    fn describes_ages() {
+        assert_eq!(describe_age(Duration::minutes(1)), "1 minute");
+        assert_eq!(describe_age(Duration::minutes(90)), "1 hour");
+        assert_eq!(describe_age(Duration::days(400)), "400 days");
+        assert_eq!(truncate("abcdef", 3), "abc...");
+        assert_eq!(truncate("abc", 3), "abc");
+    }
+}
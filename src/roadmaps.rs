@@ -1,8 +1,10 @@
-use crate::consts::MODEL_USED;
+use crate::llm::{Llm, LlmError, LlmTask};
+use crate::structured::{Field, FieldKind, StructuredReply};
 use anyhow::bail;
 use lazy_static::lazy_static;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
 use serde::Deserialize;
+use serenity::all::GuildId;
 use tracing::info;
 lazy_static! {
     static ref ROADMAP_CONFIG: RoadmapConfig = RoadmapConfig::default();
@@ -34,6 +36,13 @@ pub(crate) struct RequestingRoadmap {
     pub is_roadmap: bool,
 }
 
+impl StructuredReply for RequestingRoadmap {
+    const FIELDS: &'static [Field] = &[
+        Field::required("reason", FieldKind::String),
+        Field::required("is_roadmap", FieldKind::Bool),
+    ];
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct RoadmapProvided {
     pub roadmap: String,
@@ -92,49 +101,47 @@ fn build_message(
 }
 
 pub(crate) async fn is_message_roadmap_request(
+    llm: &Llm,
+    guild_id: GuildId,
     message: String,
     context: Vec<String>,
-) -> anyhow::Result<RequestingRoadmap> {
-    let chat_completion = ChatCompletion::builder(
-        MODEL_USED,
-        build_message(message.clone(), context, system_message_detection()),
-    )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        let roadmap_request: RequestingRoadmap = serde_json::from_str(content.as_str())?;
-        if roadmap_request.is_roadmap {
-            info!(
-                "Generating roadmap for request {} due to {}",
-                message.as_str(),
-                roadmap_request.reason.as_str()
-            );
-        } else {
-            info!(
-                "Ignoring roadmap request {} due to {}",
-                message.as_str(),
-                roadmap_request.reason.as_str()
-            );
-        }
-        Ok(roadmap_request)
+) -> Result<RequestingRoadmap, LlmError> {
+    let messages = build_message(message.clone(), context, system_message_detection());
+    let roadmap_request: RequestingRoadmap = llm
+        .complete_json_cached(
+            LlmTask::RoadmapDetect,
+            Some(guild_id),
+            message.as_str(),
+            messages,
+        )
+        .await?;
+    if roadmap_request.is_roadmap {
+        info!(
+            "Generating roadmap for request {} due to {}",
+            message.as_str(),
+            roadmap_request.reason.as_str()
+        );
     } else {
-        bail!("No reply from ChatGPT")
+        info!(
+            "Ignoring roadmap request {} due to {}",
+            message.as_str(),
+            roadmap_request.reason.as_str()
+        );
     }
+    Ok(roadmap_request)
 }
 
 pub(crate) async fn create_roadmap(
+    llm: &Llm,
+    guild_id: GuildId,
     message: String,
     context: Vec<String>,
 ) -> anyhow::Result<RoadmapProvided> {
-    let chat_completion = ChatCompletion::builder(
-        MODEL_USED,
-        build_message(message, context, system_message_creation()),
-    )
-    .create()
-    .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
+    let messages = build_message(message, context, system_message_creation());
+    if let Some(content) = llm
+        .complete(LlmTask::RoadmapCreate, Some(guild_id), messages)
+        .await?
+    {
         info!("Generated Roadmap - {}", content.as_str());
         Ok(RoadmapProvided { roadmap: content })
     } else {
@@ -1,7 +1,11 @@
-use crate::consts::MODEL_USED;
-use anyhow::bail;
+use crate::classifier::{ClassifierInput, SpamCategory};
+use crate::few_shot;
+use crate::llm::{Llm, LlmError, LlmTask};
+use crate::structured::{Field, FieldKind, StructuredReply};
+use crate::trust::TrustTier;
+use chrono::Duration;
 use lazy_static::lazy_static;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use openai::chat::{ChatCompletionMessage, ChatCompletionMessageRole};
 use serde::Deserialize;
 
 lazy_static! {
@@ -25,16 +29,34 @@ impl Default for SpamConfig {
     }
 }
 
+fn default_confidence() -> f32 {
+    1.0
+}
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct IsSpamResult {
     pub reason: String,
     pub is_spam: bool,
+    /// Older replies without a confidence are taken at their word.
+    #[serde(default = "default_confidence")]
+    pub confidence: f32,
+    #[serde(default)]
+    pub category: Option<SpamCategory>,
+}
+
+impl StructuredReply for IsSpamResult {
+    const FIELDS: &'static [Field] = &[
+        Field::required("reason", FieldKind::String),
+        Field::required("is_spam", FieldKind::Bool),
+        Field::optional("confidence", FieldKind::Number),
+        Field::optional("category", FieldKind::String),
+    ];
 }
 
-fn system_message() -> ChatCompletionMessage {
+fn system_message(content: String) -> ChatCompletionMessage {
     ChatCompletionMessage {
         role: ChatCompletionMessageRole::System,
-        content: Some(SPAM_PROMPT.to_string()),
+        content: Some(content),
         name: None,
         tool_calls: None,
         tool_call_id: None,
@@ -53,39 +75,95 @@ fn user_message(message: String) -> ChatCompletionMessage {
     }
 }
 
-fn build_message(message: String, context: Vec<String>) -> Vec<ChatCompletionMessage> {
-    let mut messages: Vec<ChatCompletionMessage> = vec![system_message()];
-    let mut message_length: usize = message.len();
-    let mut message_buffer: String = message;
-    for contextual_message in context.into_iter().take(SPAM_CONFIG.context_length) {
+fn describe_age(age: Duration) -> String {
+    if age.num_days() > 0 {
+        format!("{} days", age.num_days())
+    } else if age.num_hours() > 0 {
+        format!("{} hours", age.num_hours())
+    } else {
+        format!("{} minutes", age.num_minutes())
+    }
+}
+
+fn describe_author(input: &ClassifierInput) -> Option<String> {
+    let facts: Vec<String> = [
+        input
+            .account_age
+            .map(|age| format!("Account created {} ago.", describe_age(age))),
+        input
+            .member_age
+            .map(|age| format!("Joined the server {} ago.", describe_age(age))),
+        input
+            .message_count
+            .map(|count| format!("Has posted {count} messages in the server.")),
+        input.trust.map(|tier| match tier {
+            TrustTier::New => {
+                "Counts as a new member: a young account, a recent join or not yet through screening."
+                    .to_string()
+            }
+            TrustTier::Member => "Counts as an established member.".to_string(),
+            TrustTier::Trusted => "Counts as a trusted member.".to_string(),
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    (!facts.is_empty()).then(|| facts.join(" "))
+}
+
+fn build_message(input: &ClassifierInput) -> Vec<ChatCompletionMessage> {
+    let mut messages: Vec<ChatCompletionMessage> = vec![system_message(SPAM_PROMPT.to_string())];
+    // A message of their own, which keys the reply cache along with the main prompt.
+    if !input.examples.is_empty() {
+        messages.push(system_message(few_shot::render(&input.examples)));
+    }
+    let mut sections: Vec<String> = vec![];
+    if let Some(author) = describe_author(input) {
+        sections.push(format!("# Author\n{author}"));
+    }
+    let mut message_length: usize = input.content.len();
+    let mut earlier_messages: Vec<String> = vec![];
+    // Keep the most recent context if it won't all fit.
+    for contextual_message in input.context.iter().rev().take(SPAM_CONFIG.context_length) {
         if message_length + contextual_message.len() > SPAM_CONFIG.message_limit_chars {
             break;
         }
         message_length += contextual_message.len();
-        message_buffer.insert_str(0, contextual_message.as_str());
+        earlier_messages.insert(0, format!("- {contextual_message}"));
+    }
+    if !earlier_messages.is_empty() {
+        sections.push(format!(
+            "# Earlier messages\n{}",
+            earlier_messages.join("\n")
+        ));
     }
-    messages.push(user_message(message_buffer));
+    sections.push(format!("# Message\n{}", input.content));
+    messages.push(user_message(sections.join("\n")));
     messages
 }
 
 pub(crate) async fn classify_message_spam(
-    message: String,
-    context: Vec<String>,
-) -> anyhow::Result<IsSpamResult> {
-    let chat_completion = ChatCompletion::builder(MODEL_USED, build_message(message, context))
-        .create()
-        .await?;
-    let returned_message = chat_completion.choices.first().unwrap().message.clone();
-    if let Some(content) = returned_message.content {
-        Ok(serde_json::from_str(content.as_str())?)
-    } else {
-        bail!("No reply from ChatGPT")
-    }
+    llm: &Llm,
+    input: &ClassifierInput,
+) -> Result<IsSpamResult, LlmError> {
+    // Spam waves repeat the same text from many accounts, so the verdict is reused
+    // whatever the author's age or earlier messages.
+    llm.complete_json_cached(
+        LlmTask::Spam,
+        input.guild_id,
+        input.content.as_str(),
+        build_message(input),
+    )
+    .await
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cassette::Cassette;
+    use crate::few_shot::Example;
+    use crate::llm::LlmConfig;
+    use std::sync::Arc;
 
     #[test]
     fn parse_json() {
@@ -94,4 +172,84 @@ mod tests {
                 .unwrap();
         dbg!(result);
     }
+
+    #[test]
+    fn emit_prompt_with_context() {
+        let input = ClassifierInput {
+            content: "https://discord.gg/blueberry".to_string(),
+            context: vec!["hey, anyone want free nitro?".to_string()],
+            account_age: Some(Duration::hours(3)),
+            member_age: Some(Duration::minutes(10)),
+            message_count: Some(4),
+            trust: Some(TrustTier::New),
+            guild_id: None,
+            examples: vec![],
+        };
+        let messages = build_message(&input);
+        let prompt = messages.last().unwrap().content.as_ref().unwrap();
+        assert_eq!(
+            prompt,
+            "# Author\nAccount created 3 hours ago. Joined the server 10 minutes ago. \
+             Has posted 4 messages in the server. Counts as a new member: a young account, \
+             a recent join or not yet through screening.\n\
+             # Earlier messages\n- hey, anyone want free nitro?\n\
+             # Message\nhttps://discord.gg/blueberry"
+        );
+    }
+
+    #[test]
+    fn adds_labelled_examples() {
+        let mut input = ClassifierInput {
+            content: "free nitro".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(build_message(&input).len(), 2);
+        input.examples = vec![Example {
+            content: "free nitro at dlscord-gift.com".to_string(),
+            is_spam: true,
+        }];
+        let messages = build_message(&input);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].content.as_deref(), Some(SPAM_PROMPT));
+        assert!(messages[1]
+            .content
+            .as_ref()
+            .unwrap()
+            .ends_with("Moderators' verdict: spam."));
+    }
+
+    #[tokio::test]
+    async fn replays_recorded_classification() {
+        let llm = LlmConfig::default()
+            .build_with_client("gpt-4o-mini", Arc::new(Cassette::fixtures()))
+            .unwrap();
+        let input = ClassifierInput {
+            content: "FREE NITRO for the first 100 people: https://dlscord-gift.com/claim"
+                .to_string(),
+            context: vec!["hey everyone".to_string()],
+            account_age: Some(Duration::hours(5)),
+            member_age: Some(Duration::minutes(3)),
+            guild_id: None,
+            ..Default::default()
+        };
+        let result = classify_message_spam(&llm, &input).await.unwrap();
+        assert!(result.is_spam);
+        assert_eq!(result.category, Some(SpamCategory::Phishing));
+    }
+
+    #[test]
+    fn parse_graded_json() {
+        let result: IsSpamResult = serde_json::from_str(
+            "{\"reason\": \"Lure\", \"is_spam\": true, \"confidence\": 0.85, \"category\": \"phishing\"}",
+        )
+        .unwrap();
+        assert_eq!(result.category, Some(SpamCategory::Phishing));
+        assert_eq!(result.confidence, 0.85);
+
+        let result: IsSpamResult = serde_json::from_str(
+            "{\"reason\": \"Fine\", \"is_spam\": false, \"confidence\": 0.9, \"category\": \"none\"}",
+        )
+        .unwrap();
+        assert_eq!(result.category, Some(SpamCategory::Other));
+    }
 }
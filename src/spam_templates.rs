@@ -0,0 +1,134 @@
+use crate::classifier::Verdict;
+use crate::fingerprint::{distance, normalise, simhash};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Templates kept per guild before the oldest is dropped.
+const MAX_TEMPLATES: usize = 1_000;
+
+/// Shorter messages only match a template exactly, since a few bits say little about
+/// a handful of words.
+const MIN_NEAR_MATCH_CHARS: usize = 32;
+
+#[derive(Debug)]
+struct Template {
+    fingerprint: u64,
+    normalised: String,
+    verdict: Verdict,
+    added_at: Instant,
+}
+
+/// Messages the bot has removed as spam, so slightly altered copies are caught without
+/// asking the classifier again.
+#[derive(Debug)]
+pub struct SpamTemplates {
+    /// Fingerprints further apart than this many bits don't match. 0 only matches
+    /// identical normalised text.
+    max_distance: u32,
+    ttl: Duration,
+    templates: Mutex<VecDeque<Template>>,
+}
+
+impl SpamTemplates {
+    pub fn new(max_distance: u32, ttl: Duration) -> SpamTemplates {
+        SpamTemplates {
+            max_distance,
+            ttl,
+            templates: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// The verdict on the closest template `content` is a copy of, if any.
+    pub fn find(&self, content: &str, now: Instant) -> Option<Verdict> {
+        let normalised = normalise(content);
+        if normalised.is_empty() {
+            return None;
+        }
+        let near = self.max_distance > 0 && normalised.chars().count() >= MIN_NEAR_MATCH_CHARS;
+        let fingerprint = simhash(&normalised);
+        let mut templates = self.templates.lock().unwrap();
+        templates.retain(|template| now.duration_since(template.added_at) < self.ttl);
+        templates
+            .iter()
+            .filter_map(|template| {
+                if template.normalised == normalised {
+                    Some((0, template))
+                } else if near {
+                    let bits = distance(template.fingerprint, fingerprint);
+                    (bits <= self.max_distance).then_some((bits, template))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(|(bits, _)| *bits)
+            .map(|(_, template)| template.verdict.clone())
+    }
+
+    pub fn add(&self, content: &str, verdict: &Verdict, now: Instant) {
+        let normalised = normalise(content);
+        if normalised.is_empty() {
+            return;
+        }
+        let mut templates = self.templates.lock().unwrap();
+        templates.retain(|template| template.normalised != normalised);
+        if templates.len() >= MAX_TEMPLATES {
+            templates.pop_front();
+        }
+        templates.push_back(Template {
+            fingerprint: simhash(&normalised),
+            normalised,
+            verdict: verdict.clone(),
+            added_at: now,
+        });
+    }
+
+    /// Drops the template for `content`, once a moderator says it wasn't spam after all.
+    pub fn remove(&self, content: &str) {
+        let normalised = normalise(content);
+        self.templates
+            .lock()
+            .unwrap()
+            .retain(|template| template.normalised != normalised);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::classifier::SpamCategory;
+
+    #[test]
+    fn matches_variants_of_removed_spam() {
+        let templates = SpamTemplates::new(10, Duration::from_secs(3_600));
+        let verdict = Verdict {
+            is_spam: true,
+            confidence: 0.95,
+            category: Some(SpamCategory::Phishing),
+            reason: "fake nitro".to_string(),
+        };
+        let start = Instant::now();
+        templates.add(
+            "Hey! I'm giving away my Discord Nitro, claim it here before it's gone: https://dlscord-gift.com/claim",
+            &verdict,
+            start,
+        );
+        templates.add("free nitro", &verdict, start);
+
+        let variant = "Hey!! I'm giving away my Discord Nitro, claim it here before its gone: https://dlscord-gift.net/claim";
+        assert_eq!(templates.find(variant, start), Some(verdict.clone()));
+        assert_eq!(templates.find("FREE   nitro", start), Some(verdict));
+        assert!(templates.find("free nitro?", start).is_none());
+        assert!(templates
+            .find(
+                "Does anyone know why cargo check is slower than cargo build on this workspace?",
+                start
+            )
+            .is_none());
+        assert!(templates
+            .find(variant, start + Duration::from_secs(3_601))
+            .is_none());
+        templates.remove("free nitro");
+        assert!(templates.find("free nitro", start).is_none());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::fingerprint::{distance, normalise, simhash};
+use anyhow::bail;
+use serde::Deserialize;
+use serenity::all::{ChannelId, MessageId, UserId};
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+/// What happens to everyone taking part in a spam wave.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WaveAction {
+    Timeout,
+    Ban,
+}
+
+/// When the same message turning up repeatedly counts as a spam wave, set under
+/// `[guilds.<guild id>.spam_waves]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpamWaves {
+    /// How far back copies are looked for.
+    pub window_seconds: i64,
+    /// Channels one member must post the same message in.
+    pub channels: usize,
+    /// New members who must post the same message.
+    pub new_users: usize,
+    /// Shorter messages never count, so greetings don't start a wave.
+    pub min_chars: usize,
+    pub action: WaveAction,
+    pub timeout_hours: i64,
+}
+
+impl Default for SpamWaves {
+    fn default() -> Self {
+        SpamWaves {
+            window_seconds: 60,
+            channels: 3,
+            new_users: 4,
+            min_chars: 20,
+            action: WaveAction::Timeout,
+            timeout_hours: 72,
+        }
+    }
+}
+
+impl SpamWaves {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.window_seconds <= 0 {
+            bail!("`spam_waves.window_seconds` must be positive")
+        }
+        if self.channels < 2 || self.new_users < 2 {
+            bail!("`spam_waves.channels` and `new_users` must be at least 2")
+        }
+        // Discord refuses timeouts longer than 28 days.
+        if !(1..=28 * 24).contains(&self.timeout_hours) {
+            bail!("`spam_waves.timeout_hours` must be between 1 and 672 (28 days)")
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaveKind {
+    /// One member posted the message in several channels.
+    CrossChannel,
+    /// Several new members posted the message.
+    NewUsers,
+}
+
+/// A message the detector has been shown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Post {
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    /// Whether the author only joined recently.
+    pub new_user: bool,
+    /// Trusted members and moderators can post an announcement everywhere.
+    pub trusted: bool,
+    /// Unix timestamp the message was sent.
+    pub sent_at: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Wave {
+    pub kind: WaveKind,
+    /// Every copy still up, including the message that completed the wave.
+    pub copies: Vec<Post>,
+    /// Members not yet dealt with for this wave.
+    pub users: Vec<UserId>,
+    /// Distinct channels the copies were posted in.
+    pub channels: usize,
+}
+
+#[derive(Debug)]
+struct Seen {
+    post: Post,
+    normalised: String,
+    fingerprint: u64,
+    /// Set once the post is part of a wave that has been dealt with.
+    wave: Option<WaveKind>,
+}
+
+/// Recent messages across a guild, to spot the same text turning up in many channels
+/// or from many new members within seconds.
+#[derive(Debug)]
+pub struct WaveDetector {
+    config: SpamWaves,
+    /// Fingerprints further apart than this many bits aren't copies.
+    max_distance: u32,
+    seen: Mutex<VecDeque<Seen>>,
+}
+
+impl WaveDetector {
+    pub fn new(config: SpamWaves, max_distance: u32) -> WaveDetector {
+        WaveDetector {
+            config,
+            max_distance,
+            seen: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn config(&self) -> &SpamWaves {
+        &self.config
+    }
+
+    /// Adds `post` to the window, and returns the wave it completes or continues.
+    pub fn observe(&self, post: Post, content: &str) -> Option<Wave> {
+        let normalised = normalise(content);
+        if normalised.chars().count() < self.config.min_chars {
+            return None;
+        }
+        let fingerprint = simhash(&normalised);
+        let mut seen = self.seen.lock().unwrap();
+        let oldest = post.sent_at - self.config.window_seconds;
+        while seen
+            .front()
+            .is_some_and(|entry| entry.post.sent_at < oldest)
+        {
+            seen.pop_front();
+        }
+        seen.push_back(Seen {
+            post,
+            normalised,
+            fingerprint,
+            wave: None,
+        });
+        let latest = seen.len() - 1;
+        let copies: Vec<usize> = (0..seen.len())
+            .filter(|index| {
+                *index == latest
+                    || seen[*index].normalised == seen[latest].normalised
+                    || (self.max_distance > 0
+                        && distance(seen[*index].fingerprint, fingerprint) <= self.max_distance)
+            })
+            .collect();
+
+        // A wave that was already dealt with catches stragglers straight away.
+        let continued = copies.iter().find_map(|index| {
+            let kind = seen[*index].wave?;
+            let joins = seen[*index].post.user_id == post.user_id
+                || (kind == WaveKind::NewUsers && post.new_user);
+            joins.then_some(kind)
+        });
+        if let Some(kind) = continued {
+            let known = copies.iter().any(|index| {
+                seen[*index].wave.is_some() && seen[*index].post.user_id == post.user_id
+            });
+            seen[latest].wave = Some(kind);
+            return Some(Wave {
+                kind,
+                copies: vec![post],
+                users: if known { vec![] } else { vec![post.user_id] },
+                channels: 1,
+            });
+        }
+
+        let own: Vec<usize> = copies
+            .iter()
+            .copied()
+            .filter(|index| seen[*index].post.user_id == post.user_id)
+            .collect();
+        let by_new_users: Vec<usize> = copies
+            .iter()
+            .copied()
+            .filter(|index| seen[*index].post.new_user)
+            .collect();
+        let (kind, members) = if !post.trusted
+            && distinct(&seen, &own, |post| post.channel_id) >= self.config.channels
+        {
+            (WaveKind::CrossChannel, own)
+        } else if post.new_user
+            && distinct(&seen, &by_new_users, |post| post.user_id) >= self.config.new_users
+        {
+            (WaveKind::NewUsers, by_new_users)
+        } else {
+            return None;
+        };
+        let mut users = vec![];
+        for index in &members {
+            seen[*index].wave = Some(kind);
+            if !users.contains(&seen[*index].post.user_id) {
+                users.push(seen[*index].post.user_id);
+            }
+        }
+        Some(Wave {
+            kind,
+            copies: members.iter().map(|index| seen[*index].post).collect(),
+            users,
+            channels: distinct(&seen, &members, |post| post.channel_id),
+        })
+    }
+}
+
+fn distinct<T: Eq + std::hash::Hash>(
+    seen: &VecDeque<Seen>,
+    indexes: &[usize],
+    key: impl Fn(&Post) -> T,
+) -> usize {
+    indexes
+        .iter()
+        .map(|index| key(&seen[*index].post))
+        .collect::<HashSet<T>>()
+        .len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPAM: &str = "Free Discord Nitro for everyone, claim it at https://dlscord-gift.com";
+
+    fn post(user: u64, channel: u64, message: u64, new_user: bool, sent_at: i64) -> Post {
+        Post {
+            user_id: UserId::new(user),
+            channel_id: ChannelId::new(channel),
+            message_id: MessageId::new(message),
+            new_user,
+            trusted: false,
+            sent_at,
+        }
+    }
+
+    #[test]
+    fn catches_one_member_in_many_channels() {
+        let detector = WaveDetector::new(SpamWaves::default(), 10);
+        assert!(detector.observe(post(1, 10, 100, false, 0), SPAM).is_none());
+        // Same channel again, and a short message elsewhere, don't count.
+        assert!(detector
+            .observe(post(1, 10, 101, false, 30), SPAM)
+            .is_none());
+        assert!(detector
+            .observe(post(1, 11, 102, false, 31), "ok")
+            .is_none());
+        assert!(detector
+            .observe(post(1, 11, 103, false, 40), SPAM)
+            .is_none());
+        // The first copy has left the window by the time the third channel is hit.
+        let wave = detector
+            .observe(post(1, 12, 104, false, 62), &SPAM.to_uppercase())
+            .unwrap();
+        assert_eq!(wave.kind, WaveKind::CrossChannel);
+        assert_eq!(wave.users, vec![UserId::new(1)]);
+        assert_eq!(wave.channels, 3);
+        let copies: Vec<u64> = wave
+            .copies
+            .iter()
+            .map(|copy| copy.message_id.get())
+            .collect();
+        assert_eq!(copies, vec![101, 103, 104]);
+
+        // Later copies are caught alone, without dealing with the member again.
+        let wave = detector.observe(post(1, 13, 105, false, 63), SPAM).unwrap();
+        assert_eq!(wave.copies.len(), 1);
+        assert!(wave.users.is_empty());
+        assert!(detector
+            .observe(post(2, 13, 106, false, 63), SPAM)
+            .is_none());
+
+        // Trusted members can post an announcement in every channel.
+        let trusted = |channel, message| Post {
+            trusted: true,
+            ..post(3, channel, message, false, 70)
+        };
+        let announcement = "Office hours move to Thursday this week, see #events";
+        for channel in 10..15 {
+            assert!(detector
+                .observe(trusted(channel, 200 + channel), announcement)
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn catches_many_new_members_at_once() {
+        let detector = WaveDetector::new(SpamWaves::default(), 10);
+        assert!(detector.observe(post(1, 10, 100, true, 0), SPAM).is_none());
+        assert!(detector.observe(post(2, 10, 101, true, 5), SPAM).is_none());
+        // Established members don't add to a wave of new ones.
+        assert!(detector.observe(post(3, 10, 102, false, 6), SPAM).is_none());
+        assert!(detector.observe(post(4, 11, 103, true, 7), SPAM).is_none());
+        let wave = detector.observe(post(5, 10, 104, true, 8), SPAM).unwrap();
+        assert_eq!(wave.kind, WaveKind::NewUsers);
+        assert_eq!(wave.users.len(), 4);
+        assert_eq!(wave.copies.len(), 4);
+        assert_eq!(wave.channels, 2);
+
+        let wave = detector.observe(post(6, 12, 105, true, 9), SPAM).unwrap();
+        assert_eq!(wave.users, vec![UserId::new(6)]);
+    }
+}
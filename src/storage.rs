@@ -0,0 +1,1141 @@
+use crate::llm::LlmTask;
+use crate::review::Decision;
+use crate::strikes::Step;
+use crate::usage::{Usage, UsageKey, UsageLedger};
+use crate::user_info::{GuildUser, UserHistory};
+use chrono::NaiveDate;
+use rusqlite::{params, Connection};
+use serenity::all::{ChannelId, Context, GuildId, Message, MessageId, Timestamp, UserId};
+use serenity::prelude::TypeMapKey;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::error;
+
+/// How many recent messages per user survive a restart.
+const MESSAGES_KEPT_PER_USER: usize = 10;
+
+static SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS join_dates (
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    joined_at INTEGER NOT NULL,
+    PRIMARY KEY (guild_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS message_history (
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    channel_id INTEGER,
+    message_id INTEGER
+);
+CREATE INDEX IF NOT EXISTS message_history_user ON message_history (guild_id, user_id, sent_at);
+CREATE TABLE IF NOT EXISTS message_counts (
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    count INTEGER NOT NULL,
+    PRIMARY KEY (guild_id, user_id)
+);
+CREATE TABLE IF NOT EXISTS moderation_actions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    message_id INTEGER,
+    action TEXT NOT NULL,
+    reason TEXT,
+    content TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    mention_everyone INTEGER NOT NULL DEFAULT 0,
+    false_positive_by INTEGER,
+    false_positive_at INTEGER,
+    judged_by INTEGER,
+    judged_at INTEGER,
+    judged_correct INTEGER
+);
+CREATE INDEX IF NOT EXISTS moderation_actions_user ON moderation_actions (guild_id, user_id);
+CREATE TABLE IF NOT EXISTS strikes (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    message_id INTEGER,
+    weight INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    blunder INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS strikes_user ON strikes (guild_id, user_id, created_at);
+CREATE TABLE IF NOT EXISTS llm_usage (
+    day TEXT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    task TEXT NOT NULL,
+    model TEXT NOT NULL,
+    calls INTEGER NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    cost_usd REAL NOT NULL,
+    PRIMARY KEY (day, guild_id, task, model)
+);
+CREATE TABLE IF NOT EXISTS review_cases (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    author_name TEXT NOT NULL,
+    content TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    quarantined INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    mention_everyone INTEGER NOT NULL DEFAULT 0,
+    decision TEXT,
+    decided_by INTEGER,
+    decided_at INTEGER
+);
+CREATE TABLE IF NOT EXISTS labels (
+    guild_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    is_spam INTEGER NOT NULL,
+    labelled_by INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    content TEXT,
+    hosts TEXT,
+    PRIMARY KEY (guild_id, message_id)
+);
+";
+
+/// What the bot did to a member. Stored as text in `moderation_actions.action`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionKind {
+    /// Author warned, message left up.
+    Warn,
+    /// Message removed and the author warned.
+    Delete,
+    /// Message removed, author warned and timed out.
+    DeleteAndTimeout,
+    /// Author timed out, message left up.
+    Timeout,
+    Kick,
+    Ban,
+    /// Message taken down for a moderator because it couldn't be checked.
+    Hold,
+    /// Posted in the honeypot and was banned.
+    HoneyPotBan,
+    /// Took part in a spam wave, so every copy was removed and the author timed out or banned.
+    SpamWave,
+    /// Timed out after enough blunder reactions.
+    BlunderTimeout,
+    /// A held message a moderator found was fine, so it was put back.
+    Approve,
+}
+
+impl ActionKind {
+    pub const ALL: [ActionKind; 11] = [
+        ActionKind::Warn,
+        ActionKind::Delete,
+        ActionKind::DeleteAndTimeout,
+        ActionKind::Timeout,
+        ActionKind::Kick,
+        ActionKind::Ban,
+        ActionKind::Hold,
+        ActionKind::HoneyPotBan,
+        ActionKind::SpamWave,
+        ActionKind::BlunderTimeout,
+        ActionKind::Approve,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionKind::Warn => "warn",
+            ActionKind::Delete => "delete",
+            ActionKind::DeleteAndTimeout => "delete_and_timeout",
+            ActionKind::Timeout => "timeout",
+            ActionKind::Kick => "kick",
+            ActionKind::Ban => "ban",
+            ActionKind::Hold => "hold",
+            ActionKind::HoneyPotBan => "honey_pot_ban",
+            ActionKind::SpamWave => "spam_wave",
+            ActionKind::BlunderTimeout => "blunder_timeout",
+            ActionKind::Approve => "approve",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ActionKind> {
+        ActionKind::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == name)
+    }
+}
+
+impl ActionKind {
+    /// What taking `step` against a member amounts to, with their message `deleted` or not.
+    pub fn for_step(step: Step, deleted: bool) -> ActionKind {
+        match step {
+            Step::Warn if deleted => ActionKind::Delete,
+            Step::Warn => ActionKind::Warn,
+            Step::Timeout { .. } if deleted => ActionKind::DeleteAndTimeout,
+            Step::Timeout { .. } => ActionKind::Timeout,
+            Step::Kick => ActionKind::Kick,
+            Step::Ban => ActionKind::Ban,
+        }
+    }
+}
+
+/// A strike against a member, which counts towards their rung of the ladder until it decays.
+#[derive(Debug, Clone)]
+pub struct StrikeRecord {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    /// The message that earned it, if any.
+    pub message_id: Option<MessageId>,
+    pub weight: u32,
+    pub created_at: i64,
+    /// Blunder strikes climb their own ladder, apart from spam.
+    pub blunder: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModerationRecord {
+    pub guild_id: GuildId,
+    pub user_id: UserId,
+    pub channel_id: ChannelId,
+    pub message_id: Option<MessageId>,
+    pub action: ActionKind,
+    pub reason: Option<String>,
+    pub content: String,
+    pub created_at: i64,
+    pub mention_everyone: bool,
+}
+
+impl ModerationRecord {
+    pub fn for_message(
+        guild_id: GuildId,
+        message: &Message,
+        action: ActionKind,
+        reason: Option<&str>,
+    ) -> ModerationRecord {
+        ModerationRecord {
+            guild_id,
+            user_id: message.author.id,
+            channel_id: message.channel_id,
+            message_id: Some(message.id),
+            action,
+            reason: reason.map(str::to_string),
+            content: message.content.clone(),
+            created_at: Timestamp::now().unix_timestamp(),
+            mention_everyone: message.mention_everyone,
+        }
+    }
+}
+
+/// A held message waiting for, or given, a moderator's decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReviewCase {
+    /// Assigned by the store, so ignored when opening a case.
+    pub id: i64,
+    pub guild_id: GuildId,
+    pub channel_id: ChannelId,
+    pub message_id: MessageId,
+    pub user_id: UserId,
+    pub author_name: String,
+    pub content: String,
+    pub reason: String,
+    /// Whether the author was timed out until the case is decided.
+    pub quarantined: bool,
+    pub created_at: i64,
+    pub mention_everyone: bool,
+}
+
+/// File-backed store for everything the bot should remember across restarts.
+///
+/// Handlers call it inline rather than through `spawn_blocking`: each call is a statement
+/// or two on indexed tables in a local file, and in WAL mode a commit doesn't wait for
+/// the disk, so the lock is held for well under a millisecond.
+pub struct Storage {
+    connection: Mutex<Connection>,
+}
+
+pub struct Store;
+
+impl TypeMapKey for Store {
+    type Value = Arc<Storage>;
+}
+
+impl Storage {
+    pub fn open(path: &Path) -> rusqlite::Result<Storage> {
+        let connection = Connection::open(path)?;
+        connection
+            .pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        connection.pragma_update(None, "synchronous", "NORMAL")?;
+        Storage::from_connection(connection)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> rusqlite::Result<Storage> {
+        Storage::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(connection: Connection) -> rusqlite::Result<Storage> {
+        connection.execute_batch(SCHEMA)?;
+        // Databases from before messages were tracked by ID, actions could be undone or
+        // judged, or labels kept their message and its links, lack these columns.
+        for (table, column, kind) in [
+            ("message_history", "channel_id", "INTEGER"),
+            ("message_history", "message_id", "INTEGER"),
+            ("moderation_actions", "false_positive_by", "INTEGER"),
+            ("moderation_actions", "false_positive_at", "INTEGER"),
+            ("moderation_actions", "judged_by", "INTEGER"),
+            ("moderation_actions", "judged_at", "INTEGER"),
+            ("moderation_actions", "judged_correct", "INTEGER"),
+            (
+                "moderation_actions",
+                "mention_everyone",
+                "INTEGER NOT NULL DEFAULT 0",
+            ),
+            (
+                "review_cases",
+                "mention_everyone",
+                "INTEGER NOT NULL DEFAULT 0",
+            ),
+            ("labels", "content", "TEXT"),
+            ("labels", "hosts", "TEXT"),
+            ("strikes", "blunder", "INTEGER NOT NULL DEFAULT 0"),
+        ] {
+            let exists: bool = connection.query_row(
+                "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+                [table, column],
+                |row| row.get(0),
+            )?;
+            if !exists {
+                connection.execute_batch(
+                    format!("ALTER TABLE {table} ADD COLUMN {column} {kind}").as_str(),
+                )?;
+            }
+        }
+        Ok(Storage {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    pub fn load_join_dates(&self) -> rusqlite::Result<HashMap<GuildUser, i64>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT guild_id, user_id, joined_at FROM join_dates")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                (
+                    GuildId::new(row.get::<_, i64>(0)? as u64),
+                    UserId::new(row.get::<_, i64>(1)? as u64),
+                ),
+                row.get::<_, i64>(2)?,
+            ))
+        })?;
+        rows.collect()
+    }
+
+    pub fn save_join_date(
+        &self,
+        (guild_id, user_id): GuildUser,
+        joined_at: i64,
+    ) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO join_dates (guild_id, user_id, joined_at) VALUES (?1, ?2, ?3)",
+            params![guild_id.get() as i64, user_id.get() as i64, joined_at],
+        )?;
+        Ok(())
+    }
+
+    pub fn load_user_contexts(&self) -> rusqlite::Result<HashMap<GuildUser, UserHistory>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT guild_id, user_id, sent_at, content FROM message_history ORDER BY sent_at",
+        )?;
+        let mut contexts: HashMap<GuildUser, UserHistory> = HashMap::new();
+        let rows = statement.query_map([], |row| {
+            Ok((
+                GuildId::new(row.get::<_, i64>(0)? as u64),
+                UserId::new(row.get::<_, i64>(1)? as u64),
+                row.get::<_, i64>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?;
+        for row in rows {
+            let (guild_id, user_id, sent_at, content) = row?;
+            if let Ok(sent_at) = Timestamp::from_unix_timestamp(sent_at) {
+                contexts
+                    .entry((guild_id, user_id))
+                    .or_default()
+                    .push(sent_at, content);
+            }
+        }
+        Ok(contexts)
+    }
+
+    pub fn save_message(
+        &self,
+        (guild_id, user_id): GuildUser,
+        (channel_id, message_id): (ChannelId, MessageId),
+        sent_at: Timestamp,
+        content: &str,
+    ) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO message_history (guild_id, user_id, sent_at, content, channel_id, message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                guild_id.get() as i64,
+                user_id.get() as i64,
+                sent_at.unix_timestamp(),
+                content,
+                channel_id.get() as i64,
+                message_id.get() as i64
+            ],
+        )?;
+        transaction.execute(
+            "DELETE FROM message_history WHERE guild_id = ?1 AND user_id = ?2 AND rowid NOT IN (
+                SELECT rowid FROM message_history WHERE guild_id = ?1 AND user_id = ?2
+                ORDER BY sent_at DESC LIMIT ?3
+            )",
+            params![
+                guild_id.get() as i64,
+                user_id.get() as i64,
+                MESSAGES_KEPT_PER_USER as i64
+            ],
+        )?;
+        transaction.execute(
+            "INSERT INTO message_counts (guild_id, user_id, count) VALUES (?1, ?2, 1)
+             ON CONFLICT (guild_id, user_id) DO UPDATE SET count = count + 1",
+            params![guild_id.get() as i64, user_id.get() as i64],
+        )?;
+        transaction.commit()
+    }
+
+    /// How many messages from the member have been saved, including those trimmed since.
+    pub fn message_count(&self, (guild_id, user_id): GuildUser) -> rusqlite::Result<u32> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT COALESCE(
+                (SELECT count FROM message_counts WHERE guild_id = ?1 AND user_id = ?2), 0
+            )",
+            params![guild_id.get() as i64, user_id.get() as i64],
+            |row| row.get(0),
+        )
+    }
+
+    /// Where the member's tracked messages sent at or after `since` are, oldest first.
+    pub fn recent_messages(
+        &self,
+        (guild_id, user_id): GuildUser,
+        since: i64,
+    ) -> rusqlite::Result<Vec<(ChannelId, MessageId)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT channel_id, message_id FROM message_history
+             WHERE guild_id = ?1 AND user_id = ?2 AND sent_at >= ?3 AND message_id IS NOT NULL
+             ORDER BY sent_at",
+        )?;
+        let rows = statement.query_map(
+            params![guild_id.get() as i64, user_id.get() as i64, since],
+            |row| {
+                Ok((
+                    ChannelId::new(row.get::<_, i64>(0)? as u64),
+                    MessageId::new(row.get::<_, i64>(1)? as u64),
+                ))
+            },
+        )?;
+        rows.collect()
+    }
+
+    pub fn add_strike(&self, record: &StrikeRecord) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO strikes (guild_id, user_id, message_id, weight, created_at, blunder)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.guild_id.get() as i64,
+                record.user_id.get() as i64,
+                record.message_id.map(|id| id.get() as i64),
+                record.weight,
+                record.created_at,
+                record.blunder
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Total weight of the member's blunder or other strikes made at or after `since`.
+    pub fn active_strikes(
+        &self,
+        (guild_id, user_id): GuildUser,
+        since: i64,
+        blunder: bool,
+    ) -> rusqlite::Result<u32> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT COALESCE(SUM(weight), 0) FROM strikes
+             WHERE guild_id = ?1 AND user_id = ?2 AND created_at >= ?3 AND blunder = ?4",
+            params![guild_id.get() as i64, user_id.get() as i64, since, blunder],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether `message_id` already earned its author a strike.
+    pub fn has_strike_for(
+        &self,
+        guild_id: GuildId,
+        message_id: MessageId,
+    ) -> rusqlite::Result<bool> {
+        self.connection.lock().unwrap().query_row(
+            "SELECT COUNT(*) > 0 FROM strikes WHERE guild_id = ?1 AND message_id = ?2",
+            params![guild_id.get() as i64, message_id.get() as i64],
+            |row| row.get(0),
+        )
+    }
+
+    /// Forgives the strikes `message_id` earned its author.
+    pub fn remove_strikes_for(
+        &self,
+        guild_id: GuildId,
+        message_id: MessageId,
+    ) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "DELETE FROM strikes WHERE guild_id = ?1 AND message_id = ?2",
+            params![guild_id.get() as i64, message_id.get() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Stores an action and returns its case ID.
+    pub fn record_action(&self, record: &ModerationRecord) -> rusqlite::Result<i64> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO moderation_actions
+                (guild_id, user_id, channel_id, message_id, action, reason, content, created_at,
+                 mention_everyone)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                record.guild_id.get() as i64,
+                record.user_id.get() as i64,
+                record.channel_id.get() as i64,
+                record.message_id.map(|id| id.get() as i64),
+                record.action.as_str(),
+                record.reason,
+                record.content,
+                record.created_at,
+                record.mention_everyone,
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn moderation_action(&self, id: i64) -> rusqlite::Result<Option<ModerationRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT guild_id, user_id, channel_id, message_id, action, reason, content, created_at,
+                    mention_everyone
+             FROM moderation_actions WHERE id = ?1",
+        )?;
+        let mut rows = statement.query_map([id], |row| {
+            let action: String = row.get(4)?;
+            Ok((
+                ModerationRecord {
+                    guild_id: GuildId::new(row.get::<_, i64>(0)? as u64),
+                    user_id: UserId::new(row.get::<_, i64>(1)? as u64),
+                    channel_id: ChannelId::new(row.get::<_, i64>(2)? as u64),
+                    message_id: row
+                        .get::<_, Option<i64>>(3)?
+                        .map(|id| MessageId::new(id as u64)),
+                    action: ActionKind::Warn,
+                    reason: row.get(5)?,
+                    content: row.get(6)?,
+                    created_at: row.get(7)?,
+                    mention_everyone: row.get(8)?,
+                },
+                action,
+            ))
+        })?;
+        // Rows with an action this version doesn't know are treated as missing.
+        Ok(rows.next().transpose()?.and_then(|(record, action)| {
+            ActionKind::from_name(&action).map(|action| ModerationRecord { action, ..record })
+        }))
+    }
+
+    /// Marks an action as undone as a mistake, unless a moderator already undid it, in
+    /// which case nothing changes and `false` is returned.
+    pub fn mark_false_positive(
+        &self,
+        id: i64,
+        moderator: UserId,
+        at: i64,
+    ) -> rusqlite::Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE moderation_actions SET false_positive_by = ?2, false_positive_at = ?3
+             WHERE id = ?1 AND false_positive_at IS NULL",
+            params![id, moderator.get() as i64, at],
+        )?;
+        Ok(updated == 1)
+    }
+
+    /// Records whether a moderator thought an action was right, unless one already judged
+    /// it, in which case nothing changes and `false` is returned. Kept apart from undoing,
+    /// so an action judged wrong can still be undone.
+    pub fn judge_action(
+        &self,
+        id: i64,
+        correct: bool,
+        moderator: UserId,
+        at: i64,
+    ) -> rusqlite::Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE moderation_actions SET judged_by = ?2, judged_at = ?3, judged_correct = ?4
+             WHERE id = ?1 AND judged_at IS NULL",
+            params![id, moderator.get() as i64, at, correct],
+        )?;
+        Ok(updated == 1)
+    }
+
+    /// Records a moderator's label for a message, unless it already has one, in which case
+    /// nothing changes and `false` is returned. `content` should already be anonymised, and
+    /// `hosts` are the domains it linked to.
+    pub fn add_label(
+        &self,
+        (guild_id, message_id): (GuildId, MessageId),
+        (content, hosts, is_spam): (&str, &[String], bool),
+        moderator: UserId,
+        at: i64,
+    ) -> rusqlite::Result<bool> {
+        let inserted = self.connection.lock().unwrap().execute(
+            "INSERT OR IGNORE INTO labels
+                (guild_id, message_id, is_spam, labelled_by, created_at, content, hosts)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                guild_id.get() as i64,
+                message_id.get() as i64,
+                is_spam,
+                moderator.get() as i64,
+                at,
+                content,
+                hosts.join(" ")
+            ],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    /// The guild's latest labelled messages, oldest first, as `(content, hosts, is_spam)`.
+    pub fn labelled_examples(
+        &self,
+        guild_id: GuildId,
+        limit: usize,
+    ) -> rusqlite::Result<Vec<(String, Vec<String>, bool)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT content, hosts, is_spam FROM (
+                SELECT content, hosts, is_spam, created_at FROM labels
+                WHERE guild_id = ?1 AND content IS NOT NULL
+                ORDER BY created_at DESC LIMIT ?2
+             ) ORDER BY created_at",
+        )?;
+        let rows = statement.query_map(params![guild_id.get() as i64, limit as i64], |row| {
+            let hosts: Option<String> = row.get(1)?;
+            let hosts = hosts.map_or(vec![], |hosts| {
+                hosts.split_whitespace().map(str::to_string).collect()
+            });
+            Ok((row.get(0)?, hosts, row.get(2)?))
+        })?;
+        rows.collect()
+    }
+
+    /// Clears the mark from an undo that couldn't be carried out.
+    pub fn unmark_false_positive(&self, id: i64) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE moderation_actions SET false_positive_by = NULL, false_positive_at = NULL
+             WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    /// Stores a newly held message and returns its case ID.
+    pub fn open_review(&self, case: &ReviewCase) -> rusqlite::Result<i64> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO review_cases
+                (guild_id, channel_id, message_id, user_id, author_name, content, reason,
+                 quarantined, created_at, mention_everyone)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                case.guild_id.get() as i64,
+                case.channel_id.get() as i64,
+                case.message_id.get() as i64,
+                case.user_id.get() as i64,
+                case.author_name,
+                case.content,
+                case.reason,
+                case.quarantined,
+                case.created_at,
+                case.mention_everyone,
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    /// Notes that the author of a held message was timed out until it's decided.
+    pub fn mark_quarantined(&self, id: i64) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE review_cases SET quarantined = 1 WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    pub fn review_case(&self, id: i64) -> rusqlite::Result<Option<ReviewCase>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT guild_id, channel_id, message_id, user_id, author_name, content, reason,
+                    quarantined, created_at, mention_everyone
+             FROM review_cases WHERE id = ?1",
+        )?;
+        let mut rows = statement.query_map([id], |row| {
+            Ok(ReviewCase {
+                id,
+                guild_id: GuildId::new(row.get::<_, i64>(0)? as u64),
+                channel_id: ChannelId::new(row.get::<_, i64>(1)? as u64),
+                message_id: MessageId::new(row.get::<_, i64>(2)? as u64),
+                user_id: UserId::new(row.get::<_, i64>(3)? as u64),
+                author_name: row.get(4)?,
+                content: row.get(5)?,
+                reason: row.get(6)?,
+                quarantined: row.get(7)?,
+                created_at: row.get(8)?,
+                mention_everyone: row.get(9)?,
+            })
+        })?;
+        rows.next().transpose()
+    }
+
+    /// Records `moderator`'s decision, unless the case was already decided, in which
+    /// case nothing changes and `false` is returned.
+    pub fn decide_review(
+        &self,
+        id: i64,
+        decision: Decision,
+        moderator: UserId,
+        decided_at: i64,
+    ) -> rusqlite::Result<bool> {
+        let updated = self.connection.lock().unwrap().execute(
+            "UPDATE review_cases SET decision = ?2, decided_by = ?3, decided_at = ?4
+             WHERE id = ?1 AND decision IS NULL",
+            params![id, decision.as_str(), moderator.get() as i64, decided_at],
+        )?;
+        Ok(updated == 1)
+    }
+
+    /// Undoes a decision that couldn't be carried out, so the case can be decided again.
+    pub fn reopen_review(&self, id: i64) -> rusqlite::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "UPDATE review_cases SET decision = NULL, decided_by = NULL, decided_at = NULL
+             WHERE id = ?1",
+            [id],
+        )?;
+        Ok(())
+    }
+
+    /// Adds `rows` to the daily totals.
+    pub fn add_usage(&self, rows: &[(UsageKey, Usage)]) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for (key, usage) in rows {
+            transaction.execute(
+                "INSERT INTO llm_usage
+                    (day, guild_id, task, model, calls, prompt_tokens, completion_tokens, cost_usd)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                 ON CONFLICT (day, guild_id, task, model) DO UPDATE SET
+                    calls = calls + excluded.calls,
+                    prompt_tokens = prompt_tokens + excluded.prompt_tokens,
+                    completion_tokens = completion_tokens + excluded.completion_tokens,
+                    cost_usd = cost_usd + excluded.cost_usd",
+                params![
+                    key.day.to_string(),
+                    // 0 stands for calls made outside a guild.
+                    key.guild_id.map_or(0, |id| id.get() as i64),
+                    key.task.as_str(),
+                    key.model,
+                    usage.calls as i64,
+                    usage.prompt_tokens as i64,
+                    usage.completion_tokens as i64,
+                    usage.cost_usd,
+                ],
+            )?;
+        }
+        transaction.commit()
+    }
+
+    /// Writes through whatever `ledger` hasn't saved yet.
+    pub fn save_usage(&self, ledger: &UsageLedger) -> rusqlite::Result<()> {
+        let rows = ledger.take_unsaved();
+        if rows.is_empty() {
+            return Ok(());
+        }
+        self.add_usage(&rows)
+    }
+
+    /// Daily totals from `since` onwards. Rows for tasks this build doesn't know are skipped.
+    pub fn load_usage(&self, since: NaiveDate) -> rusqlite::Result<Vec<(UsageKey, Usage)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT day, guild_id, task, model, calls, prompt_tokens, completion_tokens, cost_usd
+             FROM llm_usage WHERE day >= ?1 ORDER BY day",
+        )?;
+        let rows = statement.query_map(params![since.to_string()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                Usage {
+                    calls: row.get::<_, i64>(4)? as u64,
+                    prompt_tokens: row.get::<_, i64>(5)? as u64,
+                    completion_tokens: row.get::<_, i64>(6)? as u64,
+                    cost_usd: row.get(7)?,
+                },
+            ))
+        })?;
+        let mut usage = vec![];
+        for row in rows {
+            let (day, guild_id, task, model, totals) = row?;
+            let (Ok(day), Some(task)) = (day.parse::<NaiveDate>(), LlmTask::from_name(&task))
+            else {
+                continue;
+            };
+            let key = UsageKey {
+                day,
+                guild_id: (guild_id != 0).then(|| GuildId::new(guild_id as u64)),
+                task,
+                model,
+            };
+            usage.push((key, totals));
+        }
+        Ok(usage)
+    }
+}
+
+pub async fn get_storage(ctx: &Context) -> Arc<Storage> {
+    let data_read = ctx.data.read().await;
+    data_read
+        .get::<Store>()
+        .expect("Expected Store in TypeMap.")
+        .clone()
+}
+
+/// Writes a moderation action through to the store, logging rather than failing on error.
+/// Returns the case ID, if it was stored.
+pub async fn record_action(ctx: &Context, record: ModerationRecord) -> Option<i64> {
+    get_storage(ctx)
+        .await
+        .record_action(&record)
+        .inspect_err(|e| error!("Failed to record moderation action {:?} due to {e}", record))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guild_user() -> GuildUser {
+        (GuildId::new(1), UserId::new(2))
+    }
+
+    #[test]
+    fn join_dates_round_trip() {
+        let storage = Storage::open_in_memory().unwrap();
+        storage.save_join_date(guild_user(), 1_700_000_000).unwrap();
+        // The first join date seen is kept.
+        storage.save_join_date(guild_user(), 1_800_000_000).unwrap();
+        let join_dates = storage.load_join_dates().unwrap();
+        assert_eq!(join_dates.get(&guild_user()), Some(&1_700_000_000));
+        assert!(!join_dates.contains_key(&(GuildId::new(3), UserId::new(2))));
+    }
+
+    #[test]
+    fn message_history_is_trimmed() {
+        let storage = Storage::open_in_memory().unwrap();
+        for i in 0..(MESSAGES_KEPT_PER_USER as i64 + 5) {
+            let sent_at = Timestamp::from_unix_timestamp(1_700_000_000 + i).unwrap();
+            storage
+                .save_message(
+                    guild_user(),
+                    (ChannelId::new(3), MessageId::new(100 + i as u64)),
+                    sent_at,
+                    format!("message {i}").as_str(),
+                )
+                .unwrap();
+        }
+        let count: i64 = storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM message_history", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, MESSAGES_KEPT_PER_USER as i64);
+        assert_eq!(
+            storage.message_count(guild_user()).unwrap(),
+            MESSAGES_KEPT_PER_USER as u32 + 5
+        );
+        assert_eq!(
+            storage
+                .message_count((GuildId::new(3), UserId::new(2)))
+                .unwrap(),
+            0
+        );
+        assert!(storage
+            .load_user_contexts()
+            .unwrap()
+            .contains_key(&guild_user()));
+        let recent = storage
+            .recent_messages(guild_user(), 1_700_000_000 + 13)
+            .unwrap();
+        assert_eq!(
+            recent,
+            vec![
+                (ChannelId::new(3), MessageId::new(113)),
+                (ChannelId::new(3), MessageId::new(114))
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_message_ids_to_old_databases() {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(
+                "CREATE TABLE message_history (
+                    guild_id INTEGER NOT NULL,
+                    user_id INTEGER NOT NULL,
+                    sent_at INTEGER NOT NULL,
+                    content TEXT NOT NULL
+                );
+                INSERT INTO message_history VALUES (1, 2, 1700000000, 'hello');",
+            )
+            .unwrap();
+        let storage = Storage::from_connection(connection).unwrap();
+        assert!(storage.recent_messages(guild_user(), 0).unwrap().is_empty());
+        assert_eq!(storage.load_user_contexts().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn reopens_files() {
+        let path = std::env::temp_dir().join(format!("bse-{}.sqlite3", std::process::id()));
+        let storage = Storage::open(&path).unwrap();
+        storage.save_join_date(guild_user(), 1_700_000_000).unwrap();
+        drop(storage);
+        let storage = Storage::open(&path).unwrap();
+        assert_eq!(storage.load_join_dates().unwrap().len(), 1);
+        drop(storage);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+        }
+    }
+
+    #[test]
+    fn strikes_decay() {
+        let storage = Storage::open_in_memory().unwrap();
+        let (guild_id, user_id) = guild_user();
+        for (message_id, weight, created_at, blunder) in [
+            (10, 1, 100, false),
+            (11, 2, 200, false),
+            (12, 5, 300, false),
+            (13, 2, 300, true),
+        ] {
+            storage
+                .add_strike(&StrikeRecord {
+                    guild_id,
+                    user_id,
+                    message_id: Some(MessageId::new(message_id)),
+                    weight,
+                    created_at,
+                    blunder,
+                })
+                .unwrap();
+        }
+        assert_eq!(storage.active_strikes(guild_user(), 0, false).unwrap(), 8);
+        assert_eq!(storage.active_strikes(guild_user(), 150, false).unwrap(), 7);
+        assert_eq!(storage.active_strikes(guild_user(), 301, false).unwrap(), 0);
+        assert_eq!(storage.active_strikes(guild_user(), 0, true).unwrap(), 2);
+        assert!(storage
+            .has_strike_for(guild_id, MessageId::new(11))
+            .unwrap());
+        assert!(!storage
+            .has_strike_for(guild_id, MessageId::new(14))
+            .unwrap());
+        storage
+            .remove_strikes_for(guild_id, MessageId::new(12))
+            .unwrap();
+        assert_eq!(storage.active_strikes(guild_user(), 0, false).unwrap(), 3);
+    }
+
+    #[test]
+    fn usage_adds_up() {
+        let storage = Storage::open_in_memory().unwrap();
+        let day = NaiveDate::from_ymd_opt(2025, 1, 2).unwrap();
+        let key = UsageKey {
+            day,
+            guild_id: Some(GuildId::new(1)),
+            task: LlmTask::Spam,
+            model: "gpt-4.1-mini".to_string(),
+        };
+        let usage = Usage {
+            calls: 1,
+            prompt_tokens: 300,
+            completion_tokens: 20,
+            cost_usd: 0.001,
+        };
+        storage.add_usage(&[(key.clone(), usage)]).unwrap();
+        storage.add_usage(&[(key.clone(), usage)]).unwrap();
+        let old = UsageKey {
+            day: day.pred_opt().unwrap(),
+            guild_id: None,
+            ..key.clone()
+        };
+        storage.add_usage(&[(old, usage)]).unwrap();
+
+        let rows = storage.load_usage(day).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, key);
+        assert_eq!(rows[0].1.calls, 2);
+        assert_eq!(rows[0].1.prompt_tokens, 600);
+        assert_eq!(
+            storage.load_usage(day.pred_opt().unwrap()).unwrap().len(),
+            2
+        );
+    }
+
+    #[test]
+    fn review_cases_are_decided_once() {
+        let storage = Storage::open_in_memory().unwrap();
+        let mut case = ReviewCase {
+            id: 0,
+            guild_id: GuildId::new(1),
+            channel_id: ChannelId::new(3),
+            message_id: MessageId::new(4),
+            user_id: UserId::new(2),
+            author_name: "someone".to_string(),
+            content: "free nitro".to_string(),
+            reason: "Spam detection was unavailable".to_string(),
+            quarantined: false,
+            created_at: 1_700_000_000,
+            mention_everyone: false,
+        };
+        case.id = storage.open_review(&case).unwrap();
+        assert_eq!(storage.review_case(case.id).unwrap(), Some(case.clone()));
+        storage.mark_quarantined(case.id).unwrap();
+        case.quarantined = true;
+        assert_eq!(storage.review_case(case.id).unwrap(), Some(case.clone()));
+        assert_eq!(storage.review_case(case.id + 1).unwrap(), None);
+
+        let first = UserId::new(10);
+        assert!(storage
+            .decide_review(case.id, Decision::Ban, first, 1_700_000_100)
+            .unwrap());
+        assert!(!storage
+            .decide_review(case.id, Decision::Approve, UserId::new(11), 1_700_000_101)
+            .unwrap());
+        storage.reopen_review(case.id).unwrap();
+        assert!(storage
+            .decide_review(case.id, Decision::Approve, UserId::new(11), 1_700_000_102)
+            .unwrap());
+    }
+
+    #[test]
+    fn moderation_actions_round_trip() {
+        let storage = Storage::open_in_memory().unwrap();
+        let record = ModerationRecord {
+            guild_id: GuildId::new(1),
+            user_id: UserId::new(2),
+            channel_id: ChannelId::new(3),
+            message_id: Some(MessageId::new(4)),
+            action: ActionKind::DeleteAndTimeout,
+            reason: Some("Phishing".to_string()),
+            content: "free nitro".to_string(),
+            created_at: 1_700_000_000,
+            mention_everyone: true,
+        };
+        let id = storage.record_action(&record).unwrap();
+        let (action, reason): (String, String) = storage
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT action, reason FROM moderation_actions WHERE guild_id = 1 AND user_id = 2",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!(action, "delete_and_timeout");
+        assert_eq!(reason, "Phishing");
+        assert_eq!(storage.moderation_action(id).unwrap(), Some(record.clone()));
+        assert_eq!(storage.moderation_action(id + 1).unwrap(), None);
+
+        let moderator = UserId::new(10);
+        assert!(storage
+            .mark_false_positive(id, moderator, 1_700_000_100)
+            .unwrap());
+        assert!(!storage
+            .mark_false_positive(id, moderator, 1_700_000_101)
+            .unwrap());
+        storage.unmark_false_positive(id).unwrap();
+        assert!(storage
+            .mark_false_positive(id, moderator, 1_700_000_102)
+            .unwrap());
+        assert!(storage
+            .judge_action(id, true, moderator, 1_700_000_103)
+            .unwrap());
+        let other = storage.record_action(&record).unwrap();
+        assert!(storage
+            .judge_action(other, false, moderator, 1_700_000_104)
+            .unwrap());
+        assert!(!storage
+            .judge_action(other, true, moderator, 1_700_000_105)
+            .unwrap());
+        // Judging an action wrong doesn't stop it being undone.
+        assert!(storage
+            .mark_false_positive(other, moderator, 1_700_000_105)
+            .unwrap());
+
+        let message = (GuildId::new(1), MessageId::new(4));
+        let hosts = vec!["dlscord-gift.com".to_string()];
+        assert!(storage
+            .add_label(
+                message,
+                ("free nitro at dlscord-gift", &hosts, true),
+                moderator,
+                1_700_000_106
+            )
+            .unwrap());
+        assert!(!storage
+            .add_label(
+                message,
+                ("free nitro at dlscord-gift", &[], false),
+                UserId::new(11),
+                1_700_000_107
+            )
+            .unwrap());
+        let later = (GuildId::new(1), MessageId::new(5));
+        storage
+            .add_label(
+                later,
+                ("how do I pin a crate?", &[], false),
+                moderator,
+                1_700_000_108,
+            )
+            .unwrap();
+        assert_eq!(
+            storage.labelled_examples(GuildId::new(1), 10).unwrap(),
+            vec![
+                ("free nitro at dlscord-gift".to_string(), hosts, true),
+                ("how do I pin a crate?".to_string(), vec![], false)
+            ]
+        );
+        assert_eq!(
+            storage.labelled_examples(GuildId::new(1), 1).unwrap(),
+            vec![("how do I pin a crate?".to_string(), vec![], false)]
+        );
+    }
+}
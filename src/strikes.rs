@@ -0,0 +1,370 @@
+use crate::config::SpamAction;
+use crate::storage::{get_storage, StrikeRecord};
+use anyhow::bail;
+use serde::Deserialize;
+use serenity::all::{Context, GuildId, MessageId, Timestamp, UserId};
+use tracing::error;
+
+/// One rung of the escalation ladder.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    Warn,
+    Timeout { minutes: i64 },
+    Kick,
+    Ban,
+}
+
+fn plural(count: i64, unit: &str) -> String {
+    if count == 1 {
+        format!("1 {unit}")
+    } else {
+        format!("{count} {unit}s")
+    }
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Step::Warn => "warn",
+            Step::Timeout { .. } => "timeout",
+            Step::Kick => "kick",
+            Step::Ban => "ban",
+        }
+    }
+
+    /// Whichever of the two steps is harder on the member.
+    pub fn harsher(self, other: Step) -> Step {
+        let severity = |step: Step| match step {
+            Step::Warn => (0, 0),
+            Step::Timeout { minutes } => (1, minutes),
+            Step::Kick => (2, 0),
+            Step::Ban => (3, 0),
+        };
+        if severity(other) > severity(self) {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// What was done to the member, for logs, e.g. `timed them out for 2 hours`.
+    pub fn describe(&self) -> String {
+        match self {
+            Step::Warn => "warned them".to_string(),
+            Step::Timeout { minutes } if minutes % 1_440 == 0 => {
+                format!("timed them out for {}", plural(minutes / 1_440, "day"))
+            }
+            Step::Timeout { minutes } if minutes % 60 == 0 => {
+                format!("timed them out for {}", plural(minutes / 60, "hour"))
+            }
+            Step::Timeout { minutes } => {
+                format!("timed them out for {}", plural(*minutes, "minute"))
+            }
+            Step::Kick => "kicked them".to_string(),
+            Step::Ban => "banned them".to_string(),
+        }
+    }
+}
+
+/// What earned a member a strike.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Offence {
+    /// A spam verdict, and what the message itself deserved.
+    Spam(SpamAction),
+    Blunder,
+    HoneyPot,
+    /// Taking part in a spam wave.
+    SpamWave,
+}
+
+/// Strikes each offence is worth, set under `[guilds.<guild id>.strikes.weights]`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StrikeWeights {
+    pub warn: u32,
+    pub delete: u32,
+    #[serde(alias = "timeout")]
+    pub escalate: u32,
+    pub blunder: u32,
+    pub honey_pot: u32,
+    pub spam_wave: u32,
+}
+
+impl Default for StrikeWeights {
+    fn default() -> Self {
+        StrikeWeights {
+            // A warning leaves the message up, and shouldn't add up to a timeout.
+            warn: 0,
+            delete: 1,
+            escalate: 3,
+            blunder: 1,
+            honey_pot: 5,
+            spam_wave: 3,
+        }
+    }
+}
+
+/// How members are punished for repeat offences, set under `[guilds.<guild id>.strikes]`.
+/// A member's strikes from the last `decay_days` pick their rung of `ladder`; once past
+/// the top, the top rung applies.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Strikes {
+    pub decay_days: i64,
+    pub weights: StrikeWeights,
+    pub ladder: Vec<Step>,
+    /// Blunder strikes are counted apart and climb this ladder instead, which can only
+    /// warn or time out, so honest mistakes never get anyone kicked.
+    pub blunder_ladder: Vec<Step>,
+}
+
+impl Default for Strikes {
+    fn default() -> Self {
+        Strikes {
+            decay_days: 30,
+            weights: StrikeWeights::default(),
+            ladder: vec![
+                Step::Warn,
+                Step::Timeout { minutes: 60 },
+                Step::Timeout { minutes: 1_440 },
+                Step::Kick,
+                Step::Ban,
+            ],
+            blunder_ladder: vec![Step::Timeout { minutes: 15 }, Step::Timeout { minutes: 60 }],
+        }
+    }
+}
+
+/// Where a member's latest strike put them on the ladder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Escalation {
+    /// Strikes not yet decayed, including the latest.
+    pub strikes: u32,
+    /// Counting from 1, or 0 when the offence was worth no strikes and only warns.
+    pub rung: usize,
+    pub rungs: usize,
+    pub step: Step,
+}
+
+impl Escalation {
+    /// Why this step, for logs, e.g. `step 2 of 5 (timeout) at 3 strikes`.
+    pub fn describe(&self) -> String {
+        if self.rung == 0 {
+            return format!(
+                "no strike, at {}",
+                plural(i64::from(self.strikes), "strike")
+            );
+        }
+        format!(
+            "step {} of {} ({}) at {}",
+            self.rung,
+            self.rungs,
+            self.step.name(),
+            plural(i64::from(self.strikes), "strike")
+        )
+    }
+}
+
+impl Strikes {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.decay_days <= 0 {
+            bail!("`strikes.decay_days` must be positive")
+        }
+        for (name, ladder) in [
+            ("ladder", &self.ladder),
+            ("blunder_ladder", &self.blunder_ladder),
+        ] {
+            if ladder.is_empty() {
+                bail!("`strikes.{name}` needs at least one step")
+            }
+            for step in ladder {
+                // Discord refuses timeouts longer than 28 days.
+                if let Step::Timeout { minutes } = step {
+                    if !(1..=28 * 1_440).contains(minutes) {
+                        bail!("Timeouts in `strikes.{name}` must be between 1 and 40320 minutes (28 days)")
+                    }
+                }
+            }
+        }
+        if self
+            .blunder_ladder
+            .iter()
+            .any(|step| matches!(step, Step::Kick | Step::Ban))
+        {
+            bail!("`strikes.blunder_ladder` may only warn or time out")
+        }
+        Ok(())
+    }
+
+    /// Sets the first timeout of the spam or blunder ladder, for the settings that used
+    /// to fix those timeouts.
+    pub fn set_first_timeout(&mut self, blunder: bool, minutes: i64) {
+        let ladder = if blunder {
+            &mut self.blunder_ladder
+        } else {
+            &mut self.ladder
+        };
+        if let Some(Step::Timeout { minutes: first }) = ladder
+            .iter_mut()
+            .find(|step| matches!(step, Step::Timeout { .. }))
+        {
+            *first = minutes;
+        }
+    }
+
+    pub fn weight(&self, offence: Offence) -> u32 {
+        match offence {
+            Offence::Spam(SpamAction::Warn) => self.weights.warn,
+            Offence::Spam(SpamAction::Delete) => self.weights.delete,
+            Offence::Spam(SpamAction::Escalate) => self.weights.escalate,
+            Offence::Blunder => self.weights.blunder,
+            Offence::HoneyPot => self.weights.honey_pot,
+            Offence::SpamWave => self.weights.spam_wave,
+        }
+    }
+
+    /// Where `offence` puts a member who already has `earlier` active strikes. Offences
+    /// worth no strikes don't climb the ladder, and only warn.
+    pub fn escalate(&self, offence: Offence, earlier: u32) -> Escalation {
+        match self.weight(offence) {
+            0 => Escalation {
+                strikes: earlier,
+                rung: 0,
+                step: Step::Warn,
+                ..self.escalation(offence, earlier)
+            },
+            weight => self.escalation(offence, earlier + weight),
+        }
+    }
+
+    /// The rung a member with `strikes` active strikes is on, on the ladder for `offence`.
+    pub fn escalation(&self, offence: Offence, strikes: u32) -> Escalation {
+        let ladder = if offence == Offence::Blunder {
+            &self.blunder_ladder
+        } else {
+            &self.ladder
+        };
+        let rung = (strikes as usize).clamp(1, ladder.len());
+        Escalation {
+            strikes,
+            rung,
+            rungs: ladder.len(),
+            step: ladder[rung - 1],
+        }
+    }
+}
+
+/// Adds a strike for `offence` to the member's record and works out the step it earns.
+/// With `save` off, as in shadow mode, the step is worked out but nothing is recorded.
+pub async fn strike(
+    ctx: &Context,
+    strikes: &Strikes,
+    (guild_id, user_id): (GuildId, UserId),
+    message_id: Option<MessageId>,
+    offence: Offence,
+    save: bool,
+) -> Escalation {
+    let storage = get_storage(ctx).await;
+    let now = Timestamp::now().unix_timestamp();
+    let since = now - strikes.decay_days * 86_400;
+    let blunder = offence == Offence::Blunder;
+    let earlier = storage
+        .active_strikes((guild_id, user_id), since, blunder)
+        .unwrap_or_else(|e| {
+            error!("Couldn't load strikes for {user_id} due to {e}");
+            0
+        });
+    let weight = strikes.weight(offence);
+    if save && weight > 0 {
+        let record = StrikeRecord {
+            guild_id,
+            user_id,
+            message_id,
+            weight,
+            created_at: now,
+            blunder,
+        };
+        if let Err(e) = storage.add_strike(&record) {
+            error!("Failed to persist strike for {user_id} due to {e}");
+        }
+    }
+    strikes.escalate(offence, earlier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn climbs_the_ladder() {
+        let strikes = Strikes::default();
+        let spam = Offence::Spam(SpamAction::Delete);
+        assert_eq!(strikes.escalation(spam, 1).step, Step::Warn);
+        let second = strikes.escalation(spam, 2);
+        assert_eq!(second.step, Step::Timeout { minutes: 60 });
+        assert_eq!(second.describe(), "step 2 of 5 (timeout) at 2 strikes");
+        assert_eq!(second.step.describe(), "timed them out for 1 hour");
+        // A first sure verdict times out for a day, as it did before the ladder.
+        let escalate = Offence::Spam(SpamAction::Escalate);
+        assert_eq!(
+            strikes.escalation(escalate, strikes.weight(escalate)).step,
+            Step::Timeout { minutes: 1_440 }
+        );
+        assert_eq!(
+            Step::Timeout { minutes: 2_880 }.describe(),
+            "timed them out for 2 days"
+        );
+        assert_eq!(strikes.escalation(spam, 4).step, Step::Kick);
+        // Warnings leave the message up, so they neither add a strike nor punish.
+        let warning = strikes.escalate(Offence::Spam(SpamAction::Warn), 2);
+        assert_eq!(warning.step, Step::Warn);
+        assert_eq!(warning.describe(), "no strike, at 2 strikes");
+        assert_eq!(
+            strikes.escalate(spam, 1).step,
+            Step::Timeout { minutes: 60 }
+        );
+        assert_eq!(
+            strikes
+                .escalation(Offence::HoneyPot, strikes.weight(Offence::HoneyPot))
+                .step,
+            Step::Ban
+        );
+        assert_eq!(strikes.escalation(spam, 50).rung, 5);
+        // However many blunders, they only ever time out.
+        assert_eq!(
+            strikes.escalation(Offence::Blunder, 1).step,
+            Step::Timeout { minutes: 15 }
+        );
+        assert_eq!(
+            strikes.escalation(Offence::Blunder, 50).step,
+            Step::Timeout { minutes: 60 }
+        );
+        let wave_timeout = Step::Timeout { minutes: 4_320 };
+        assert_eq!(Step::Warn.harsher(wave_timeout), wave_timeout);
+        assert_eq!(
+            Step::Timeout { minutes: 60 }.harsher(wave_timeout),
+            wave_timeout
+        );
+        assert_eq!(Step::Kick.harsher(wave_timeout), Step::Kick);
+
+        let custom: Strikes = toml::from_str(
+            "decay_days = 7\nladder = [{ action = \"timeout\", minutes = 15 }, { action = \"ban\" }]\n",
+        )
+        .unwrap();
+        custom.validate().unwrap();
+        assert_eq!(
+            custom.escalation(spam, 1).step,
+            Step::Timeout { minutes: 15 }
+        );
+        assert_eq!(custom.escalation(spam, 3).step, Step::Ban);
+        let invalid: Strikes = toml::from_str("ladder = []\n").unwrap();
+        assert!(invalid.validate().is_err());
+        let invalid: Strikes =
+            toml::from_str("blunder_ladder = [{ action = \"kick\" }]\n").unwrap();
+        assert!(invalid.validate().is_err());
+        // Weights set under their old name still count.
+        let renamed: Strikes = toml::from_str("weights = { timeout = 4 }\n").unwrap();
+        assert_eq!(renamed.weight(escalate), 4);
+    }
+}
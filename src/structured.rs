@@ -0,0 +1,203 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldKind {
+    String,
+    Bool,
+    /// A number, also accepted as `"0.8"` or `"80%"`.
+    Number,
+}
+
+/// One field of the JSON object a prompt asks for.
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub name: &'static str,
+    pub kind: FieldKind,
+    pub required: bool,
+}
+
+impl Field {
+    pub const fn required(name: &'static str, kind: FieldKind) -> Field {
+        Field {
+            name,
+            kind,
+            required: true,
+        }
+    }
+
+    pub const fn optional(name: &'static str, kind: FieldKind) -> Field {
+        Field {
+            name,
+            kind,
+            required: false,
+        }
+    }
+}
+
+/// A JSON object a model is asked to reply with, described well enough to repair
+/// near misses and to tell the model what went wrong.
+pub trait StructuredReply: DeserializeOwned {
+    const FIELDS: &'static [Field];
+
+    /// e.g. `{"reason": string, "is_spam": boolean}`, for repair prompts.
+    fn describe() -> String {
+        let fields: Vec<String> = Self::FIELDS
+            .iter()
+            .map(|field| {
+                let kind = match field.kind {
+                    FieldKind::String => "string",
+                    FieldKind::Bool => "boolean",
+                    FieldKind::Number => "number",
+                };
+                let optional = if field.required { "" } else { " (optional)" };
+                format!("\"{}\": {kind}{optional}", field.name)
+            })
+            .collect();
+        format!("{{{}}}", fields.join(", "))
+    }
+}
+
+/// Finds the first complete JSON object in a reply, skipping code fences and any
+/// prose around it.
+pub fn extract_object(reply: &str) -> Option<&str> {
+    let start = reply.find('{')?;
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (offset, c) in reply[start..].char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' => in_string = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&reply[start..=start + offset]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn coerce(field: &Field, value: Value) -> Result<Value, String> {
+    match (field.kind, value) {
+        (FieldKind::String, Value::String(text)) => Ok(Value::String(text)),
+        (FieldKind::String, Value::Null) if !field.required => Ok(Value::Null),
+        (FieldKind::String, value @ (Value::Bool(_) | Value::Number(_))) => {
+            Ok(Value::String(value.to_string()))
+        }
+        (FieldKind::Bool, Value::Bool(flag)) => Ok(Value::Bool(flag)),
+        (FieldKind::Bool, Value::String(text)) => match text.trim().to_lowercase().as_str() {
+            "true" | "yes" => Ok(Value::Bool(true)),
+            "false" | "no" => Ok(Value::Bool(false)),
+            _ => Err(format!(
+                "`{}` should be true or false, not \"{text}\"",
+                field.name
+            )),
+        },
+        (FieldKind::Number, Value::Number(number)) => Ok(Value::Number(number)),
+        (FieldKind::Number, Value::String(text)) => {
+            let trimmed = text.trim();
+            let (digits, scale) = match trimmed.strip_suffix('%') {
+                Some(digits) => (digits.trim(), 100.0),
+                None => (trimmed, 1.0),
+            };
+            digits
+                .parse::<f64>()
+                .ok()
+                .and_then(|number| serde_json::Number::from_f64(number / scale))
+                .map(Value::Number)
+                .ok_or_else(|| format!("`{}` should be a number, not \"{text}\"", field.name))
+        }
+        (_, value) => Err(format!("`{}` has the wrong type: {value}", field.name)),
+    }
+}
+
+/// Pulls `T` out of a model's reply, forgiving fences, surrounding prose and
+/// scalars sent as strings. The error says what was wrong, for the repair prompt.
+pub fn parse_reply<T: StructuredReply>(reply: &str) -> Result<T, String> {
+    let object = extract_object(reply).ok_or("The reply has no JSON object in it")?;
+    let mut fields: Map<String, Value> = match serde_json::from_str(object) {
+        Ok(Value::Object(fields)) => fields,
+        Ok(_) => return Err("The reply isn't a JSON object".to_string()),
+        Err(e) => return Err(format!("The reply isn't valid JSON: {e}")),
+    };
+    for field in T::FIELDS {
+        match fields.remove(field.name) {
+            Some(value) => {
+                fields.insert(field.name.to_string(), coerce(field, value)?);
+            }
+            None if field.required => return Err(format!("`{}` is missing", field.name)),
+            None => {}
+        }
+    }
+    serde_json::from_value(Value::Object(fields)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Reply {
+        reason: String,
+        is_spam: bool,
+        #[serde(default)]
+        confidence: Option<f32>,
+    }
+
+    impl StructuredReply for Reply {
+        const FIELDS: &'static [Field] = &[
+            Field::required("reason", FieldKind::String),
+            Field::required("is_spam", FieldKind::Bool),
+            Field::optional("confidence", FieldKind::Number),
+        ];
+    }
+
+    #[test]
+    fn forgives_near_misses() {
+        let expected = Reply {
+            reason: "Lure {with braces}".to_string(),
+            is_spam: true,
+            confidence: Some(0.9),
+        };
+        for reply in [
+            r#"{"reason": "Lure {with braces}", "is_spam": true, "confidence": 0.9}"#,
+            "```json\n{\"reason\": \"Lure {with braces}\", \"is_spam\": \"true\", \"confidence\": \"90%\"}\n```",
+            "Sure! Here's my answer:\n{\"reason\": \"Lure {with braces}\", \"is_spam\": \"Yes\", \"confidence\": \"0.9\"}\nLet me know if you need more.",
+        ] {
+            assert_eq!(parse_reply::<Reply>(reply).unwrap(), expected, "{reply}");
+        }
+    }
+
+    #[test]
+    fn explains_failures() {
+        assert!(parse_reply::<Reply>("I think it's spam")
+            .unwrap_err()
+            .contains("no JSON object"));
+        assert!(parse_reply::<Reply>(r#"{"reason": "Lure"}"#)
+            .unwrap_err()
+            .contains("`is_spam` is missing"));
+        assert!(
+            parse_reply::<Reply>(r#"{"reason": "Lure", "is_spam": "maybe"}"#)
+                .unwrap_err()
+                .contains("true or false")
+        );
+        assert_eq!(
+            Reply::describe(),
+            r#"{"reason": string, "is_spam": boolean, "confidence": number (optional)}"#
+        );
+    }
+}
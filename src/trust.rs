@@ -0,0 +1,246 @@
+use crate::config::get_guild_config;
+use anyhow::{bail, Context as _};
+use chrono::Duration;
+use serde::Deserialize;
+use serenity::all::{Context, GuildId, Permissions, RoleId};
+use std::sync::Mutex;
+
+/// Which signals make a member new or trusted, set under `[guilds.<guild id>.trust]`.
+/// How recently a member must have joined to count as new is
+/// `thresholds.new_user_window_minutes`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrustConfig {
+    /// Accounts younger than this many days count as new, however long they've been
+    /// in the guild. 0 ignores account age.
+    pub new_account_days: i64,
+    /// Members with at least this many messages in the guild are trusted. 0 trusts
+    /// nobody for their activity alone.
+    pub trusted_messages: u32,
+    /// Members holding any of these roles are trusted, whatever else is true of them.
+    pub trusted_roles: Vec<RoleId>,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        TrustConfig {
+            new_account_days: 7,
+            trusted_messages: 100,
+            trusted_roles: vec![],
+        }
+    }
+}
+
+impl TrustConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.new_account_days < 0 {
+            bail!("`trust.new_account_days` must not be negative")
+        }
+        Ok(())
+    }
+
+    /// Combines what is known about a member into how far they're trusted. Anything
+    /// unknown counts for nothing either way.
+    pub fn tier(&self, signals: &TrustSignals, join_window: Duration, now: i64) -> TrustTier {
+        if signals
+            .roles
+            .iter()
+            .any(|role| self.trusted_roles.contains(role))
+        {
+            return TrustTier::Trusted;
+        }
+        let young_account = signals.account_created_at.is_some_and(|created_at| {
+            Duration::seconds(now - created_at) < Duration::days(self.new_account_days)
+        });
+        let just_joined = signals
+            .joined_at
+            .is_some_and(|joined_at| Duration::seconds(now - joined_at) < join_window);
+        if young_account || just_joined || signals.passed_onboarding == Some(false) {
+            return TrustTier::New;
+        }
+        if self.trusted_messages > 0
+            && signals
+                .message_count
+                .is_some_and(|count| count >= self.trusted_messages)
+        {
+            return TrustTier::Trusted;
+        }
+        TrustTier::Member
+    }
+}
+
+/// A guild's trust settings, and the roles that can moderate it, which are trusted too.
+#[derive(Debug)]
+pub struct Trust {
+    config: TrustConfig,
+    /// Looked up from Discord, as gateway messages don't say what their author may do.
+    moderator_roles: Mutex<Vec<RoleId>>,
+}
+
+impl Trust {
+    pub fn new(config: TrustConfig) -> Trust {
+        Trust {
+            config,
+            moderator_roles: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn set_moderator_roles(&self, roles: Vec<RoleId>) {
+        *self.moderator_roles.lock().unwrap() = roles;
+    }
+
+    /// How far the author behind `signals` is trusted at `now`. Moderators always are.
+    pub fn tier(&self, signals: &TrustSignals, join_window: Duration, now: i64) -> TrustTier {
+        let moderator_roles = self.moderator_roles.lock().unwrap();
+        if signals
+            .roles
+            .iter()
+            .any(|role| moderator_roles.contains(role))
+        {
+            return TrustTier::Trusted;
+        }
+        self.config.tier(signals, join_window, now)
+    }
+}
+
+/// Looks up which of the guild's roles can manage messages, the same permission the
+/// review and undo buttons ask for.
+pub async fn load_moderator_roles(ctx: &Context, guild_id: GuildId) -> anyhow::Result<()> {
+    let Some(guild_config) = get_guild_config(ctx, Some(guild_id)).await else {
+        return Ok(());
+    };
+    let roles = guild_id
+        .roles(&ctx.http)
+        .await
+        .with_context(|| format!("Couldn't fetch the roles of guild {guild_id}"))?;
+    let moderator_roles = roles
+        .into_values()
+        .filter(|role| {
+            role.permissions
+                .intersects(Permissions::MANAGE_MESSAGES | Permissions::ADMINISTRATOR)
+        })
+        .map(|role| role.id)
+        .collect();
+    guild_config.trust.set_moderator_roles(moderator_roles);
+    Ok(())
+}
+
+/// What is known about a message's author, each part `None` when it isn't.
+#[derive(Debug, Clone, Default)]
+pub struct TrustSignals {
+    /// Unix timestamp the account was created, from its ID.
+    pub account_created_at: Option<i64>,
+    /// Unix timestamp the member joined the guild.
+    pub joined_at: Option<i64>,
+    /// Messages the bot has seen from the member in the guild before this one.
+    pub message_count: Option<u32>,
+    pub roles: Vec<RoleId>,
+    /// Whether the member got through the guild's membership screening.
+    pub passed_onboarding: Option<bool>,
+}
+
+/// How far a member is trusted not to spam, from least to most.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TrustTier {
+    /// A young account, a recent join, or not yet through screening.
+    New,
+    Member,
+    /// Holds a trusted role or has posted a lot.
+    Trusted,
+}
+
+impl TrustTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TrustTier::New => "new",
+            TrustTier::Member => "member",
+            TrustTier::Trusted => "trusted",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn tier(signals: TrustSignals) -> TrustTier {
+        let config = TrustConfig {
+            trusted_roles: vec![RoleId::new(5)],
+            ..Default::default()
+        };
+        config.tier(&signals, Duration::minutes(120), NOW)
+    }
+
+    fn established() -> TrustSignals {
+        TrustSignals {
+            account_created_at: Some(NOW - 365 * 86_400),
+            joined_at: Some(NOW - 30 * 86_400),
+            message_count: Some(3),
+            roles: vec![],
+            passed_onboarding: Some(true),
+        }
+    }
+
+    #[test]
+    fn trusts_moderators() {
+        let trust = Trust::new(TrustConfig::default());
+        let moderator = TrustSignals {
+            account_created_at: Some(NOW - 86_400),
+            roles: vec![RoleId::new(9)],
+            ..Default::default()
+        };
+        assert_eq!(
+            trust.tier(&moderator, Duration::minutes(120), NOW),
+            TrustTier::New
+        );
+        trust.set_moderator_roles(vec![RoleId::new(9)]);
+        assert_eq!(
+            trust.tier(&moderator, Duration::minutes(120), NOW),
+            TrustTier::Trusted
+        );
+    }
+
+    #[test]
+    fn combines_signals() {
+        assert_eq!(tier(established()), TrustTier::Member);
+        // Nothing known is not the same as new.
+        assert_eq!(tier(TrustSignals::default()), TrustTier::Member);
+
+        let young_account = TrustSignals {
+            account_created_at: Some(NOW - 86_400),
+            ..established()
+        };
+        assert_eq!(tier(young_account), TrustTier::New);
+        let just_joined = TrustSignals {
+            joined_at: Some(NOW - 600),
+            ..established()
+        };
+        assert_eq!(tier(just_joined), TrustTier::New);
+        let screening = TrustSignals {
+            passed_onboarding: Some(false),
+            ..established()
+        };
+        assert_eq!(tier(screening), TrustTier::New);
+
+        let active = TrustSignals {
+            message_count: Some(100),
+            ..established()
+        };
+        assert_eq!(tier(active.clone()), TrustTier::Trusted);
+        // Activity doesn't make up for a brand new account.
+        let active_but_young = TrustSignals {
+            account_created_at: Some(NOW - 86_400),
+            ..active
+        };
+        assert_eq!(tier(active_but_young), TrustTier::New);
+        // A trusted role does.
+        let vouched_for = TrustSignals {
+            joined_at: Some(NOW - 600),
+            roles: vec![RoleId::new(5)],
+            ..established()
+        };
+        assert_eq!(tier(vouched_for), TrustTier::Trusted);
+    }
+}
@@ -0,0 +1,164 @@
+use crate::config::get_guild_config;
+use crate::feedback;
+use crate::messaging;
+use crate::review::{close_with, is_moderator, reply_privately};
+use crate::storage::{self, ActionKind, ModerationRecord};
+use anyhow::bail;
+use serenity::all::{
+    ButtonStyle, ComponentInteraction, Context, CreateButton, Mentionable, Timestamp,
+};
+use tracing::{error, info};
+
+/// Custom IDs of undo buttons start with this, followed by the case ID.
+const CUSTOM_ID_PREFIX: &str = "undo";
+
+/// The button under a log entry that reverses the action it reports.
+pub fn button(case_id: i64) -> CreateButton {
+    CreateButton::new(format!("{CUSTOM_ID_PREFIX}:{case_id}"))
+        .label("Undo")
+        .style(ButtonStyle::Secondary)
+}
+
+/// The case behind an undo button, or `None` for other components.
+pub fn parse_custom_id(custom_id: &str) -> Option<i64> {
+    custom_id
+        .strip_prefix(CUSTOM_ID_PREFIX)?
+        .strip_prefix(':')?
+        .parse()
+        .ok()
+}
+
+/// Reverses what the bot did to the member, and returns what was done about it.
+async fn reverse(ctx: &Context, record: &ModerationRecord) -> anyhow::Result<Vec<&'static str>> {
+    let mut done = vec![];
+    match record.action {
+        ActionKind::Timeout | ActionKind::DeleteAndTimeout | ActionKind::BlunderTimeout => {
+            messaging::lift_timeout(ctx, record.guild_id, record.user_id).await?;
+            done.push("lifted the timeout");
+        }
+        ActionKind::Ban | ActionKind::HoneyPotBan => {
+            record.guild_id.unban(&ctx.http, record.user_id).await?;
+            done.push("unbanned them");
+        }
+        ActionKind::Kick => done.push("kicks can't be undone"),
+        // The wave's timeout or ban, or a harsher step of the ladder, so whichever it was.
+        ActionKind::SpamWave => match record.guild_id.unban(&ctx.http, record.user_id).await {
+            Ok(()) => done.push("unbanned them"),
+            Err(e) if messaging::is_discord_error(&e, messaging::UNKNOWN_BAN) => {
+                match messaging::lift_timeout(ctx, record.guild_id, record.user_id).await {
+                    Ok(()) => done.push("lifted the timeout"),
+                    Err(e) if messaging::is_discord_error(&e, messaging::UNKNOWN_MEMBER) => {
+                        done.push("kicks can't be undone")
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(e) => return Err(e.into()),
+        },
+        ActionKind::Warn | ActionKind::Delete | ActionKind::Hold | ActionKind::Approve => {}
+    }
+    // Only messages Discord says are gone are put back, so undoing a warning, or an
+    // undo that merely hit a rate limit, doesn't post a second copy.
+    if let Some(message_id) = record.message_id {
+        let gone = match record.channel_id.message(ctx, message_id).await {
+            Ok(_) => false,
+            Err(e) if messaging::is_discord_error(&e, messaging::UNKNOWN_MESSAGE) => true,
+            Err(e) => return Err(e.into()),
+        };
+        if gone && !record.content.is_empty() {
+            let author_name = record
+                .user_id
+                .to_user(ctx)
+                .await
+                .map_or_else(|_| record.user_id.to_string(), |user| user.name);
+            messaging::repost_restored(
+                ctx,
+                record.channel_id,
+                record.user_id,
+                author_name.as_str(),
+                record.content.as_str(),
+            )
+            .await?;
+            done.push("restored the message");
+        }
+    }
+    Ok(done)
+}
+
+/// Carries out a moderator's click on an undo button: the action is reversed, the member's
+/// strikes for it are forgiven and the case is marked as a false positive, and labelled
+/// as not spam for the dataset.
+pub async fn handle_button(
+    ctx: &Context,
+    component: &ComponentInteraction,
+    case_id: i64,
+) -> anyhow::Result<()> {
+    let Some(guild_config) = get_guild_config(ctx, component.guild_id).await else {
+        bail!("Undo button pressed outside a configured guild")
+    };
+    if !is_moderator(component) {
+        reply_privately(
+            ctx,
+            component,
+            "Only moderators can undo actions.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let storage = storage::get_storage(ctx).await;
+    let Some(record) = storage.moderation_action(case_id)? else {
+        bail!("No moderation action {case_id}")
+    };
+    let moderator = &component.user;
+    // Claimed before acting, so two moderators clicking at once can't both act.
+    if !storage.mark_false_positive(case_id, moderator.id, Timestamp::now().unix_timestamp())? {
+        reply_privately(
+            ctx,
+            component,
+            "Another moderator already undid this one.".to_string(),
+        )
+        .await?;
+        return Ok(());
+    }
+    let done = match reverse(ctx, &record).await {
+        Ok(done) => done,
+        Err(e) => {
+            storage.unmark_false_positive(case_id)?;
+            reply_privately(ctx, component, format!("Couldn't undo this one: {e}")).await?;
+            return Ok(());
+        }
+    };
+    if let Some(message_id) = record.message_id {
+        storage.remove_strikes_for(record.guild_id, message_id)?;
+    }
+    if let Err(e) = feedback::label_action(ctx, &record, false, moderator.id).await {
+        error!("Failed to label case {case_id} as not spam due to {e}");
+    }
+    guild_config.spam_templates.remove(record.content.as_str());
+    info!(
+        "Case {case_id} - {} undone by {} as a false positive",
+        record.action.as_str(),
+        moderator.name
+    );
+    let mut outcome = format!("False positive - undone by {}", moderator.mention());
+    if !done.is_empty() {
+        outcome = format!("{outcome} ({})", done.join("; "));
+    }
+    close_with(ctx, component, "Undone", outcome).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_custom_ids() {
+        let button = serde_json::to_value(button(42)).unwrap();
+        assert_eq!(button["custom_id"], "undo:42");
+        assert_eq!(parse_custom_id("undo:42"), Some(42));
+        assert_eq!(parse_custom_id("undo42"), None);
+        assert_eq!(parse_custom_id("undo:x"), None);
+        assert_eq!(parse_custom_id("review:42:ban"), None);
+    }
+}
@@ -0,0 +1,355 @@
+use crate::llm::LlmTask;
+use anyhow::bail;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use serenity::all::GuildId;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Tokens a single completion used, as reported by the server.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// What a model costs in USD per million tokens, set under `[llm.prices."<model>"]`.
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    pub prompt_per_million: f64,
+    pub completion_per_million: f64,
+}
+
+impl Price {
+    fn cost(&self, tokens: TokenUsage) -> f64 {
+        (tokens.prompt_tokens as f64 * self.prompt_per_million
+            + tokens.completion_tokens as f64 * self.completion_per_million)
+            / 1_000_000.0
+    }
+}
+
+/// The parts of the bot a spend cap can switch off, cheapest to lose first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Feature {
+    Roadmaps,
+    Requests,
+    SpamDetection,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 3] = [Feature::Roadmaps, Feature::Requests, Feature::SpamDetection];
+
+    pub fn of(task: LlmTask) -> Feature {
+        match task {
+            LlmTask::Spam => Feature::SpamDetection,
+            LlmTask::Request | LlmTask::Verify => Feature::Requests,
+            LlmTask::RoadmapDetect | LlmTask::RoadmapCreate => Feature::Roadmaps,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Feature::Roadmaps => "roadmaps",
+            Feature::Requests => "requests",
+            Feature::SpamDetection => "spam_detection",
+        }
+    }
+}
+
+fn default_roadmaps_share() -> f64 {
+    0.5
+}
+
+fn default_requests_share() -> f64 {
+    0.8
+}
+
+fn default_spam_detection_share() -> f64 {
+    1.0
+}
+
+/// A guild's daily LLM spend cap, set under `[guilds.<guild id>.budget]`. Each feature
+/// stops calling the LLM once the day's spend reaches its share of `daily_usd`, so
+/// roadmaps go first and spam detection last.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Budget {
+    pub daily_usd: f64,
+    #[serde(default = "default_roadmaps_share")]
+    pub roadmaps: f64,
+    #[serde(default = "default_requests_share")]
+    pub requests: f64,
+    #[serde(default = "default_spam_detection_share")]
+    pub spam_detection: f64,
+}
+
+impl Budget {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.daily_usd.is_nan() || self.daily_usd <= 0.0 {
+            bail!("`budget.daily_usd` must be positive")
+        }
+        for (name, share) in [
+            ("roadmaps", self.roadmaps),
+            ("requests", self.requests),
+            ("spam_detection", self.spam_detection),
+        ] {
+            if share <= 0.0 || !(0.0..=1.0).contains(&share) {
+                bail!("`budget.{name}` must be more than 0.0 and at most 1.0")
+            }
+        }
+        Ok(())
+    }
+
+    /// Spend in USD at which `feature` stops for the day.
+    pub fn limit(&self, feature: Feature) -> f64 {
+        self.daily_usd
+            * match feature {
+                Feature::Roadmaps => self.roadmaps,
+                Feature::Requests => self.requests,
+                Feature::SpamDetection => self.spam_detection,
+            }
+    }
+}
+
+/// One row of the daily totals.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UsageKey {
+    pub day: NaiveDate,
+    /// `None` for calls made outside a guild, e.g. by a backtest.
+    pub guild_id: Option<GuildId>,
+    pub task: LlmTask,
+    pub model: String,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Usage {
+    pub calls: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cost_usd: f64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.calls += other.calls;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+        self.cost_usd += other.cost_usd;
+    }
+}
+
+#[derive(Debug)]
+struct LedgerState {
+    day: NaiveDate,
+    today: HashMap<UsageKey, Usage>,
+    /// Added since the last `take_unsaved`, possibly for earlier days.
+    unsaved: HashMap<UsageKey, Usage>,
+    /// Features already reported as over budget today.
+    cut_off: HashSet<(GuildId, Feature)>,
+}
+
+impl LedgerState {
+    fn roll_over(&mut self, day: NaiveDate) {
+        if day != self.day {
+            self.day = day;
+            self.today.clear();
+            self.cut_off.clear();
+        }
+    }
+}
+
+/// Tokens and spend per guild, task and model, for today and until saved.
+#[derive(Debug)]
+pub struct UsageLedger {
+    prices: HashMap<String, Price>,
+    budgets: HashMap<GuildId, Budget>,
+    state: Mutex<LedgerState>,
+}
+
+impl UsageLedger {
+    pub fn new(prices: HashMap<String, Price>) -> UsageLedger {
+        UsageLedger {
+            prices,
+            budgets: HashMap::new(),
+            state: Mutex::new(LedgerState {
+                day: NaiveDate::MIN,
+                today: HashMap::new(),
+                unsaved: HashMap::new(),
+                cut_off: HashSet::new(),
+            }),
+        }
+    }
+
+    pub fn set_budgets(&mut self, budgets: HashMap<GuildId, Budget>) {
+        self.budgets = budgets;
+    }
+
+    pub fn has_price(&self, model: &str) -> bool {
+        self.prices.contains_key(model)
+    }
+
+    pub fn budget(&self, guild_id: GuildId) -> Option<&Budget> {
+        self.budgets.get(&guild_id)
+    }
+
+    pub fn record(
+        &self,
+        day: NaiveDate,
+        guild_id: Option<GuildId>,
+        task: LlmTask,
+        model: &str,
+        tokens: TokenUsage,
+    ) {
+        let usage = Usage {
+            calls: 1,
+            prompt_tokens: tokens.prompt_tokens,
+            completion_tokens: tokens.completion_tokens,
+            cost_usd: self
+                .prices
+                .get(model)
+                .map_or(0.0, |price| price.cost(tokens)),
+        };
+        let key = UsageKey {
+            day,
+            guild_id,
+            task,
+            model: model.to_string(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.roll_over(day);
+        state.today.entry(key.clone()).or_default().add(&usage);
+        state.unsaved.entry(key).or_default().add(&usage);
+    }
+
+    /// Adds totals saved by an earlier run, so caps hold across restarts.
+    pub fn restore(&self, day: NaiveDate, rows: Vec<(UsageKey, Usage)>) {
+        let mut state = self.state.lock().unwrap();
+        state.roll_over(day);
+        for (key, usage) in rows.into_iter().filter(|(key, _)| key.day == day) {
+            state.today.entry(key).or_default().add(&usage);
+        }
+    }
+
+    /// Totals not yet written to storage. They're forgotten once taken.
+    pub fn take_unsaved(&self) -> Vec<(UsageKey, Usage)> {
+        self.state.lock().unwrap().unsaved.drain().collect()
+    }
+
+    /// Today's totals, sorted by guild, task and model.
+    pub fn today(&self, day: NaiveDate) -> Vec<(UsageKey, Usage)> {
+        let mut state = self.state.lock().unwrap();
+        state.roll_over(day);
+        let mut rows: Vec<(UsageKey, Usage)> = state
+            .today
+            .iter()
+            .map(|(key, usage)| (key.clone(), *usage))
+            .collect();
+        rows.sort_by(|(a, _), (b, _)| a.cmp(b));
+        rows
+    }
+
+    pub fn spent(&self, day: NaiveDate, guild_id: GuildId) -> f64 {
+        self.today(day)
+            .iter()
+            .filter(|(key, _)| key.guild_id == Some(guild_id))
+            .map(|(_, usage)| usage.cost_usd)
+            .sum()
+    }
+
+    /// Features `guild_id` has spent its budget for today.
+    pub fn paused(&self, day: NaiveDate, guild_id: GuildId) -> Vec<Feature> {
+        let Some(budget) = self.budgets.get(&guild_id) else {
+            return vec![];
+        };
+        let spent = self.spent(day, guild_id);
+        Feature::ALL
+            .into_iter()
+            .filter(|feature| spent >= budget.limit(*feature))
+            .collect()
+    }
+
+    /// Whether `guild_id` may still spend on `feature` today. The first refusal of
+    /// the day for each feature is logged.
+    pub fn allows(&self, day: NaiveDate, guild_id: GuildId, feature: Feature) -> bool {
+        if !self.paused(day, guild_id).contains(&feature) {
+            return true;
+        }
+        let spent = self.spent(day, guild_id);
+        if self
+            .state
+            .lock()
+            .unwrap()
+            .cut_off
+            .insert((guild_id, feature))
+        {
+            warn!(
+                "Guild {guild_id} has spent ${spent:.2} on LLM calls today, pausing {}",
+                feature.as_str()
+            );
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(prompt_tokens: u64, completion_tokens: u64) -> TokenUsage {
+        TokenUsage {
+            prompt_tokens,
+            completion_tokens,
+        }
+    }
+
+    #[test]
+    fn caps_features_in_order() {
+        let guild_id = GuildId::new(1);
+        let mut ledger = UsageLedger::new(HashMap::from([(
+            "gpt-4.1-mini".to_string(),
+            Price {
+                prompt_per_million: 0.4,
+                completion_per_million: 1.6,
+            },
+        )]));
+        ledger.set_budgets(HashMap::from([(
+            guild_id,
+            toml::from_str::<Budget>("daily_usd = 1.0").unwrap(),
+        )]));
+        let day = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+        // $0.40 + $0.16 = $0.56, past the roadmap share but not the request share.
+        ledger.record(
+            day,
+            Some(guild_id),
+            LlmTask::Spam,
+            "gpt-4.1-mini",
+            tokens(1_000_000, 100_000),
+        );
+        ledger.record(
+            day,
+            Some(guild_id),
+            LlmTask::Request,
+            "local-model",
+            tokens(500, 20),
+        );
+        assert!((ledger.spent(day, guild_id) - 0.56).abs() < 1e-9);
+        assert!(!ledger.allows(day, guild_id, Feature::Roadmaps));
+        assert_eq!(ledger.paused(day, guild_id), vec![Feature::Roadmaps]);
+        assert!(ledger.allows(day, guild_id, Feature::Requests));
+        assert!(ledger.allows(day, guild_id, Feature::SpamDetection));
+        assert!(ledger.allows(day, GuildId::new(2), Feature::Roadmaps));
+
+        // A new day starts from nothing, but unsaved totals keep the old day's rows.
+        let tomorrow = day.succ_opt().unwrap();
+        assert!(ledger.allows(tomorrow, guild_id, Feature::Roadmaps));
+        assert!(ledger.today(tomorrow).is_empty());
+        let unsaved = ledger.take_unsaved();
+        assert_eq!(unsaved.len(), 2);
+        assert!(ledger.take_unsaved().is_empty());
+
+        ledger.restore(day, unsaved);
+        assert!(!ledger.allows(day, guild_id, Feature::Roadmaps));
+    }
+}
@@ -1,13 +1,19 @@
+use crate::storage::get_storage;
+use crate::trust::TrustSignals;
 use chrono::Duration;
-use serenity::all::{Context, Message, Timestamp, User, UserId};
+use serenity::all::{Context, GuildId, Message, Timestamp, User, UserId};
 use serenity::prelude::TypeMapKey;
 use std::cmp::Ordering;
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use tokio::sync::RwLock;
+use tracing::error;
 
-pub async fn update_user_join_date(ctx: &Context, user: &User, join_date: i64) {
-    if get_user_join_date(ctx, user).await.is_none() {
+/// Users are tracked per guild, so nothing learned in one server is visible from another.
+pub type GuildUser = (GuildId, UserId);
+
+pub async fn update_user_join_date(ctx: &Context, guild_id: GuildId, user: &User, join_date: i64) {
+    if get_user_join_date(ctx, guild_id, user.id).await.is_none() {
         let counter_lock = {
             let data_read = ctx.data.read().await;
             data_read
@@ -17,12 +23,18 @@ pub async fn update_user_join_date(ctx: &Context, user: &User, join_date: i64) {
         };
         {
             let mut counter = counter_lock.write().await;
-            let _ = counter.entry(user.id).or_insert(join_date);
+            let _ = counter.entry((guild_id, user.id)).or_insert(join_date);
+        }
+        if let Err(e) = get_storage(ctx)
+            .await
+            .save_join_date((guild_id, user.id), join_date)
+        {
+            error!("Failed to persist join date for {} due to {e}", user.id);
         }
     }
 }
 
-pub async fn get_user_join_date(ctx: &Context, user: &User) -> Option<i64> {
+pub async fn get_user_join_date(ctx: &Context, guild_id: GuildId, user_id: UserId) -> Option<i64> {
     let counter_lock = {
         let data_read = ctx.data.read().await;
         data_read
@@ -31,19 +43,51 @@ pub async fn get_user_join_date(ctx: &Context, user: &User) -> Option<i64> {
             .clone()
     };
     let user_date_info = counter_lock.read().await;
-    user_date_info.get(&user.id).copied()
+    user_date_info.get(&(guild_id, user_id)).copied()
+}
+
+/// What is known about the author of `message` for judging how far to trust them.
+pub async fn trust_signals(ctx: &Context, guild_id: GuildId, message: &Message) -> TrustSignals {
+    let member = message.member.as_deref();
+    let joined_at = match get_user_join_date(ctx, guild_id, message.author.id).await {
+        Some(joined_at) => Some(joined_at),
+        None => member
+            .and_then(|member| member.joined_at)
+            .map(|joined_at| joined_at.unix_timestamp()),
+    };
+    // `update_user_context` has already counted this message, so only earlier ones count.
+    let message_count = get_storage(ctx)
+        .await
+        .message_count((guild_id, message.author.id))
+        .map(|count| count.saturating_sub(1))
+        .inspect_err(|e| {
+            error!(
+                "Failed to count messages from {} due to {e}",
+                message.author.id
+            )
+        })
+        .ok();
+    TrustSignals {
+        account_created_at: Some(message.author.id.created_at().unix_timestamp()),
+        joined_at,
+        message_count,
+        roles: member
+            .map(|member| member.roles.clone())
+            .unwrap_or_default(),
+        passed_onboarding: member.map(|member| !member.pending),
+    }
 }
 
 pub struct UserJoinDate;
 
 impl TypeMapKey for UserJoinDate {
-    type Value = Arc<RwLock<HashMap<UserId, i64>>>;
+    type Value = Arc<RwLock<HashMap<GuildUser, i64>>>;
 }
 
 pub struct UserContext;
 
 impl TypeMapKey for UserContext {
-    type Value = Arc<RwLock<HashMap<UserId, UserHistory>>>;
+    type Value = Arc<RwLock<HashMap<GuildUser, UserHistory>>>;
 }
 
 pub struct UserHistory {
@@ -95,13 +139,13 @@ impl UserHistory {
         }
     }
 
+    /// Messages sent within `context_window` before `time`, oldest first.
     pub fn context(&self, time: Timestamp, context_window: Duration) -> Vec<String> {
         self.history
             .iter()
             .filter_map(|(history_timestamp, message)| {
-                if (time.unix_timestamp() - history_timestamp.unix_timestamp())
-                    < context_window.num_milliseconds()
-                {
+                let age = time.unix_timestamp() - history_timestamp.unix_timestamp();
+                if age > 0 && age < context_window.num_seconds() {
                     Some(message.clone())
                 } else {
                     None
@@ -111,7 +155,7 @@ impl UserHistory {
     }
 }
 
-pub async fn update_user_context(ctx: &Context, message: &Message) {
+pub async fn update_user_context(ctx: &Context, guild_id: GuildId, message: &Message) {
     let context_lock = {
         let data_read = ctx.data.read().await;
         data_read
@@ -122,13 +166,30 @@ pub async fn update_user_context(ctx: &Context, message: &Message) {
     {
         let mut all_users_context = context_lock.write().await;
         let user_context = all_users_context
-            .entry(message.author.id)
+            .entry((guild_id, message.author.id))
             .or_insert(UserHistory::default());
         user_context.push(message.timestamp, message.content.clone());
     }
+    if let Err(e) = get_storage(ctx).await.save_message(
+        (guild_id, message.author.id),
+        (message.channel_id, message.id),
+        message.timestamp,
+        message.content.as_str(),
+    ) {
+        error!(
+            "Failed to persist message history for {} due to {e}",
+            message.author.id
+        );
+    }
 }
 
-pub async fn retrieve_user_context(ctx: &Context, message: &Message) -> Vec<String> {
+/// The author's earlier messages from within `context_window` of `message`.
+pub async fn retrieve_user_context(
+    ctx: &Context,
+    guild_id: GuildId,
+    message: &Message,
+    context_window: Duration,
+) -> Vec<String> {
     let context_lock = {
         let data_read = ctx.data.read().await;
         data_read
@@ -138,10 +199,31 @@ pub async fn retrieve_user_context(ctx: &Context, message: &Message) -> Vec<Stri
     };
     {
         let all_users_context = context_lock.read().await;
-        if let Some(user_context) = all_users_context.get(&message.author.id) {
-            user_context.context(message.timestamp, Duration::minutes(1))
+        if let Some(user_context) = all_users_context.get(&(guild_id, message.author.id)) {
+            user_context.context(message.timestamp, context_window)
         } else {
             vec![]
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64) -> Timestamp {
+        Timestamp::from_unix_timestamp(1_700_000_000 + seconds).unwrap()
+    }
+
+    #[test]
+    fn context_excludes_old_and_current_messages() {
+        let mut history = UserHistory::default();
+        history.push(at(0), "too old".to_string());
+        history.push(at(200), "hey, anyone want free nitro?".to_string());
+        history.push(at(230), "https://discord.gg/nitro".to_string());
+        assert_eq!(
+            history.context(at(230), Duration::minutes(1)),
+            vec!["hey, anyone want free nitro?".to_string()]
+        );
+    }
+}